@@ -1,38 +1,146 @@
 use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired};
 
-// Copied as is from the sdl2::audio documentation:
+use std::f32::consts::PI;
+
+// XO-CHIP audio patterns are 16 bytes, played back one bit at a time.
+pub const PATTERN_SIZE: usize = 16;
+const PATTERN_BITS: f32 = (PATTERN_SIZE * 8) as f32;
+// Pitch at which the pattern is played back at 4000 bits per second.
+pub const DEFAULT_PITCH: u8 = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Waveform {
+    Square,
+    Sine,
+    Triangle,
+    Noise,
+}
+
+// Based on the square wave example from the sdl2::audio documentation:
 // https://github.com/Rust-SDL2/rust-sdl2/blob/master/examples/audio-squarewave.rs
-pub struct SquareWave {
+pub struct Wave {
     phase_inc: f32,
     phase: f32,
-    volume: f32
+    volume: f32,
+    waveform: Waveform,
+    // When an XO-CHIP pattern has been loaded, it's played instead of the waveform.
+    pattern: Option<[u8; PATTERN_SIZE]>,
+    // Position within the pattern in bits, along with its increment per sample.
+    pattern_pos: f32,
+    pattern_inc: f32,
 }
 
-impl AudioCallback for SquareWave {
+impl Wave {
+    // XO-CHIP playback rate in bits per second for the given pitch register value.
+    fn pattern_rate(pitch: u8) -> f32 {
+        return 4000.0 * 2f32.powf((pitch as f32 - 64.0) / 48.0);
+    }
+
+    // Returns the sample for bit |pos| of the pattern, with set bits being high.
+    fn pattern_sample(pattern: &[u8; PATTERN_SIZE], pos: f32, volume: f32) -> f32 {
+        let bit = pos as usize % (PATTERN_SIZE * 8);
+        if (pattern[bit / 8] >> (7 - bit % 8)) & 1 == 1 {
+            return volume;
+        }
+
+        return -volume;
+    }
+
+    // Returns the sample for a |phase| in the range [0, 1).
+    fn sample(waveform: Waveform, phase: f32, volume: f32) -> f32 {
+        match waveform {
+            Waveform::Square => return if phase <= 0.5 { volume } else { -volume },
+            Waveform::Sine => return volume * (2.0 * PI * phase).sin(),
+            // Rises from -volume to volume over the first half, then falls back.
+            Waveform::Triangle => return if phase <= 0.5 {
+                volume * (4.0 * phase - 1.0)
+            } else {
+                volume * (3.0 - 4.0 * phase)
+            },
+            Waveform::Noise => return volume * (2.0 * rand::random::<f32>() - 1.0),
+        }
+    }
+}
+
+impl AudioCallback for Wave {
     type Channel = f32;
 
     fn callback(&mut self, out: &mut [f32]) {
-        // Generate a square wave
         for x in out.iter_mut() {
-            *x = if self.phase <= 0.5 {
-                self.volume
-            } else {
-                -self.volume
-            };
+            if let Some(pattern) = &self.pattern {
+                *x = Wave::pattern_sample(pattern, self.pattern_pos, self.volume);
+                self.pattern_pos = (self.pattern_pos + self.pattern_inc) % PATTERN_BITS;
+                continue;
+            }
+
+            *x = Wave::sample(self.waveform, self.phase, self.volume);
             self.phase = (self.phase + self.phase_inc) % 1.0;
         }
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AudioConfig {
+    // Frequency of the beep in Hz.
+    pub freq: f32,
+    // Amplitude of the wave, between 0.0 and 1.0.
+    pub volume: f32,
+    pub waveform: Waveform,
+    // The beep only sounds while the sound timer is at least this high, as
+    // some interpreters don't beep for very short sounds.
+    pub min_sound_ticks: u8,
+}
+
+impl Default for AudioConfig {
+    fn default() -> Self {
+        return AudioConfig {
+            freq: 440.0,
+            volume: 0.25,
+            waveform: Waveform::Square,
+            min_sound_ticks: 1,
+        };
+    }
+}
+
 pub struct Audio {
-    device: AudioDevice<SquareWave>,
+    device: Option<AudioDevice<Wave>>,
+    config: AudioConfig,
+    pattern: Option<[u8; PATTERN_SIZE]>,
+    pitch: u8,
+    // Whether the wave was last started rather than stopped.
+    playing: bool,
 }
 
+// AudioDevice isn't Send or Sync since SDL wants to be used from the thread it
+// was initialized on. The device is only paused, resumed and locked, which SDL
+// guards with its own audio lock, and Audio is always owned by a single user at
+// a time (the Timer keeps it behind a Mutex), so sharing it across threads is safe.
 unsafe impl Sync for Audio {}
 unsafe impl Send for Audio {}
 
+impl Default for Audio {
+    fn default() -> Self {
+        return Audio::new();
+    }
+}
+
 impl Audio {
     pub fn new() -> Self {
+        return Audio::with_config(AudioConfig::default(), false);
+    }
+
+    // When |for_test| is set, SDL isn't initialized and there is no device to play on.
+    pub fn with_config(config: AudioConfig, for_test: bool) -> Self {
+        if for_test {
+            return Audio {
+                device: None,
+                config,
+                pattern: None,
+                pitch: DEFAULT_PITCH,
+                playing: false,
+            };
+        }
+
         let sdl_context = sdl2::init().unwrap();
         let audio_subsystem = sdl_context.audio().unwrap();
 
@@ -45,24 +153,198 @@ impl Audio {
         let device = audio_subsystem
             .open_playback(None, &desired_spec, |spec| {
                 // initialize the audio callback
-                SquareWave {
-                    phase_inc: 440.0 / spec.freq as f32,
+                Wave {
+                    phase_inc: config.freq / spec.freq as f32,
                     phase: 0.0,
-                    volume: 0.25,
+                    volume: config.volume,
+                    waveform: config.waveform,
+                    pattern: None,
+                    pattern_pos: 0.0,
+                    pattern_inc: Wave::pattern_rate(DEFAULT_PITCH) / spec.freq as f32,
                 }
             })
             .unwrap();
 
         return Audio {
-          device: device,
+          device: Some(device),
+          config,
+          pattern: None,
+          pitch: DEFAULT_PITCH,
+          playing: false,
         };
     }
 
-    pub fn start(&self) {
-        self.device.resume();
+    pub fn config(&self) -> AudioConfig {
+        return self.config;
+    }
+
+    // Update the parameters of the wave, which takes effect on the playing device as well.
+    pub fn set_config(&mut self, config: AudioConfig) {
+        self.config = config;
+        if let Some(device) = &mut self.device {
+            let sample_rate = device.spec().freq;
+            let mut wave = device.lock();
+            wave.phase_inc = config.freq / sample_rate as f32;
+            wave.volume = config.volume;
+            wave.waveform = config.waveform;
+        }
+    }
+
+    pub fn pattern(&self) -> Option<[u8; PATTERN_SIZE]> {
+        return self.pattern;
     }
 
-    pub fn stop(&self) {
-        self.device.pause();
+    // XO-CHIP F002 instruction, replaces the beep with the given pattern.
+    pub fn set_pattern(&mut self, pattern: [u8; PATTERN_SIZE]) {
+        self.pattern = Some(pattern);
+        if let Some(device) = &mut self.device {
+            let mut wave = device.lock();
+            wave.pattern = Some(pattern);
+            wave.pattern_pos = 0.0;
+        }
+    }
+
+    pub fn pitch(&self) -> u8 {
+        return self.pitch;
+    }
+
+    // XO-CHIP FX3A instruction, sets the rate the pattern is played back at.
+    pub fn set_pitch(&mut self, pitch: u8) {
+        self.pitch = pitch;
+        if let Some(device) = &mut self.device {
+            let sample_rate = device.spec().freq;
+            device.lock().pattern_inc = Wave::pattern_rate(pitch) / sample_rate as f32;
+        }
+    }
+
+    // Go back to the plain beep at the default pitch, as before any XO-CHIP
+    // pattern was loaded.
+    pub fn reset_pattern(&mut self) {
+        self.pattern = None;
+        self.set_pitch(DEFAULT_PITCH);
+        if let Some(device) = &mut self.device {
+            device.lock().pattern = None;
+        }
+    }
+
+    pub fn is_playing(&self) -> bool {
+        return self.playing;
+    }
+
+    pub fn start(&mut self) {
+        self.playing = true;
+        if let Some(device) = &self.device {
+            device.resume();
+        }
+    }
+
+    pub fn stop(&mut self) {
+        self.playing = false;
+        if let Some(device) = &self.device {
+            device.pause();
+        }
+    }
+
+    // Silence the device and let go of it, after which nothing can be played.
+    fn release(&mut self) {
+        self.stop();
+        self.device = None;
+    }
+}
+
+impl Drop for Audio {
+    // Don't leave a tone playing if the device outlives us.
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Audio, AudioConfig, Wave, Waveform, DEFAULT_PITCH};
+
+    #[test]
+    fn config() {
+        let config = AudioConfig { freq: 880.0, volume: 0.5, waveform: Waveform::Sine, min_sound_ticks: 2 };
+        let mut audio = Audio::with_config(config, true);
+        assert_eq!(audio.config(), config);
+
+        audio.set_config(AudioConfig::default());
+        assert_eq!(audio.config().freq, 440.0);
+        assert_eq!(audio.config().volume, 0.25);
+        assert_eq!(audio.config().waveform, Waveform::Square);
+        assert_eq!(audio.config().min_sound_ticks, 1);
+
+        // Starting and stopping without a device only tracks the state.
+        assert!(!audio.is_playing());
+        audio.start();
+        assert!(audio.is_playing());
+        audio.stop();
+        assert!(!audio.is_playing());
+    }
+
+    #[test]
+    fn release() {
+        let mut audio = Audio::with_config(AudioConfig::default(), true);
+        audio.start();
+        assert!(audio.is_playing());
+
+        // This is what runs when the Audio is dropped.
+        audio.release();
+        assert!(!audio.is_playing());
+        assert!(audio.device.is_none());
+
+        // Starting again has nothing to play on, and dropping afterwards is fine.
+        audio.start();
+        drop(audio);
+    }
+
+    #[test]
+    fn pattern() {
+        let mut audio = Audio::with_config(AudioConfig::default(), true);
+        assert_eq!(audio.pattern(), None);
+        assert_eq!(audio.pitch(), DEFAULT_PITCH);
+
+        let mut pattern = [0; 16];
+        pattern[0] = 0xA0;
+        pattern[15] = 0x01;
+        audio.set_pattern(pattern);
+        audio.set_pitch(112);
+        assert_eq!(audio.pattern(), Some(pattern));
+        assert_eq!(audio.pitch(), 112);
+
+        // Bits are played MSB first, and the position wraps around the pattern.
+        let expected = [(0.0, 0.5), (1.0, -0.5), (2.0, 0.5), (3.5, -0.5), (127.0, 0.5), (128.0, 0.5)];
+        for (pos, sample) in expected.iter() {
+            assert_eq!(Wave::pattern_sample(&pattern, *pos, 0.5), *sample, "bit {}", pos);
+        }
+
+        // Every 48 steps of pitch doubles the playback rate.
+        assert_eq!(Wave::pattern_rate(64), 4000.0);
+        assert_eq!(Wave::pattern_rate(112), 8000.0);
+        assert_eq!(Wave::pattern_rate(16), 2000.0);
+    }
+
+    #[test]
+    fn samples() {
+        let close = |a: f32, b: f32| (a - b).abs() < 1e-4;
+        let table: [(Waveform, [f32; 4]); 3] = [
+            (Waveform::Square, [0.5, 0.5, 0.5, -0.5]),
+            (Waveform::Sine, [0.0, 0.5, 0.0, -0.5]),
+            (Waveform::Triangle, [-0.5, 0.0, 0.5, 0.0]),
+        ];
+
+        for (waveform, expected) in table.iter() {
+            for (ind, phase) in [0.0, 0.25, 0.5, 0.75].iter().enumerate() {
+                let sample = Wave::sample(*waveform, *phase, 0.5);
+                assert!(close(sample, expected[ind]), "{:?} at {}: {}", waveform, phase, sample);
+            }
+        }
+
+        // Noise is random, but stays within the volume.
+        for _ in 0..100 {
+            let sample = Wave::sample(Waveform::Noise, 0.0, 0.5);
+            assert!((-0.5..=0.5).contains(&sample));
+        }
     }
 }
\ No newline at end of file
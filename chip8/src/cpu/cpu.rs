@@ -1,6 +1,79 @@
 use std::{collections::{LinkedList, HashMap}, sync::Arc};
 
-use crate::{mem::mem::Memory, display::display::{Display, WIDTH, HEIGHT}, timer::timer::Timer};
+use crate::{mem::mem::{self, Memory}, display::display::{Display, HIRES_WIDTH}, timer::timer::Timer,
+            audio::audio::PATTERN_SIZE, error::error::Chip8Error};
+
+// Where I is left after the FX55/FX65 store and load instructions, which
+// interpreters disagree on.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MemIncrement {
+    Unchanged, // Modern interpreters leave I alone.
+    IncrementByX, // CHIP-48 and SUPER-CHIP 1.0 advance I by X.
+    IncrementByXPlus1, // The original interpreter leaves I past the last register.
+}
+
+// What to do when an instruction can't be decoded.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BadOpcodePolicy {
+    Halt, // Stop as if the program had finished.
+    Skip, // Log it and carry on with the next instruction.
+    Error, // Return the error to the caller.
+}
+
+// Set of behaviour toggles for instructions whose semantics differ between
+// CHIP-8 interpreters. The default is the "modern" behaviour for each of them.
+#[derive(Clone, Copy)]
+pub struct CpuConfig {
+    pub mem_increment: MemIncrement, // How load/store operations update I.
+    pub logic_resets_vf: bool, // Whether AND/OR/XOR instructions clear the VF flag.
+    pub shift_uses_vy: bool, // Whether shifts copy VY into VX first (true), rather than shifting VX in place.
+    pub jump_quirk: bool, // Whether BNNN jumps are offset by VX (BXNN) instead of V0.
+    pub fx1e_sets_vf: bool, // Whether FX1E sets VF to whether I overflowed past 0xFFF.
+    pub max_stack_depth: usize, // Maximum number of nested subroutine calls.
+    pub draw_wait_vblank: bool, // Whether draws are limited to one per display frame.
+    pub start_addr: u16, // Address the program is loaded at, and execution starts from.
+    pub key_timeout: Option<u64>, // Cycles "Get Key" waits before giving up, or None to wait forever.
+    pub on_unknown_opcode: BadOpcodePolicy, // How instructions which can't be decoded are handled.
+    pub halt_on_self_jump: bool, // Whether a jump to itself halts, rather than waiting on the timers or keys.
+}
+
+impl Default for CpuConfig {
+    fn default() -> Self {
+        CpuConfig {
+            mem_increment: MemIncrement::Unchanged,
+            logic_resets_vf: false,
+            shift_uses_vy: false,
+            jump_quirk: false,
+            fx1e_sets_vf: false,
+            max_stack_depth: DEFAULT_STACK_DEPTH,
+            draw_wait_vblank: false,
+            start_addr: PROGRAM_ADDRESS,
+            key_timeout: None,
+            on_unknown_opcode: BadOpcodePolicy::Error,
+            halt_on_self_jump: false,
+        }
+    }
+}
+
+// Counts of the executed instructions, for profiling which ones a program uses.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct OpcodeHistogram {
+    pub families: [u64; 16], // Keyed by the high nibble of the instruction.
+    pub logic: [u64; 16], // 8XYN instructions, keyed by N.
+    pub misc: HashMap<u8, u64>, // FXNN instructions, keyed by NN.
+}
+
+impl OpcodeHistogram {
+    fn record(&mut self, instr: u16) {
+        let family = (instr >> 12) as usize;
+        self.families[family] += 1;
+        match family {
+            0x8 => self.logic[(instr & 0xF) as usize] += 1,
+            0xF => *self.misc.entry((instr & 0xFF) as u8).or_insert(0) += 1,
+            _ => {},
+        }
+    }
+}
 
 pub struct Cpu {
     pc: u16, // program counter
@@ -8,46 +81,263 @@ pub struct Cpu {
     v: [u8; 16], // V0-VF
     stack: LinkedList<u16>, // Stack
     pressed: HashMap<u8, bool>, // Keep track of pressed keys for "Get Key" instruction.
-    mem_quirk: bool, // Whether to apply memory quirk or not.
-    vf_reset_quirk: bool, // Whether AND/OR/XOR instructions clear the VF flag.
-    shift_quirk: bool // Whether shift operations act on VY or VX.
+    config: CpuConfig, // Quirk settings.
+    cycles: u64, // Number of successfully decoded instructions.
+    halted: bool, // Whether the program stopped, see is_halted().
+    rpl: [u8; RPL_FLAGS], // SUPER-CHIP user flags.
+    last_draw_frame: Option<u64>, // Display frame of the last draw, for the vblank quirk.
+    key_wait_cycles: u64, // Number of cycles the current "Get Key" has been waiting.
+    waiting_for_key: bool, // Whether a "Get Key" is in progress.
+    key_wait_addr: u16, // Address of the "Get Key" in progress.
+    histogram: Option<OpcodeHistogram>, // Executed instructions, when profiling.
+    skipped_opcode: Option<u16>, // Unknown instruction skipped since the last check.
 }
 
-const PROGRAM_ADDRESS: u16 = 0x200;
+// Kept in sync with where the memory loads programs by default.
+const PROGRAM_ADDRESS: u16 = mem::PROGRAM_ADDRESS as u16;
+// The original interpreter supported 16 levels of nested subroutines.
+const DEFAULT_STACK_DEPTH: usize = 16;
+// SUPER-CHIP (on the HP48) has 8 user flags to save registers to.
+pub const RPL_FLAGS: usize = 8;
+// Value "Get Key" stores in VX when it times out, which isn't a valid key.
+pub const KEY_TIMEOUT_VALUE: u8 = 0xFF;
 
 impl Cpu {
-    pub fn new(mem_quirk: bool, vf_reset_quirk: bool, shift_quirk: bool) -> Self {
+    pub fn new(config: CpuConfig) -> Self {
         Cpu {
-            pc:  PROGRAM_ADDRESS,
+            pc: config.start_addr,
             i: 0x0,
             v: [0; 16],
             stack: LinkedList::new(),
             pressed: HashMap::new(),
-            mem_quirk,
-            vf_reset_quirk,
-            shift_quirk,
+            config,
+            cycles: 0,
+            halted: false,
+            rpl: [0; RPL_FLAGS],
+            last_draw_frame: None,
+            key_wait_cycles: 0,
+            waiting_for_key: false,
+            key_wait_addr: 0,
+            histogram: None,
+            skipped_opcode: None,
         }
     }
 
+    // A CPU starting out with the given registers, I and PC rather than zeroed
+    // ones, for programs which expect a particular initial state.
+    pub fn new_with_state(config: CpuConfig, regs: [u8; 16], i: u16, pc: u16) -> Self {
+        let mut cpu = Cpu::new(config);
+        cpu.v = regs;
+        cpu.i = i;
+        cpu.pc = pc;
+        cpu.cancel_key_wait();
+        return cpu;
+    }
+
+    // Return the CPU to its power-on state. The configuration and the RPL flags,
+    // which are meant to persist, are kept.
+    pub fn reset(&mut self) {
+        self.pc = self.config.start_addr;
+        self.i = 0x0;
+        self.v = [0; 16];
+        self.stack.clear();
+        self.pressed.clear();
+        self.cycles = 0;
+        self.halted = false;
+        self.last_draw_frame = None;
+        self.skipped_opcode = None;
+        self.cancel_key_wait();
+    }
+
+    // Forget about a "Get Key" in progress, for when the PC is moved away from it.
+    fn cancel_key_wait(&mut self) {
+        self.key_wait_cycles = 0;
+        self.waiting_for_key = false;
+        self.key_wait_addr = 0;
+    }
+
     // Get the next instruction from the PC.
     // Big Endian format.
-    pub fn fetch(&mut self, mem: &Memory) -> Result<u16, String> {
-        let byte1 = match mem.read(self.pc.into()) {
-            Ok(byte) => byte,
-            Err(e) => return Err(String::from("Fetch failed") + &e),
+    pub fn fetch(&mut self, mem: &Memory) -> Result<u16, Chip8Error> {
+        // Work out the addresses as usize, so that nothing overflows at the top
+        // of the 64KB XO-CHIP memory.
+        let addr: usize = self.pc.into();
+        let byte1 = mem.read(addr)?;
+        let byte2 = mem.read(addr + 1)?;
+
+        let instruction = ((byte1 as u16) << 8) | byte2 as u16;
+
+        // Increment the PC by 1 instruction immediately. An instruction in the
+        // last 2 bytes of the address space has nowhere to continue to.
+        self.pc = match self.pc.checked_add(2) {
+            Some(pc) => pc,
+            None => return Err(Chip8Error::InvalidAddress(addr + 2)),
         };
 
-        let byte2 = match mem.read((self.pc + 1).into()) {
-            Ok(byte) => byte,
-            Err(e) => return Err(String::from("Fetch failed") + &e),
+        return Ok(instruction);
+    }
+
+    pub fn cycle_count(&self) -> u64 {
+        return self.cycles;
+    }
+
+    // Start or stop counting the executed instructions. The counts carry on
+    // across resets, so that a whole session can be profiled.
+    pub fn set_profiling(&mut self, enabled: bool) {
+        if !enabled {
+            self.histogram = None;
+        } else if self.histogram.is_none() {
+            self.histogram = Some(OpcodeHistogram::default());
+        }
+    }
+
+    pub fn opcode_histogram(&self) -> Option<&OpcodeHistogram> {
+        return self.histogram.as_ref();
+    }
+
+    // Many ROMs finish by jumping to their own address, which halts the CPU when
+    // halt_on_self_jump is set. It also halts on a bad instruction, if configured to.
+    pub fn is_halted(&self) -> bool {
+        return self.halted;
+    }
+
+    // Returns the unknown instruction skipped by the last decode, if any, so
+    // that the caller can decide whether to report it.
+    pub fn take_skipped_opcode(&mut self) -> Option<u16> {
+        return self.skipped_opcode.take();
+    }
+
+    pub fn pc(&self) -> u16 {
+        return self.pc;
+    }
+
+    // Address execution starts from, which is also where the program should be loaded.
+    pub fn start_addr(&self) -> u16 {
+        return self.config.start_addr;
+    }
+
+    pub fn index(&self) -> u16 {
+        return self.i;
+    }
+
+    // Returns a copy of the V0-VF registers.
+    pub fn registers(&self) -> [u8; 16] {
+        return self.v;
+    }
+
+    // Continue execution from |addr|, which has to hold a whole instruction. Odd
+    // addresses are allowed, as some programs place instructions there.
+    pub fn set_pc(&mut self, addr: u16, mem: &Memory) -> Result<i32, Chip8Error> {
+        if addr as usize + 1 >= mem.size() {
+            return Err(Chip8Error::InvalidAddress(addr.into()));
+        }
+
+        self.pc = addr;
+        self.cancel_key_wait();
+        return Ok(0);
+    }
+
+    pub fn get_register(&self, idx: u8) -> Result<u8, Chip8Error> {
+        match self.v.get(idx as usize) {
+            Some(val) => return Ok(*val),
+            None => return Err(Chip8Error::InvalidRegister(idx)),
+        }
+    }
+
+    // Lets embedders poke the registers, e.g. for cheats or a state editor.
+    pub fn set_register(&mut self, idx: u8, val: u8) -> Result<i32, Chip8Error> {
+        match self.v.get_mut(idx as usize) {
+            Some(reg) => *reg = val,
+            None => return Err(Chip8Error::InvalidRegister(idx)),
+        }
+        return Ok(0);
+    }
+
+    // The RPL flags are exposed so that they can be persisted across runs.
+    pub fn rpl_flags(&self) -> [u8; RPL_FLAGS] {
+        return self.rpl;
+    }
+
+    pub fn set_rpl_flags(&mut self, flags: [u8; RPL_FLAGS]) {
+        self.rpl = flags;
+    }
+
+    // Number of subroutine calls that haven't returned yet.
+    pub fn stack_depth(&self) -> usize {
+        return self.stack.len();
+    }
+
+    // Return addresses of the subroutine calls that haven't returned yet, with
+    // the outermost call first.
+    pub fn call_stack(&self) -> Vec<u16> {
+        return self.stack.iter().copied().collect();
+    }
+
+    // Serializes the CPU state into a binary blob with the following layout:
+    // pc (2 bytes) | i (2 bytes) | V0-VF (16 bytes) | pressed keys (16 bytes) |
+    // stack depth (2 bytes) | stack entries (2 bytes each, oldest first)
+    // Multi-byte values are stored in Big Endian format.
+    pub fn snapshot(&self) -> Vec<u8> {
+        let mut blob: Vec<u8> = Vec::new();
+        blob.extend_from_slice(&self.pc.to_be_bytes());
+        blob.extend_from_slice(&self.i.to_be_bytes());
+        blob.extend_from_slice(&self.v);
+
+        // A key can be untracked, released or pressed.
+        for key in 0..=0xF {
+            blob.push(match self.pressed.get(&key) {
+                None => 0,
+                Some(false) => 1,
+                Some(true) => 2,
+            });
+        }
+
+        blob.extend_from_slice(&(self.stack.len() as u16).to_be_bytes());
+        for addr in self.stack.iter() {
+            blob.extend_from_slice(&addr.to_be_bytes());
+        }
+
+        return blob;
+    }
+
+    // Restores the CPU state from a blob created by snapshot(). The state
+    // is left untouched if the blob is malformed.
+    pub fn restore(&mut self, blob: &[u8]) -> Result<i32, Chip8Error> {
+        const PRESSED_OFFSET: usize = 20;
+        const DEPTH_OFFSET: usize = 36;
+        const STACK_OFFSET: usize = 38;
+
+        if blob.len() < STACK_OFFSET {
+            return Err(Chip8Error::InvalidSnapshot(String::from("too short")));
+        }
+
+        let read_u16 = |offset: usize| -> u16 {
+            ((blob[offset] as u16) << 8) | blob[offset + 1] as u16
         };
 
-        let instruction = ((byte1 as u16) << 8) | byte2 as u16;
+        let depth = read_u16(DEPTH_OFFSET) as usize;
+        if blob.len() != STACK_OFFSET + 2 * depth {
+            return Err(Chip8Error::InvalidSnapshot(String::from("invalid size")));
+        }
 
-        // Increment the PC by 1 instruction immediately.
-        self.pc = self.pc + 2;
+        let mut pressed: HashMap<u8, bool> = HashMap::new();
+        for key in 0..=0xF {
+            match blob[PRESSED_OFFSET + key as usize] {
+                0 => {},
+                1 => { pressed.insert(key, false); },
+                2 => { pressed.insert(key, true); },
+                val => return Err(Chip8Error::InvalidSnapshot(format!("invalid key state {}", val))),
+            }
+        }
 
-        return Ok(instruction);
+        self.pc = read_u16(0);
+        self.i = read_u16(2);
+        self.v.copy_from_slice(&blob[4..PRESSED_OFFSET]);
+        self.pressed = pressed;
+        self.stack = (0..depth).map(|ind| read_u16(STACK_OFFSET + 2 * ind)).collect();
+        self.cancel_key_wait();
+
+        return Ok(0);
     }
 
     // Handler for the "Set I" instruction.
@@ -63,40 +353,61 @@ impl Cpu {
     }
 
     fn add_v(&mut self, instr: u16) {
-        let val = instr & 0xFF;
-        let ind = (instr >> 8) & 0xF;
+        let val = (instr & 0xFF) as u8;
+        let ind = ((instr >> 8) & 0xF) as usize;
 
-        let old_reg = self.v[ind as usize] as u32;
-        let new_reg = (old_reg + val as u32) as u8;
         // Don't update the VF register even if there is an overflow.
-        self.v[ind as usize] = new_reg;
+        self.v[ind] = self.v[ind].wrapping_add(val);
     }
 
     fn handle_jump(&mut self, instr: u16) {
-        self.pc = instr & 0xFFF;
+        let addr = instr & 0xFFF;
+        // The PC has already moved past the jump, so check against the previous instruction.
+        if self.config.halt_on_self_jump && addr == self.pc.wrapping_sub(2) {
+            self.halted = true;
+        }
+
+        self.pc = addr;
     }
 
-    fn subroutine(&mut self, instr: u16) {
+    fn subroutine(&mut self, instr: u16) -> Result<i32, Chip8Error> {
+        if self.stack.len() >= self.config.max_stack_depth {
+            return Err(Chip8Error::StackOverflow);
+        }
+
         let addr = instr & 0xFFF;
         self.stack.push_back(self.pc);
 
         self.pc = addr;
+        return Ok(0);
     }
 
-    fn return_routine(&mut self) {
+    fn return_routine(&mut self) -> Result<i32, Chip8Error> {
         if let Some(addr) = self.stack.pop_back() {
             self.pc = addr;
+            return Ok(0);
         } else {
-            panic!("Trying to pop and empty stack, can't return.")
+            return Err(Chip8Error::StackUnderflow);
         }
     }
 
+    // PC arithmetic wraps around the 16 bit address space rather than
+    // overflowing. Past the end of a smaller memory, the next fetch fails instead.
+    fn skip_instruction(&mut self) {
+        self.pc = self.pc.wrapping_add(2);
+    }
+
+    // Go back to the instruction that was just fetched, so it runs again.
+    fn repeat_instruction(&mut self) {
+        self.pc = self.pc.wrapping_sub(2);
+    }
+
     fn skip_vx_equal(&mut self, instr: u16) {
         let val = instr & 0xFF;
         let x = (instr >> 8) & 0xF;
 
         if self.v[x as usize] == val as u8 {
-            self.pc = self.pc + 2;
+            self.skip_instruction();
         }
     }
 
@@ -105,26 +416,37 @@ impl Cpu {
         let x = (instr >> 8) & 0xF;
 
         if self.v[x as usize] != val as u8 {
-            self.pc = self.pc + 2;
+            self.skip_instruction();
         }
     }
 
-    fn skip_vx_vy_equal(&mut self, instr: u16) {
+    // 5XY0 and 9XY0 must end in a 0.
+    fn skip_vx_vy_equal(&mut self, instr: u16) -> Result<i32, Chip8Error> {
+        if instr & 0xF != 0 {
+            return Err(Chip8Error::UnknownOpcode(instr));
+        }
+
         let x = (instr >> 8) & 0xF;
         let y = (instr >> 4) & 0xF;
 
         if self.v[x as usize] == self.v[y as usize] {
-            self.pc = self.pc + 2;
+            self.skip_instruction();
         }
+        return Ok(0);
     }
 
-    fn skip_vx_vy_not_equal(&mut self, instr: u16) {
+    fn skip_vx_vy_not_equal(&mut self, instr: u16) -> Result<i32, Chip8Error> {
+        if instr & 0xF != 0 {
+            return Err(Chip8Error::UnknownOpcode(instr));
+        }
+
         let x = (instr >> 8) & 0xF;
         let y = (instr >> 4) & 0xF;
 
         if self.v[x as usize] != self.v[y as usize] {
-            self.pc = self.pc + 2;
+            self.skip_instruction();
         }
+        return Ok(0);
     }
 
     fn set_vx_to_vy(&mut self, instr: u16) {
@@ -141,15 +463,11 @@ impl Cpu {
 
         let vx = self.v[x_ind as usize];
         let vy = self.v[y_ind as usize];
-  
-        if vx > vy {
-            self.v[0xF] = 1;
-        } else {
-            self.v[0xF] = 0;
-        }
 
-        let result = vx.wrapping_sub(vy);
-        self.v[x_ind as usize] = result;
+        // The flag is written last, so that it wins when VF is the destination.
+        let flag = if vx > vy { 1 } else { 0 };
+        self.v[x_ind as usize] = vx.wrapping_sub(vy);
+        self.v[0xF] = flag;
     }
 
     fn arith_vx_plus_vy(&mut self, instr: u16) {
@@ -159,15 +477,11 @@ impl Cpu {
         let vx = self.v[x_ind as usize];
         let vy = self.v[y_ind as usize];
 
-        let result: u16 = vx as u16 + vy as u16;
-        if result > 255 {
-            self.v[0xF] = 1;
-        } else {
-            self.v[0xF] = 0;
-        }
-
-        let result = vx.wrapping_add(vy);
+        // Work out the carry from the operands, and set VF after the result so
+        // that the flag is kept when VF is the destination.
+        let (result, carry) = vx.overflowing_add(vy);
         self.v[x_ind as usize] = result;
+        self.v[0xF] = carry as u8;
     }
 
     fn arith_vy_minus_vx(&mut self, instr: u16) {
@@ -177,14 +491,9 @@ impl Cpu {
         let vx = self.v[x_ind as usize];
         let vy = self.v[y_ind as usize];
 
-        if vy > vx {
-            self.v[0xF] = 1;
-        } else {
-            self.v[0xF] = 0;
-        }
-
-        let result = vy.wrapping_sub(vx);
-        self.v[x_ind as usize] = result;
+        let flag = if vy > vx { 1 } else { 0 };
+        self.v[x_ind as usize] = vy.wrapping_sub(vx);
+        self.v[0xF] = flag;
     }
 
     fn logic_vx_or_vy(&mut self, instr: u16) {
@@ -196,7 +505,7 @@ impl Cpu {
 
         self.v[x_ind as usize] = vx | vy;
 
-        if self.vf_reset_quirk {
+        if self.config.logic_resets_vf {
             self.v[0xF] = 0;
         }
     }
@@ -210,7 +519,7 @@ impl Cpu {
 
         self.v[x_ind as usize] = vx & vy;
 
-        if self.vf_reset_quirk {
+        if self.config.logic_resets_vf {
             self.v[0xF] = 0;
         }
     }
@@ -224,7 +533,7 @@ impl Cpu {
 
         self.v[x_ind as usize] = vx ^ vy;
 
-        if self.vf_reset_quirk {
+        if self.config.logic_resets_vf {
             self.v[0xF] = 0;
         }
     }
@@ -233,41 +542,32 @@ impl Cpu {
         let x_ind = (instr >> 8) & 0xF;
         let y_ind = (instr >> 4) & 0xF;
 
-        if self.shift_quirk {
+        if self.config.shift_uses_vy {
             self.v[x_ind as usize] = self.v[y_ind as usize];
         }
 
         let vx = self.v[x_ind as usize];
 
-        if (vx & 0x80) >> 0x7 == 1 {
-            self.v[0xF] = 1;
-        } else {
-            self.v[0xF] = 0;
-        }
-
+        // The shifted out bit is written last, so that it wins when VF is the destination.
         self.v[x_ind as usize] = vx << 1;
+        self.v[0xF] = (vx & 0x80) >> 0x7;
     }
 
     fn right_shift(&mut self, instr: u16) {
         let x_ind = (instr >> 8) & 0xF;
         let y_ind = (instr >> 4) & 0xF;
 
-        if self.shift_quirk {
+        if self.config.shift_uses_vy {
             self.v[x_ind as usize] = self.v[y_ind as usize];
         }
 
         let vx = self.v[x_ind as usize];
 
-        if (vx & 0x1) == 1 {
-            self.v[0xF] = 1;
-        } else {
-            self.v[0xF] = 0;
-        }
-
         self.v[x_ind as usize] = vx >> 1;
+        self.v[0xF] = vx & 0x1;
     }
 
-    fn handle_logic_arith(&mut self, instr: u16) -> Result<i32, String> {
+    fn handle_logic_arith(&mut self, instr: u16) -> Result<i32, Chip8Error> {
         match instr & 0xF {
             0 => self.set_vx_to_vy(instr),
             5 => self.arith_vx_minus_vy(instr),
@@ -278,7 +578,7 @@ impl Cpu {
             3 => self.logic_vx_xor_vy(instr),
             4 => self.arith_vx_plus_vy(instr),
             0xE => self.left_shift(instr),
-            _ => return Err(String::from("Unhandled instruction: 0x") + format!("{:X}", &instr).as_str()),
+            _ => return Err(Chip8Error::UnknownOpcode(instr)),
         }
         return Ok(0);
     }
@@ -293,31 +593,92 @@ impl Cpu {
         self.i = mem.get_font_addr(chr) as u16;
     }
 
-    fn store(&mut self, instr: u16, mem: &mut Memory) {
-        // TODO: Add config to update the i with each copy.
+    // SUPER-CHIP FX30 instruction, points I at the 8x10 glyph for VX.
+    fn big_font_character(&mut self, instr: u16, mem: &Memory) {
+        let chr = self.get_font_char(instr);
+        self.i = mem.get_big_font_addr(chr) as u16;
+    }
+
+    fn store(&mut self, instr: u16, mem: &mut Memory) -> Result<i32, Chip8Error> {
         let ind = (instr >> 8)  & 0xF;
         for i in 0..=ind {
-            mem.mem[(self.i + i) as usize] = self.v[i as usize];
+            mem.write(self.i as usize + i as usize, self.v[i as usize])?;
         }
 
-        if self.mem_quirk {
-            self.i += ind + 1;
-        }
+        self.increment_i_after_transfer(ind);
+        return Ok(0);
     }
 
-    fn load(&mut self, instr: u16, mem: &Memory) {
-        // TODO: Add config to update the i with each copy.
+    fn load(&mut self, instr: u16, mem: &Memory) -> Result<i32, Chip8Error> {
         let ind = (instr >> 8)  & 0xF;
         for i in 0..=ind {
-            self.v[i as usize] = mem.mem[(self.i + i) as usize];
+            self.v[i as usize] = mem.read(self.i as usize + i as usize)?;
         }
 
-        if self.mem_quirk {
-            self.i += ind + 1;
+        self.increment_i_after_transfer(ind);
+        return Ok(0);
+    }
+
+    // Update I after registers V0 to V|x| were stored or loaded.
+    fn increment_i_after_transfer(&mut self, x: u16) {
+        match self.config.mem_increment {
+            MemIncrement::Unchanged => {},
+            MemIncrement::IncrementByX => self.i = self.i.wrapping_add(x),
+            MemIncrement::IncrementByXPlus1 => self.i = self.i.wrapping_add(x + 1),
         }
     }
 
-    fn bcd(&self, instr: u16, mem: &mut Memory) {
+    // FX75: Store V0 to VX in the RPL flags.
+    fn store_rpl(&mut self, instr: u16) -> Result<i32, Chip8Error> {
+        let x = ((instr >> 8) & 0xF) as usize;
+        if x >= RPL_FLAGS {
+            return Err(Chip8Error::UnknownOpcode(instr));
+        }
+
+        self.rpl[..=x].copy_from_slice(&self.v[..=x]);
+        return Ok(0);
+    }
+
+    // FX85: Load V0 to VX from the RPL flags.
+    fn load_rpl(&mut self, instr: u16) -> Result<i32, Chip8Error> {
+        let x = ((instr >> 8) & 0xF) as usize;
+        if x >= RPL_FLAGS {
+            return Err(Chip8Error::UnknownOpcode(instr));
+        }
+
+        self.v[..=x].copy_from_slice(&self.rpl[..=x]);
+        return Ok(0);
+    }
+
+    // XO-CHIP F000 NNNN: Load the 16 bit address in the following word into I.
+    fn load_i_long(&mut self, mem: &Memory) -> Result<(), Chip8Error> {
+        let byte1 = mem.read(self.pc as usize)?;
+        let byte2 = mem.read(self.pc as usize + 1)?;
+        self.i = ((byte1 as u16) << 8) | byte2 as u16;
+
+        // Skip over the address word.
+        self.skip_instruction();
+        return Ok(());
+    }
+
+    // XO-CHIP F002 instruction, loads the audio pattern from memory at I.
+    fn load_audio_pattern(&self, mem: &Memory, timer: &Arc<Timer>) -> Result<(), Chip8Error> {
+        let mut pattern = [0; PATTERN_SIZE];
+        for (ind, byte) in pattern.iter_mut().enumerate() {
+            *byte = mem.read(self.i as usize + ind)?;
+        }
+
+        Timer::set_audio_pattern(timer, pattern);
+        return Ok(());
+    }
+
+    // XO-CHIP FX3A instruction, sets the audio pattern playback pitch to VX.
+    fn set_pitch(&self, instr: u16, timer: &Arc<Timer>) {
+        let x_ind = (instr >> 8) & 0xF;
+        Timer::set_pitch(timer, self.v[x_ind as usize]);
+    }
+
+    fn bcd(&self, instr: u16, mem: &mut Memory) -> Result<i32, Chip8Error> {
         let x = (instr >> 8) & 0xF;
         let mut val = self.v[x as usize];
 
@@ -327,9 +688,11 @@ impl Cpu {
         val = val / 10;
         let digit1 = val % 10;
 
-        mem.mem[self.i as usize] = digit1;
-        mem.mem[(self.i + 1) as usize] = digit2;
-        mem.mem[(self.i + 2) as usize] = digit3;
+        mem.write(self.i as usize, digit1)?;
+        mem.write(self.i as usize + 1, digit2)?;
+        mem.write(self.i as usize + 2, digit3)?;
+
+        return Ok(0);
     }
 
     fn increment_i(&mut self, instr: u16) {
@@ -338,19 +701,20 @@ impl Cpu {
 
         let old_i = self.i as u32;
         let result = old_i + val as u32;
-        if result >= 4096 {
-            self.v[0xF] = 1
-        }
         self.i = (result & 0xFFFF) as u16;
+        // Only some interpreters (e.g. the Amiga one) flag the overflow.
+        if self.config.fx1e_sets_vf {
+            self.v[0xF] = (result >= 4096) as u8;
+        }
     }
 
-    fn set_delay(&self, instr: u16, timer: &mut Arc<Timer>) {
+    fn set_delay(&self, instr: u16, timer: &Arc<Timer>) {
         let x_ind = (instr >> 8) & 0xF;
         let val = self.v[x_ind as usize];
         Timer::set_delay(timer, val);
     }
 
-    fn set_sound(&self, instr: u16, timer: &mut Arc<Timer>) {
+    fn set_sound(&self, instr: u16, timer: &Arc<Timer>) {
         let x_ind = (instr >> 8) & 0xF;
         let val = self.v[x_ind as usize];
         Timer::set_sound(timer, val);
@@ -376,19 +740,64 @@ impl Cpu {
         return new_pressed;
     }
 
-    fn check_key_state(&mut self, new_pressed: HashMap<u8, bool>, instr: u16) {
-        for (k, v) in self.pressed.iter() {
-            // Found a pressed key which was then released.
-            if *v == true && *(new_pressed.get(k).unwrap()) == false {
-                let x_ind = instr >> 8 & 0xF;
-                self.v[x_ind as usize] = *k;
-                self.pressed.clear();
+    // |pressed| holds the keypad state from the previous check, and is empty when
+    // no "Get Key" is in progress. |released| is the mask of keys the display saw
+    // being released since the previous check, which catches a key that was
+    // pressed and released in between two checks.
+    fn check_key_state(&mut self, new_pressed: HashMap<u8, bool>, released: u16, instr: u16) {
+        let x_ind = (instr >> 8 & 0xF) as usize;
+
+        // Releases from before we started waiting don't count.
+        if !self.pressed.is_empty() {
+            let was_released = |key: &u8| -> bool {
+                // Keys missing from either map count as not pressed.
+                let was_held = self.pressed.get(key).copied().unwrap_or(false);
+                let held = was_held && !new_pressed.get(key).copied().unwrap_or(false);
+                return held || (released >> key) & 0x1 == 0x1;
+            };
+
+            if let Some(key) = (0..=0xF).find(was_released) {
+                self.v[x_ind] = key;
+                self.finish_key_wait(new_pressed, key);
                 return;
             }
         }
 
+        if Cpu::key_wait_timed_out(self.key_wait_cycles, self.config.key_timeout) {
+            self.v[x_ind] = KEY_TIMEOUT_VALUE;
+            self.finish_key_wait(new_pressed, KEY_TIMEOUT_VALUE);
+            return;
+        }
+
+        // Remember where the instruction is when we start waiting, so that each
+        // re-poll goes back to the same address however the PC got here.
+        if !self.waiting_for_key {
+            self.waiting_for_key = true;
+            self.key_wait_addr = self.pc.wrapping_sub(2);
+        }
+        self.key_wait_cycles += 1;
         self.pressed = new_pressed;
-        self.pc -= 2;
+        self.pc = self.key_wait_addr;
+    }
+
+    // Only the released key is consumed, any other keys which are still held
+    // stay tracked for the next "Get Key".
+    fn finish_key_wait(&mut self, new_pressed: HashMap<u8, bool>, key: u8) {
+        self.pressed = new_pressed.into_iter().filter(|(k, v)| *v && *k != key).collect();
+        self.key_wait_cycles = 0;
+        // Continue after the "Get Key", exactly once however many times it polled.
+        if self.waiting_for_key {
+            self.waiting_for_key = false;
+            self.pc = self.key_wait_addr.wrapping_add(2);
+        }
+    }
+
+    // Whether a "Get Key" which has already waited for |waited| cycles should give up.
+    fn key_wait_timed_out(waited: u64, timeout: Option<u64>) -> bool {
+        match timeout {
+            Some(timeout) => return waited >= timeout,
+            None => return false,
+        }
     }
 
     // The only way to reasonably achieve this, is to get the entire keypad
@@ -396,57 +805,74 @@ impl Cpu {
     // If any key which was pressed is now not pressed, we register that as a keypress.
     fn get_key(&mut self, instr: u16, disp: &Arc<Display>) {
         let new_pressed = Cpu::get_new_key_pressed_state(disp);
+        let released = Display::take_released_keys(disp);
 
-        self.check_key_state(new_pressed, instr);
+        self.check_key_state(new_pressed, released, instr);
     }
 
     fn handle_f_instructions(&mut self, instr: u16, mem: Option<&mut Memory>,
-        timer: Option<&mut Arc<Timer>>, disp: Option<&Arc<Display>>) -> Result<i32, String> {
+        timer: Option<&mut Arc<Timer>>, disp: Option<&Arc<Display>>) -> Result<i32, Chip8Error> {
+        let timer = timer.as_deref();
         match instr & 0xFF {
-            0x0A => self.get_key(instr, disp.unwrap()),
-            0x18 => self.set_sound(instr, timer.unwrap()),
-            0x07 => self.get_delay(instr, &*timer.unwrap()),
-            0x15 => self.set_delay(instr, timer.unwrap()),
+            0x0A => self.get_key(instr, Cpu::require(disp, "display")?),
+            0x18 => self.set_sound(instr, Cpu::require(timer, "timer")?),
+            0x07 => self.get_delay(instr, Cpu::require(timer, "timer")?),
+            0x15 => self.set_delay(instr, Cpu::require(timer, "timer")?),
             0x1E => self.increment_i(instr),
-            0x29 => self.font_character(instr, &*mem.unwrap()),
-            0x33 => self.bcd(instr, mem.unwrap()),
-            0x55 => self.store(instr, mem.unwrap()),
-            0x65 => self.load(instr, mem.unwrap()),
-            _ => return Err(String::from("Unhandled instruction: 0x")  + format!("{:X}", &instr).as_str())
+            0x29 => self.font_character(instr, Cpu::require(mem, "memory")?),
+            0x30 => self.big_font_character(instr, Cpu::require(mem, "memory")?),
+            0x33 => { self.bcd(instr, Cpu::require(mem, "memory")?)?; },
+            0x55 => { self.store(instr, Cpu::require(mem, "memory")?)?; },
+            0x65 => { self.load(instr, Cpu::require(mem, "memory")?)?; },
+            0x75 => { self.store_rpl(instr)?; },
+            0x85 => { self.load_rpl(instr)?; },
+            0x00 if instr == 0xF000 => self.load_i_long(Cpu::require(mem, "memory")?)?,
+            0x02 if instr == 0xF002 => {
+                self.load_audio_pattern(Cpu::require(mem, "memory")?, Cpu::require(timer, "timer")?)?;
+            },
+            0x3A => self.set_pitch(instr, Cpu::require(timer, "timer")?),
+            0x01 => { Display::set_planes(Cpu::require(disp, "display")?, ((instr >> 8) & 0xF) as u8)?; },
+            _ => return Err(Chip8Error::UnknownOpcode(instr)),
         }
         return Ok(0);
     }
 
-    fn key_pressed(&mut self, instr: u16, disp: &Arc<Display>) -> Result<i32, String> {
+    // Returns the machine component an instruction needs, or an error if it
+    // wasn't passed to decode().
+    fn require<T>(component: Option<T>, name: &'static str) -> Result<T, Chip8Error> {
+        return component.ok_or(Chip8Error::MissingComponent(name));
+    }
+
+    fn key_pressed(&mut self, instr: u16, disp: &Arc<Display>) -> Result<i32, Chip8Error> {
         let x_ind = instr >> 8 & 0xF;
         let vx = self.v[x_ind as usize];
 
         let key_state = Display::get_key_state(disp, vx)?;
         if key_state == true {
-            self.pc += 2;
+            self.skip_instruction();
         }
 
         return Ok(0);
     }
 
-    fn key_not_pressed(&mut self, instr: u16, disp: &Arc<Display>) -> Result<i32, String> {
+    fn key_not_pressed(&mut self, instr: u16, disp: &Arc<Display>) -> Result<i32, Chip8Error> {
         let x_ind = instr >> 8 & 0xF;
         let vx = self.v[x_ind as usize];
 
         let key_state = Display::get_key_state(disp, vx)?;
 
         if key_state == false {
-            self.pc += 2;
+            self.skip_instruction();
         }
 
         return Ok(0);
     }
 
-    fn handle_e_instructions(&mut self, instr: u16, disp: &Arc<Display>) -> Result<i32, String> {
+    fn handle_e_instructions(&mut self, instr: u16, disp: &Arc<Display>) -> Result<i32, Chip8Error> {
         match instr & 0xFF {
             0x9E => { self.key_pressed(instr, disp)?; },
             0xA1 => { self.key_not_pressed(instr, disp)?; },
-            _ => return Err(format!("Unhandled instruction: 0x{:X}", instr)),
+            _ => return Err(Chip8Error::UnknownOpcode(instr)),
         }
         return Ok(0);
     }
@@ -462,20 +888,28 @@ impl Cpu {
        the display module can effectively unit test the display logic (part 2)
        of the code.
     */
-    fn get_sprite(&self, instr: u16, mem: &Memory) -> (u8, u8, Vec<u8>) {
+    // With several XO-CHIP planes selected, the sprite data for each plane follows one another.
+    fn get_sprite(&self, instr: u16, mem: &Memory, width: usize, height: usize, planes: usize) -> Result<(u8, u8, Vec<u8>), Chip8Error> {
         let x_reg_ind = ((instr >> 8) & 0xF) as usize;
         let y_reg_ind = ((instr >> 4) & 0xF) as usize;
 
-        let x = self.v[x_reg_ind] % (WIDTH as u8);
-        let y = self.v[y_reg_ind] % (HEIGHT as u8);
-        let n = instr & 0xF;
+        // The starting coordinates wrap according to the active resolution.
+        let x = self.v[x_reg_ind] % (width as u8);
+        let y = self.v[y_reg_ind] % (height as u8);
+        let n = if Cpu::is_big_sprite(instr, width) { 32 } else { instr & 0xF };
+        let n = n * planes as u16;
 
         let mut sprite: Vec<u8> = Vec::new();
         for ind in 0..n {
-            sprite.push(mem.mem[self.i as usize + ind as usize])
+            sprite.push(mem.read(self.i as usize + ind as usize)?)
         }
 
-        return (x, y, sprite);
+        return Ok((x, y, sprite));
+    }
+
+    // SUPER-CHIP draws a 16x16 sprite for DXY0, but only in high resolution mode.
+    fn is_big_sprite(instr: u16, width: usize) -> bool {
+        return instr & 0xF == 0 && width == HIRES_WIDTH;
     }
 
     fn random(&mut self, instr: u16) {
@@ -486,54 +920,99 @@ impl Cpu {
         self.v[x_ind as usize] = random_num & nn;
     }
 
+    // Jump with offset. The original interpreter adds V0 to NNN, while SUPER-CHIP
+    // treats the instruction as BXNN and adds VX instead.
     fn branch(&mut self, instr: u16) {
         let nnn = instr & 0xFFF;
-        let ind = 0;
-        self.pc = nnn + self.v[ind as usize] as u16;
-    }
+        let ind = if self.config.jump_quirk { (instr >> 8) & 0xF } else { 0 };
+        self.pc = (nnn + self.v[ind as usize] as u16) & 0xFFF;
+    }
+
+    fn handle_draw(&mut self, instr: u16, mem: &Memory, disp: &Arc<Display>) -> Result<i32, Chip8Error> {
+        // The original interpreter waited for the vertical blank before drawing. If
+        // we've already drawn during this frame, hold on the draw until the next one.
+        if self.config.draw_wait_vblank {
+            let frame = Display::frame_count(disp);
+            if self.last_draw_frame == Some(frame) {
+                self.repeat_instruction();
+                return Ok(0);
+            }
+            self.last_draw_frame = Some(frame);
+        }
 
-    fn handle_draw(&mut self, instr: u16, mem: Option<&Memory>, disp: &Arc<Display>) {
-        let (x, y, sprite) =self.get_sprite(instr, mem.unwrap());
-        self.v[0xf] = Display::draw(disp, x, y, &sprite);
+        let (width, height) = Display::resolution(disp);
+        let planes = Display::plane_count(disp);
+        let (x, y, sprite) = self.get_sprite(instr, mem, width, height, planes)?;
+        let sprite_width = if Cpu::is_big_sprite(instr, width) { 16 } else { 8 };
+        self.v[0xf] = Display::draw(disp, x, y, &sprite, sprite_width);
+
+        return Ok(0);
     }
 
     pub fn decode(&mut self, instr: u16, disp: Option<&Arc<Display>>, mem: Option<&mut Memory>,
-        timer: Option<&mut Arc<Timer>>) -> Result<i32, String>{
+        timer: Option<&mut Arc<Timer>>) -> Result<i32, Chip8Error> {
+        match self.execute(instr, disp, mem, timer) {
+            // The PC has already moved past the instruction, so skipping it is
+            // just a matter of not failing.
+            Err(Chip8Error::UnknownOpcode(op)) => match self.config.on_unknown_opcode {
+                BadOpcodePolicy::Halt => {
+                    self.halted = true;
+                    return Ok(0);
+                },
+                BadOpcodePolicy::Skip => {
+                    self.skipped_opcode = Some(op);
+                    return Ok(0);
+                },
+                BadOpcodePolicy::Error => return Err(Chip8Error::UnknownOpcode(op)),
+            },
+            result => return result,
+        }
+    }
+
+    fn execute(&mut self, instr: u16, disp: Option<&Arc<Display>>, mem: Option<&mut Memory>,
+        timer: Option<&mut Arc<Timer>>) -> Result<i32, Chip8Error>{
             match instr {
-            0x00e0 => if let Some(disp) = disp {
-                Display::clear(disp);
+            0x00e0 => Display::clear(Cpu::require(disp, "display")?),
+            0x00fb => Display::scroll_right(Cpu::require(disp, "display")?),
+            0x00fc => Display::scroll_left(Cpu::require(disp, "display")?),
+            0x00fe => Display::set_hires(Cpu::require(disp, "display")?, false),
+            0x00ff => Display::set_hires(Cpu::require(disp, "display")?, true),
+            0x00ee => { self.return_routine()?; },
+            instr2 if (instr2 & 0xFFF0) == 0x00C0 => {
+                Display::scroll_down(Cpu::require(disp, "display")?, (instr2 & 0xF) as usize);
             },
-            0x00ee => self.return_routine(),
             instr2 => {
                 match (instr2 >> 12) & 0xF {
+                    // 0NNN called a machine code routine on the original hardware,
+                    // which modern interpreters ignore.
+                    0x0 => {},
                     0x1 => self.handle_jump(instr2),
-                    0x2 => self.subroutine(instr),
+                    0x2 => { self.subroutine(instr)?; },
                     0x3 => self.skip_vx_equal(instr2),
                     0x4 => self.skip_vx_ne(instr2),
-                    0x5 => self.skip_vx_vy_equal(instr2),
-                    0x9 => self.skip_vx_vy_not_equal(instr2),
+                    0x5 => { self.skip_vx_vy_equal(instr2)?; },
+                    0x9 => { self.skip_vx_vy_not_equal(instr2)?; },
                     0xA => self.set_i(instr2),
                     0x6 => self.set_v(instr2),
                     0x7 => self.add_v(instr2),
-                    0x8 => if let Err(e) = self.handle_logic_arith(instr2) {
-                        return Err(e);
-                    },
+                    0x8 => { self.handle_logic_arith(instr2)?; },
                     0xB => self.branch(instr2),
                     0xC => self.random(instr2),
-                    0xD => self.handle_draw(instr2, Some(&*mem.unwrap()), &mut disp.unwrap()),
-                    0xE => if let Some(disp) =  disp {
-                        self.handle_e_instructions(instr, disp)?;
+                    0xD => {
+                        let mem = Cpu::require(mem, "memory")?;
+                        self.handle_draw(instr2, mem, Cpu::require(disp, "display")?)?;
                     },
-                    0xF => if let Err(e) = self.handle_f_instructions(instr2, mem, timer, disp) {
-                        return Err(e);
-                    }
-                    _ => {
-                        return Err(String::from("Unknown instruction: 0x") + format!("{:X}", &instr2).as_str());
-                    }
+                    0xE => { self.handle_e_instructions(instr, Cpu::require(disp, "display")?)?; },
+                    0xF => { self.handle_f_instructions(instr2, mem, timer, disp)?; },
+                    _ => return Err(Chip8Error::UnknownOpcode(instr2)),
                 }
             }
 
 
+        }
+        self.cycles += 1;
+        if let Some(histogram) = &mut self.histogram {
+            histogram.record(instr);
         }
         return Ok(0);
     }
@@ -544,13 +1023,16 @@ impl Cpu {
 mod tests {
     use std::collections::HashMap;
 
-    use super::{Memory, Cpu, PROGRAM_ADDRESS};
+    use crate::display::display::{Display, WIDTH, HEIGHT, HIRES_WIDTH, HIRES_HEIGHT};
+    use crate::{audio::audio::AudioConfig, mem::mem::MEM_SIZE};
+
+    use super::{Memory, Cpu, CpuConfig, MemIncrement, BadOpcodePolicy, Timer, Chip8Error, PROGRAM_ADDRESS, KEY_TIMEOUT_VALUE};
 
     #[test]
     // Verify that two consecutive fetches work correctly.
     fn check_fetch() {
-        let mut cpu = Cpu::new(false, false, false);
-        let mut mem_array: [u8; 4096] = [0; 4096];
+        let mut cpu = Cpu::new(CpuConfig::default());
+        let mut mem_array: [u8; MEM_SIZE] = [0; MEM_SIZE];
 
         let instr1: u16 = 0x00E0;
         let instr2: u16 = 0x70AB;
@@ -560,9 +1042,7 @@ mod tests {
         mem_array[(PROGRAM_ADDRESS + 2) as usize] = ((instr2 >> 8) & 0xFF) as u8;
         mem_array[(PROGRAM_ADDRESS + 3) as usize] = (instr2 & 0xFF) as u8;
 
-        let mem = Memory {
-            mem: mem_array,
-        };
+        let mem = Memory::from_bytes(mem_array);
 
         assert_eq!(cpu.fetch(&mem).unwrap(), instr1);
         assert_eq!(cpu.pc, PROGRAM_ADDRESS + 2);
@@ -571,37 +1051,358 @@ mod tests {
 
     #[test]
     fn fetch_invalid_addr() {
-        let mut cpu = Cpu::new(false, false, false);
-        let mem = Memory {
-            mem: [0; 4096],
-        };
+        let mut cpu = Cpu::new(CpuConfig::default());
+        let mem = Memory::from_bytes([0; MEM_SIZE]);
     
-        cpu.pc = 4096 + 10;
-        assert!(cpu.fetch(&mem).is_err());
+        cpu.pc = (MEM_SIZE + 10) as u16;
+        assert_eq!(cpu.fetch(&mem), Err(Chip8Error::InvalidAddress(MEM_SIZE + 10)));
+    }
+
+    #[test]
+    fn fetch_last_byte() {
+        let mut cpu = Cpu::new(CpuConfig::default());
+        let mut mem_array = [0; MEM_SIZE];
+        mem_array[MEM_SIZE - 1] = 0x12;
+        let mem = Memory::from_bytes(mem_array);
+
+        // Only half of the instruction is in memory.
+        cpu.pc = (MEM_SIZE - 1) as u16;
+        assert_eq!(cpu.fetch(&mem), Err(Chip8Error::InvalidAddress(MEM_SIZE)));
+        assert_eq!(cpu.pc, (MEM_SIZE - 1) as u16);
+
+        // The last full instruction can still be fetched.
+        cpu.pc = (MEM_SIZE - 2) as u16;
+        assert_eq!(cpu.fetch(&mem), Ok(0x0012));
+        assert_eq!(cpu.pc as usize, MEM_SIZE);
+
+        // At the top of the XO-CHIP memory, the PC would overflow.
+        let mem = Memory::new_xo_chip();
+        cpu.pc = 0xFFFF;
+        assert_eq!(cpu.fetch(&mem), Err(Chip8Error::InvalidAddress(0x10000)));
+        cpu.pc = 0xFFFE;
+        assert_eq!(cpu.fetch(&mem), Err(Chip8Error::InvalidAddress(0x10000)));
+        assert_eq!(cpu.pc, 0xFFFE);
+    }
+
+    #[test]
+    fn decode_missing_component() {
+        let mut cpu = Cpu::new(CpuConfig::default());
+        let mut mem = Memory::new();
+        let timer_err = Err(Chip8Error::MissingComponent("timer"));
+        assert_eq!(cpu.decode(0xF307, None, Some(&mut mem), None), timer_err);
+        assert_eq!(cpu.decode(0xF315, None, Some(&mut mem), None), timer_err);
+        assert_eq!(cpu.decode(0xF318, None, None, None), timer_err);
+        assert_eq!(cpu.decode(0xF355, None, None, None), Err(Chip8Error::MissingComponent("memory")));
+        assert_eq!(cpu.decode(0xF30A, None, None, None), Err(Chip8Error::MissingComponent("display")));
+        assert_eq!(cpu.decode(0xD125, None, Some(&mut mem), None), Err(Chip8Error::MissingComponent("display")));
+        assert_eq!(cpu.decode(0xD125, None, None, None), Err(Chip8Error::MissingComponent("memory")));
+        assert_eq!(cpu.decode(0xE39E, None, None, None), Err(Chip8Error::MissingComponent("display")));
+        assert_eq!(cpu.decode(0xE3A1, None, None, None), Err(Chip8Error::MissingComponent("display")));
+        for instr in [0x00E0, 0x00FB, 0x00FC, 0x00FE, 0x00FF, 0x00C4] {
+            assert_eq!(cpu.decode(instr, None, None, None), Err(Chip8Error::MissingComponent("display")));
+        }
+        // Failed decodes aren't counted.
+        assert_eq!(cpu.cycle_count(), 0);
+    }
+
+    #[test]
+    fn opcode_histogram() {
+        let mut cpu = Cpu::new(CpuConfig::default());
+        assert!(cpu.opcode_histogram().is_none());
+        assert!(cpu.decode(0x6005, None, None, None).is_ok());
+
+        cpu.set_profiling(true);
+        let program = [0x6005, 0x6103, 0x8014, 0x8015, 0x8014, 0xA300, 0xF01E, 0xF11E, 0xF265, 0x00E0];
+        let mut mem = Memory::new();
+        let disp = Display::new(true);
+        for instr in program.iter() {
+            assert!(cpu.decode(*instr, Some(&disp), Some(&mut mem), None).is_ok());
+        }
+        // Failed decodes aren't counted.
+        assert!(cpu.decode(0x8008, None, None, None).is_err());
+
+        let histogram = cpu.opcode_histogram().unwrap();
+        assert_eq!(histogram.families[0x0], 1);
+        assert_eq!(histogram.families[0x6], 2);
+        assert_eq!(histogram.families[0x8], 3);
+        assert_eq!(histogram.families[0xA], 1);
+        assert_eq!(histogram.families[0xF], 3);
+        assert_eq!(histogram.families.iter().sum::<u64>(), program.len() as u64);
+        assert_eq!(histogram.logic[0x4], 2);
+        assert_eq!(histogram.logic[0x5], 1);
+        assert_eq!(histogram.logic[0x8], 0);
+        assert_eq!(histogram.misc.get(&0x1E), Some(&2));
+        assert_eq!(histogram.misc.get(&0x65), Some(&1));
+        assert_eq!(histogram.misc.len(), 2);
+
+        // Profiling keeps going across a reset.
+        cpu.reset();
+        assert_eq!(cpu.opcode_histogram().unwrap().families[0x6], 2);
+
+        cpu.set_profiling(false);
+        assert!(cpu.opcode_histogram().is_none());
+    }
+
+    #[test]
+    fn set_pc() {
+        let mut cpu = Cpu::new(CpuConfig::default());
+        let mem = Memory::new();
+        assert_eq!(cpu.set_pc(0x3A0, &mem), Ok(0));
+        assert_eq!(cpu.pc(), 0x3A0);
+        assert_eq!(cpu.set_pc(0x3A1, &mem), Ok(0));
+        assert_eq!(cpu.set_pc(0xFFE, &mem), Ok(0));
+        assert_eq!(cpu.pc(), 0xFFE);
+
+        // The instruction has to fit in memory.
+        assert_eq!(cpu.set_pc(0xFFF, &mem), Err(Chip8Error::InvalidAddress(0xFFF)));
+        assert_eq!(cpu.set_pc(0x1000, &mem), Err(Chip8Error::InvalidAddress(0x1000)));
+        assert_eq!(cpu.pc(), 0xFFE);
+
+        // XO-CHIP has the whole 16 bit address space.
+        assert_eq!(cpu.set_pc(0xFFFE, &Memory::new_xo_chip()), Ok(0));
+        assert_eq!(cpu.set_pc(0xFFFF, &Memory::new_xo_chip()), Err(Chip8Error::InvalidAddress(0xFFFF)));
+    }
+
+    #[test]
+    fn get_set_register() {
+        let mut cpu = Cpu::new(CpuConfig::default());
+        assert_eq!(cpu.set_register(5, 0x42), Ok(0));
+        assert_eq!(cpu.get_register(5), Ok(0x42));
+        assert_eq!(cpu.registers()[5], 0x42);
+        assert!(cpu.set_register(0xF, 0x1).is_ok());
+        assert_eq!(cpu.get_register(0xF), Ok(0x1));
+
+        assert_eq!(cpu.set_register(16, 0x1), Err(Chip8Error::InvalidRegister(16)));
+        assert_eq!(cpu.get_register(16), Err(Chip8Error::InvalidRegister(16)));
+        assert_eq!(cpu.get_register(0xFF), Err(Chip8Error::InvalidRegister(0xFF)));
     }
 
     #[test]
     fn decode_invalid() {
-        let mut cpu = Cpu::new(false, false, false);
+        let mut cpu = Cpu::new(CpuConfig::default());
+        assert_eq!(cpu.decode(0x8008, None, None, None), Err(Chip8Error::UnknownOpcode(0x8008)));
+        assert_eq!(cpu.decode(0xF0FF, None, None, None), Err(Chip8Error::UnknownOpcode(0xF0FF)));
+    }
+
+    #[test]
+    fn decode_skip_vx_vy_low_nibble() {
+        let mut cpu = Cpu::new(CpuConfig::default());
+        assert_eq!(cpu.decode(0x5231, None, None, None), Err(Chip8Error::UnknownOpcode(0x5231)));
+        assert_eq!(cpu.decode(0x9231, None, None, None), Err(Chip8Error::UnknownOpcode(0x9231)));
+        assert_eq!(cpu.decode(0x923F, None, None, None), Err(Chip8Error::UnknownOpcode(0x923F)));
+        assert_eq!(cpu.pc, PROGRAM_ADDRESS);
+
+        // V2 and V3 are both 0, so only 5XY0 skips.
+        assert!(cpu.decode(0x5230, None, None, None).is_ok());
+        assert_eq!(cpu.pc, PROGRAM_ADDRESS + 2);
+        assert!(cpu.decode(0x9230, None, None, None).is_ok());
+        assert_eq!(cpu.pc, PROGRAM_ADDRESS + 2);
+    }
+
+    #[test]
+    fn bad_opcode_policy() {
+        let config = |policy| CpuConfig { on_unknown_opcode: policy, ..Default::default() };
+
+        let mut cpu = Cpu::new(config(BadOpcodePolicy::Error));
+        assert_eq!(cpu.decode(0x8008, None, None, None), Err(Chip8Error::UnknownOpcode(0x8008)));
+        assert!(!cpu.is_halted());
+
+        let mut cpu = Cpu::new(config(BadOpcodePolicy::Skip));
+        cpu.pc = PROGRAM_ADDRESS + 2;
+        assert_eq!(cpu.decode(0x8008, None, None, None), Ok(0));
+        assert_eq!(cpu.take_skipped_opcode(), Some(0x8008));
+        assert_eq!(cpu.take_skipped_opcode(), None);
+        assert_eq!(cpu.decode(0x5231, None, None, None), Ok(0));
+        assert_eq!(cpu.take_skipped_opcode(), Some(0x5231));
+        assert!(!cpu.is_halted());
+        assert_eq!(cpu.pc, PROGRAM_ADDRESS + 2);
+        assert_eq!(cpu.cycle_count(), 0);
+        // Other errors are still returned.
+        assert_eq!(cpu.decode(0x00EE, None, None, None), Err(Chip8Error::StackUnderflow));
+
+        let mut cpu = Cpu::new(config(BadOpcodePolicy::Halt));
+        assert_eq!(cpu.decode(0xF0FF, None, None, None), Ok(0));
+        assert!(cpu.is_halted());
+        assert_eq!(cpu.cycle_count(), 0);
+    }
+
+    #[test]
+    fn decode_sys() {
+        let mut cpu = Cpu::new(CpuConfig::default());
+        assert!(cpu.decode(0x0123, None, None, None).is_ok());
+        assert_eq!(cpu.pc(), PROGRAM_ADDRESS);
+        assert_eq!(cpu.stack_depth(), 0);
+        assert_eq!(cpu.cycle_count(), 1);
+    }
+
+    #[test]
+    fn new_with_state() {
+        let mut regs = [0; 16];
+        for (ind, reg) in regs.iter_mut().enumerate() {
+            *reg = 0x10 + ind as u8;
+        }
+        let mut cpu = Cpu::new_with_state(CpuConfig::default(), regs, 0x345, 0x400);
+        assert_eq!(cpu.registers(), regs);
+        assert_eq!(cpu.get_register(0xA), Ok(0x1A));
+        assert_eq!(cpu.index(), 0x345);
+        assert_eq!(cpu.pc(), 0x400);
+        assert_eq!(cpu.stack_depth(), 0);
+        assert_eq!(cpu.cycle_count(), 0);
+
+        // Execution carries on from the preset state.
+        assert!(cpu.decode(0x7101, None, None, None).is_ok());
+        assert_eq!(cpu.get_register(0x1), Ok(0x12));
+
+        // Unlike the preset state, the power-on state comes from the config.
+        cpu.reset();
+        assert_eq!(cpu.registers(), [0; 16]);
+        assert_eq!(cpu.pc(), PROGRAM_ADDRESS);
+    }
+
+    #[test]
+    fn cycle_count() {
+        let mut cpu = Cpu::new(CpuConfig::default());
+        assert_eq!(cpu.cycle_count(), 0);
+
+        assert!(cpu.decode(0x600c, None, None, None).is_ok());
+        assert!(cpu.decode(0x7001, None, None, None).is_ok());
+        assert!(cpu.decode(0xa22a, None, None, None).is_ok());
+        assert_eq!(cpu.cycle_count(), 3);
+
+        // Failed decodes aren't counted.
         assert!(cpu.decode(0x8008, None, None, None).is_err());
+        assert_eq!(cpu.cycle_count(), 3);
+
+        assert!(cpu.decode(0x1300, None, None, None).is_ok());
+        assert_eq!(cpu.cycle_count(), 4);
+        assert_eq!(cpu.pc(), 0x300);
+        assert_eq!(cpu.index(), 0x22a);
+    }
+
+    #[test]
+    fn accessors() {
+        let mut cpu = Cpu::new(CpuConfig::default());
+        assert_eq!(cpu.pc(), PROGRAM_ADDRESS);
+        assert_eq!(cpu.index(), 0);
+        assert_eq!(cpu.registers(), [0; 16]);
+        assert_eq!(cpu.stack_depth(), 0);
+
+        assert!(cpu.decode(0x63AB, None, None, None).is_ok());
+        assert!(cpu.decode(0x6F01, None, None, None).is_ok());
+        assert!(cpu.decode(0xA123, None, None, None).is_ok());
+        assert!(cpu.decode(0x2400, None, None, None).is_ok());
+        assert!(cpu.decode(0x2500, None, None, None).is_ok());
+
+        let mut expected = [0; 16];
+        expected[0x3] = 0xAB;
+        expected[0xF] = 0x01;
+        assert_eq!(cpu.registers(), expected);
+        assert_eq!(cpu.index(), 0x123);
+        assert_eq!(cpu.pc(), 0x500);
+        assert_eq!(cpu.stack_depth(), 2);
+
+        assert!(cpu.decode(0x00EE, None, None, None).is_ok());
+        assert_eq!(cpu.pc(), 0x400);
+        assert_eq!(cpu.stack_depth(), 1);
+    }
+
+    #[test]
+    fn reset() {
+        let mut cpu = Cpu::new(CpuConfig { max_stack_depth: 4, halt_on_self_jump: true, ..Default::default() });
+        assert!(cpu.decode(0x63AB, None, None, None).is_ok());
+        assert!(cpu.decode(0xA123, None, None, None).is_ok());
+        assert!(cpu.decode(0x2400, None, None, None).is_ok());
+        assert!(cpu.decode(0xF375, None, None, None).is_ok());
+        // Jump to the instruction before the PC, which looks like a jump to itself.
+        assert!(cpu.decode(0x13FE, None, None, None).is_ok());
+        cpu.pressed.insert(0x5, true);
+        assert!(cpu.is_halted());
+
+        cpu.reset();
+        assert_eq!(cpu.pc, PROGRAM_ADDRESS);
+        assert_eq!(cpu.i, 0);
+        assert_eq!(cpu.v, [0; 16]);
+        assert!(cpu.stack.is_empty());
+        assert!(cpu.pressed.is_empty());
+        assert_eq!(cpu.cycle_count(), 0);
+        assert!(!cpu.is_halted());
+
+        // The configuration and the RPL flags survive.
+        assert_eq!(cpu.config.max_stack_depth, 4);
+        assert_eq!(cpu.rpl_flags()[3], 0xAB);
+    }
+
+    #[test]
+    fn snapshot_restore() {
+        let mut cpu = Cpu::new(CpuConfig::default());
+        cpu.pc = 0x345;
+        cpu.i = 0x678;
+        for ind in 0..16 {
+            cpu.v[ind] = ind as u8 * 3;
+        }
+        cpu.stack.push_back(0x210);
+        cpu.stack.push_back(0x220);
+        cpu.pressed.insert(0x3, true);
+        cpu.pressed.insert(0x7, false);
+
+        let blob = cpu.snapshot();
+
+        // Mess up all the state.
+        cpu.pc = 0x200;
+        cpu.i = 0;
+        cpu.v = [0xFF; 16];
+        cpu.stack.clear();
+        cpu.stack.push_back(0x500);
+        cpu.pressed.clear();
+        cpu.pressed.insert(0x1, true);
+
+        assert!(cpu.restore(&blob).is_ok());
+        assert_eq!(cpu.pc, 0x345);
+        assert_eq!(cpu.i, 0x678);
+        for ind in 0..16 {
+            assert_eq!(cpu.v[ind], ind as u8 * 3);
+        }
+        assert_eq!(cpu.stack.iter().copied().collect::<Vec<u16>>(), vec![0x210, 0x220]);
+        let mut expected_pressed = HashMap::new();
+        expected_pressed.insert(0x3, true);
+        expected_pressed.insert(0x7, false);
+        assert_eq!(cpu.pressed, expected_pressed);
+    }
+
+    #[test]
+    fn restore_invalid() {
+        let mut cpu = Cpu::new(CpuConfig::default());
+        cpu.stack.push_back(0x210);
+        let blob = cpu.snapshot();
+
+        assert!(cpu.restore(&blob[..10]).is_err());
+        assert!(cpu.restore(&blob[..blob.len() - 1]).is_err());
+
+        let mut bad_key = blob.clone();
+        bad_key[20] = 3;
+        cpu.pc = 0x300;
+        assert!(cpu.restore(&bad_key).is_err());
+        // Nothing should've been restored.
+        assert_eq!(cpu.pc, 0x300);
     }
 
     #[test]
     fn decode_disp_clear() {
-        let mut cpu = Cpu::new(false, false, false);
-        assert!(cpu.decode(0x00e0, None, None, None).is_ok());
+        let mut cpu = Cpu::new(CpuConfig::default());
+        let disp = Display::new(true);
+        assert!(cpu.decode(0x00e0, Some(&disp), None, None).is_ok());
     }
 
     #[test]
     fn decode_set_i() {
-        let mut cpu = Cpu::new(false, false, false);
+        let mut cpu = Cpu::new(CpuConfig::default());
         assert!(cpu.decode(0xa22a, None, None, None).is_ok());
         assert_eq!(cpu.i, 0x22a);
     }
 
     #[test]
     fn decode_set_v() {
-        let mut cpu = Cpu::new(false, false, false);
+        let mut cpu = Cpu::new(CpuConfig::default());
         assert!(cpu.decode(0x600c, None, None, None).is_ok());
         assert_eq!(cpu.v[0], 0xc);
         assert!(cpu.decode(0x6FFE, None, None, None).is_ok());
@@ -610,7 +1411,7 @@ mod tests {
 
     #[test]
     fn decode_add_v() {
-        let mut cpu = Cpu::new(false, false, false);
+        let mut cpu = Cpu::new(CpuConfig::default());
         let x = 0x4 as usize;
         let nn = 0x32;
         cpu.v[x] = 0x32;
@@ -626,18 +1427,65 @@ mod tests {
         assert_eq!(cpu.v[0xf], 0);
     }
 
+    #[test]
+    // 7FNN adds to VF like any other register, without any carry being stored.
+    fn decode_add_vf() {
+        let mut cpu = Cpu::new(CpuConfig::default());
+        cpu.v[0xF] = 0x10;
+        assert!(cpu.decode(0x7F05, None, None, None).is_ok());
+        assert_eq!(cpu.v[0xF], 0x15);
+
+        // The sum wraps, rather than VF being set to 1.
+        cpu.v[0xF] = 0xFE;
+        assert!(cpu.decode(0x7F03, None, None, None).is_ok());
+        assert_eq!(cpu.v[0xF], 0x01);
+        cpu.v[0xF] = 0xFF;
+        assert!(cpu.decode(0x7F01, None, None, None).is_ok());
+        assert_eq!(cpu.v[0xF], 0x00);
+    }
+
     #[test]
     fn handle_jump() {
-        let mut cpu = Cpu::new(false, false, false);
+        let mut cpu = Cpu::new(CpuConfig::default());
         let instr = (0x1 << 12) | 0x123;
 
         assert!(cpu.decode(instr, None, None, None).is_ok());
-        assert_eq!(cpu.pc, 0x123);
+        assert_eq!(cpu.pc, 0x123);
+    }
+
+    #[test]
+    fn handle_jump_self() {
+        let mut cpu = Cpu::new(CpuConfig { halt_on_self_jump: true, ..Default::default() });
+        let mut mem = Memory::from_bytes([0; MEM_SIZE]);
+
+        // Jump forward first, which shouldn't halt.
+        mem.mem[PROGRAM_ADDRESS as usize] = 0x12;
+        mem.mem[PROGRAM_ADDRESS as usize + 1] = 0x04;
+        let instr = cpu.fetch(&mem).unwrap();
+        assert!(cpu.decode(instr, None, None, None).is_ok());
+        assert!(!cpu.is_halted());
+
+        // Jump to the jump instruction itself.
+        mem.mem[PROGRAM_ADDRESS as usize + 4] = 0x12;
+        mem.mem[PROGRAM_ADDRESS as usize + 5] = 0x04;
+        let instr = cpu.fetch(&mem).unwrap();
+        assert!(cpu.decode(instr, None, None, None).is_ok());
+        assert!(cpu.is_halted());
+        assert_eq!(cpu.pc, PROGRAM_ADDRESS + 4);
+
+        // By default, programs are left to spin on the jump, waiting for the
+        // timers or a key.
+        let mut cpu = Cpu::new(CpuConfig::default());
+        cpu.pc = PROGRAM_ADDRESS + 4;
+        let instr = cpu.fetch(&mem).unwrap();
+        assert!(cpu.decode(instr, None, None, None).is_ok());
+        assert!(!cpu.is_halted());
+        assert_eq!(cpu.pc, PROGRAM_ADDRESS + 4);
     }
 
     #[test]
     fn subroutine() {
-        let mut cpu = Cpu::new(false, false, false);
+        let mut cpu = Cpu::new(CpuConfig::default());
         const OLD_ADDR: u16 = 0x654;
         const NEW_ADDR: u16 = 0x456;
         let instr = (0x2 << 12) | NEW_ADDR;
@@ -655,7 +1503,7 @@ mod tests {
 
     #[test]
     fn return_routine() {
-        let mut cpu = Cpu::new(false, false, false);
+        let mut cpu = Cpu::new(CpuConfig::default());
         const OLD_ADDR: u16 = 0x654;
         const NEW_ADDR: u16 = 0x456;
         cpu.pc = NEW_ADDR;
@@ -666,9 +1514,72 @@ mod tests {
         assert!(cpu.stack.is_empty());
     }
 
+    #[test]
+    fn subroutine_overflow() {
+        let mut cpu = Cpu::new(CpuConfig::default());
+        for _ in 0..16 {
+            assert!(cpu.decode(0x2400, None, None, None).is_ok());
+        }
+        assert_eq!(cpu.stack.len(), 16);
+
+        // The 17th nested call should fail, and leave the stack alone.
+        assert_eq!(cpu.decode(0x2400, None, None, None), Err(Chip8Error::StackOverflow));
+        assert_eq!(cpu.stack.len(), 16);
+
+        // The depth is configurable.
+        let mut cpu = Cpu::new(CpuConfig { max_stack_depth: 2, ..Default::default() });
+        assert!(cpu.decode(0x2400, None, None, None).is_ok());
+        assert!(cpu.decode(0x2400, None, None, None).is_ok());
+        assert!(cpu.decode(0x2400, None, None, None).is_err());
+    }
+
+    #[test]
+    fn call_stack() {
+        let mut cpu = Cpu::new(CpuConfig::default());
+        assert!(cpu.call_stack().is_empty());
+
+        // Without fetching, each call returns to the start of the previous subroutine.
+        for instr in [0x2400, 0x2500, 0x2600].iter() {
+            assert!(cpu.decode(*instr, None, None, None).is_ok());
+        }
+        assert_eq!(cpu.call_stack(), vec![PROGRAM_ADDRESS, 0x400, 0x500]);
+        assert_eq!(cpu.call_stack(), cpu.stack.iter().copied().collect::<Vec<u16>>());
+
+        assert!(cpu.decode(0x00EE, None, None, None).is_ok());
+        assert_eq!(cpu.call_stack(), vec![PROGRAM_ADDRESS, 0x400]);
+    }
+
+    #[test]
+    fn return_routine_empty() {
+        let mut cpu = Cpu::new(CpuConfig::default());
+        assert_eq!(cpu.decode(0x00EE, None, None, None), Err(Chip8Error::StackUnderflow));
+        assert_eq!(cpu.pc, PROGRAM_ADDRESS);
+    }
+
+    #[test]
+    fn skip_wraps_pc() {
+        // (instruction, VX) for each kind of skip, all of which are taken.
+        let table = [(0x3000, 0x00), (0x4000, 0x01), (0x5010, 0x00), (0x9010, 0x01)];
+        for (instr, vx) in table.iter() {
+            let mut cpu = Cpu::new(CpuConfig::default());
+            cpu.v[0x0] = *vx;
+            cpu.pc = 0xFFFE;
+            assert!(cpu.decode(*instr, None, None, None).is_ok());
+            assert_eq!(cpu.pc, 0x0000, "{:04X}", instr);
+        }
+
+        // Waiting instructions go back the other way.
+        let disp = Display::new(true);
+        let mut cpu = Cpu::new(CpuConfig { draw_wait_vblank: true, ..Default::default() });
+        cpu.pc = 0x0000;
+        assert!(cpu.decode(0xD001, Some(&disp), Some(&mut Memory::new()), None).is_ok());
+        assert!(cpu.decode(0xD001, Some(&disp), Some(&mut Memory::new()), None).is_ok());
+        assert_eq!(cpu.pc, 0xFFFE);
+    }
+
     #[test]
     fn decode_skip_vx_eq() {
-        let mut cpu = Cpu::new(false, false, false);
+        let mut cpu = Cpu::new(CpuConfig::default());
         const X: u8 = 0x2;
         const NN: u8 = 0x45;
         let instr = ((0x3 << 12) | (X as u16 )<< 8 | NN as u16) as u16;
@@ -687,7 +1598,7 @@ mod tests {
 
     #[test]
     fn decode_skip_vx_ne() {
-        let mut cpu = Cpu::new(false, false, false);
+        let mut cpu = Cpu::new(CpuConfig::default());
         const X: u8 = 0x2;
         const NN: u8 = 0x45;
         let instr = ((0x4 << 12) | (X as u16 )<< 8 | NN as u16) as u16;
@@ -706,7 +1617,7 @@ mod tests {
 
     #[test]
     fn decode_skip_vx_vy_eq() {
-        let mut cpu = Cpu::new(false, false, false);
+        let mut cpu = Cpu::new(CpuConfig::default());
         const X: u8 = 0x2;
         const Y: u8 = 0x3;
         const VAL: u8 = 0x45;
@@ -727,7 +1638,7 @@ mod tests {
 
     #[test]
     fn decode_skip_vx_vy_not_eq() {
-        let mut cpu = Cpu::new(false, false, false);
+        let mut cpu = Cpu::new(CpuConfig::default());
         const X: u8 = 0x2;
         const Y: u8 = 0x3;
         const VAL: u8 = 0x45;
@@ -748,7 +1659,7 @@ mod tests {
 
     #[test]
     fn set_vx_to_vy() {
-        let mut cpu = Cpu::new(false, false, false);
+        let mut cpu = Cpu::new(CpuConfig::default());
         const X: u8 = 0x2;
         const Y: u8 = 0x3;
         const VAL1: u8 = 0x50;
@@ -763,7 +1674,7 @@ mod tests {
 
     #[test]
     fn decode_arith_vx_minus_vy() {
-        let mut cpu = Cpu::new(false, false, false);
+        let mut cpu = Cpu::new(CpuConfig::default());
         const X: u8 = 0x2;
         const Y: u8 = 0x3;
         const VAL1: u8 = 0x50;
@@ -786,7 +1697,7 @@ mod tests {
 
     #[test]
     fn decode_arith_vx_plus_vy() {
-        let mut cpu = Cpu::new(false, false, false);
+        let mut cpu = Cpu::new(CpuConfig::default());
         const X: u8 = 0x2;
         const Y: u8 = 0x3;
         const VAL1: u8 = 0x50;
@@ -806,9 +1717,63 @@ mod tests {
         assert_eq!(cpu.v[0xF], 1);
     }
 
+    #[test]
+    // VF is both an operand and the destination, the carry flag should win.
+    fn decode_arith_vf_plus_vy() {
+        let mut cpu = Cpu::new(CpuConfig::default());
+
+        // No carry.
+        cpu.v[0xF] = 0x10;
+        cpu.v[0x1] = 0x20;
+        assert!(cpu.decode(0x8F14, None, None, None).is_ok());
+        assert_eq!(cpu.v[0xF], 0);
+
+        // Carry.
+        cpu.v[0xF] = 0xF0;
+        cpu.v[0x1] = 0x20;
+        assert!(cpu.decode(0x8F14, None, None, None).is_ok());
+        assert_eq!(cpu.v[0xF], 1);
+
+        // VF as VY uses the value from before the flag is set.
+        cpu.v[0x2] = 0xFF;
+        cpu.v[0xF] = 0x01;
+        assert!(cpu.decode(0x82F4, None, None, None).is_ok());
+        assert_eq!(cpu.v[0x2], 0x00);
+        assert_eq!(cpu.v[0xF], 1);
+    }
+
+    #[test]
+    // With VF as the destination, the flag should be what remains in VF rather
+    // than the arithmetic result.
+    fn decode_vf_destination() {
+        // (instr, VF, V1, expected VF)
+        let table: [(u16, u8, u8, u8); 8] = [
+            // 8XY5, VF = VF - V1
+            (0x8F15, 0x30, 0x10, 1),
+            (0x8F15, 0x10, 0x30, 0),
+            // 8XY7, VF = V1 - VF
+            (0x8F17, 0x10, 0x30, 1),
+            (0x8F17, 0x30, 0x10, 0),
+            // 8XY6, VF = VF >> 1
+            (0x8F16, 0x03, 0x00, 1),
+            (0x8F16, 0x02, 0x00, 0),
+            // 8XYE, VF = VF << 1
+            (0x8F1E, 0x81, 0x00, 1),
+            (0x8F1E, 0x41, 0x00, 0),
+        ];
+
+        for (instr, vf, v1, expected) in table.iter() {
+            let mut cpu = Cpu::new(CpuConfig::default());
+            cpu.v[0xF] = *vf;
+            cpu.v[0x1] = *v1;
+            assert!(cpu.decode(*instr, None, None, None).is_ok());
+            assert_eq!(cpu.v[0xF], *expected, "instr 0x{:04X} with VF 0x{:02X}", instr, vf);
+        }
+    }
+
     #[test]
     fn decode_arith_vy_minus_vx() {
-        let mut cpu = Cpu::new(false, false, false);
+        let mut cpu = Cpu::new(CpuConfig::default());
         const X: u8 = 0x2;
         const Y: u8 = 0x3;
         const VAL1: u8 = 0x50;
@@ -831,7 +1796,7 @@ mod tests {
 
     #[test]
     fn decode_logic_vx_or_vy() {
-        let mut cpu = Cpu::new(false, false, false);
+        let mut cpu = Cpu::new(CpuConfig::default());
         const X: u8 = 0x2;
         const Y: u8 = 0x3;
         const VAL1: u8 = 0xF;
@@ -846,7 +1811,7 @@ mod tests {
 
     #[test]
     fn decode_logic_vx_and_vy() {
-        let mut cpu = Cpu::new(false, false, false);
+        let mut cpu = Cpu::new(CpuConfig::default());
         const X: u8 = 0x2;
         const Y: u8 = 0x3;
         const VAL1: u8 = 0xFF;
@@ -861,7 +1826,7 @@ mod tests {
 
     #[test]
     fn decode_logic_vx_xor_vy() {
-        let mut cpu = Cpu::new(false, false, false);
+        let mut cpu = Cpu::new(CpuConfig::default());
         const X: u8 = 0x2;
         const Y: u8 = 0x3;
         const VAL1: u8 = 0xAA;
@@ -874,9 +1839,26 @@ mod tests {
         assert_eq!(cpu.v[X as usize], 0xFF);
     }
 
+    #[test]
+    fn logic_resets_vf() {
+        const X: u8 = 0x2;
+        const Y: u8 = 0x3;
+        for op in [0x1, 0x2, 0x3].iter() {
+            let instr = ((0x8 << 12) | (X as u16 ) << 8 | (Y as u16) << 4) | op;
+            for quirk in [false, true].iter() {
+                let mut cpu = Cpu::new(CpuConfig { logic_resets_vf: *quirk, ..Default::default() });
+                cpu.v[X as usize] = 0xAA;
+                cpu.v[Y as usize] = 0x0F;
+                cpu.v[0xF] = 1;
+                assert!(cpu.decode(instr, None, None, None).is_ok());
+                assert_eq!(cpu.v[0xF], if *quirk { 0 } else { 1 }, "{:04X} quirk {}", instr, quirk);
+            }
+        }
+    }
+
     #[test]
     fn decode_left_shift() {
-        let mut cpu = Cpu::new(false, false, false);
+        let mut cpu = Cpu::new(CpuConfig::default());
         const X: u8 = 0x2;
         const Y: u8 = 0x3;
         const VAL1: u8 = 0xAA;
@@ -896,7 +1878,7 @@ mod tests {
 
     #[test]
     fn decode_right_shift() {
-        let mut cpu = Cpu::new(false, false, false);
+        let mut cpu = Cpu::new(CpuConfig::default());
         const X: u8 = 0x2;
         const Y: u8 = 0x3;
         const VAL1: u8 = 0xAA;
@@ -914,21 +1896,86 @@ mod tests {
         assert_eq!(cpu.v[0xF], 1);
     }
 
+    #[test]
+    fn decode_left_shift_quirk() {
+        const X: u8 = 0x2;
+        const Y: u8 = 0x3;
+        const VAL1: u8 = 0x55;
+        const VAL2: u8 = 0xAA;
+        let instr = ((0x8 << 12) | (X as u16 ) << 8 | (Y as u16) << 4) | 0xE;
+
+        // Without the quirk, VX is shifted in place and VY is ignored.
+        let mut cpu = Cpu::new(CpuConfig::default());
+        cpu.v[X as usize] = VAL1;
+        cpu.v[Y as usize] = VAL2;
+        assert!(cpu.decode(instr, None, None, None).is_ok());
+        assert_eq!(cpu.v[X as usize], 0xAA);
+        assert_eq!(cpu.v[0xF], 0);
+
+        // With the quirk, VY is copied into VX before the shift.
+        let mut cpu = Cpu::new(CpuConfig { shift_uses_vy: true, ..Default::default() });
+        cpu.v[X as usize] = VAL1;
+        cpu.v[Y as usize] = VAL2;
+        assert!(cpu.decode(instr, None, None, None).is_ok());
+        assert_eq!(cpu.v[X as usize], 0x54);
+        assert_eq!(cpu.v[Y as usize], VAL2);
+        assert_eq!(cpu.v[0xF], 1);
+    }
+
+    #[test]
+    fn decode_right_shift_quirk() {
+        const X: u8 = 0x2;
+        const Y: u8 = 0x3;
+        const VAL1: u8 = 0xAA;
+        const VAL2: u8 = 0x55;
+        let instr = ((0x8 << 12) | (X as u16 ) << 8 | (Y as u16) << 4) | 0x6;
+
+        // Without the quirk, VX is shifted in place and VY is ignored.
+        let mut cpu = Cpu::new(CpuConfig::default());
+        cpu.v[X as usize] = VAL1;
+        cpu.v[Y as usize] = VAL2;
+        assert!(cpu.decode(instr, None, None, None).is_ok());
+        assert_eq!(cpu.v[X as usize], 0x55);
+        assert_eq!(cpu.v[0xF], 0);
+
+        // With the quirk, VY is copied into VX before the shift.
+        let mut cpu = Cpu::new(CpuConfig { shift_uses_vy: true, ..Default::default() });
+        cpu.v[X as usize] = VAL1;
+        cpu.v[Y as usize] = VAL2;
+        assert!(cpu.decode(instr, None, None, None).is_ok());
+        assert_eq!(cpu.v[X as usize], 0x2A);
+        assert_eq!(cpu.v[Y as usize], VAL2);
+        assert_eq!(cpu.v[0xF], 1);
+    }
+
     // The memory fetch aspect is tested in the memory module, so we just need to test that
     // we can get the character value out correctly.
     #[test]
     fn get_font_char() {
-        let mut cpu = Cpu::new(false, false, false);
+        let mut cpu = Cpu::new(CpuConfig::default());
         const X: usize = 0x4;
         cpu.v[X] = 0xA;
         let instr = 0xF << 12 | (X << 8)  as u16 | 0x29;
         assert_eq!(cpu.get_font_char(instr), 0xA)
     }
 
+    #[test]
+    fn big_font_character() {
+        let mut cpu = Cpu::new(CpuConfig::default());
+        let mut mem = Memory::new();
+        cpu.v[0x3] = 0x7;
+        assert!(cpu.decode(0xF330, None, Some(&mut mem), None).is_ok());
+        assert_eq!(cpu.i as usize, mem.get_big_font_addr(0x7));
+
+        // The small font is still used by FX29.
+        assert!(cpu.decode(0xF329, None, Some(&mut mem), None).is_ok());
+        assert_eq!(cpu.i as usize, mem.get_font_addr(0x7));
+    }
+
     #[test]
     fn store() {
-        let mut cpu = Cpu::new(false, false, false);
-        let mut mem = Memory { mem: [0; 4096] };
+        let mut cpu = Cpu::new(CpuConfig::default());
+        let mut mem = Memory::from_bytes([0; MEM_SIZE]);
         const I : usize = 0x600;
         const X: u8 = 0x4;
         const VAL: u8 = 0xAA;
@@ -951,10 +1998,28 @@ mod tests {
         assert_eq!(cpu.i, I as u16);
     }
 
+    #[test]
+    fn store_protected() {
+        let mut cpu = Cpu::new(CpuConfig::default());
+        let mut mem = Memory::new();
+        mem.set_protect_interpreter(true);
+        cpu.v[0x0] = 0xAA;
+        cpu.v[0x1] = 0xBB;
+
+        cpu.i = PROGRAM_ADDRESS;
+        assert_eq!(cpu.decode(0xF155, None, Some(&mut mem), None), Ok(0));
+        cpu.i = PROGRAM_ADDRESS - 1;
+        assert_eq!(cpu.decode(0xF155, None, Some(&mut mem), None),
+                   Err(Chip8Error::ProtectedAddress((PROGRAM_ADDRESS - 1) as usize)));
+        cpu.i = 0x180;
+        assert_eq!(cpu.decode(0xF033, None, Some(&mut mem), None), Err(Chip8Error::ProtectedAddress(0x180)));
+        assert_eq!(mem.read(0x180).unwrap(), 0);
+    }
+
     #[test]
     fn store_quirk() {
-        let mut cpu = Cpu::new(true, false, false);
-        let mut mem = Memory { mem: [0; 4096] };
+        let mut cpu = Cpu::new(CpuConfig { mem_increment: MemIncrement::IncrementByXPlus1, ..Default::default() });
+        let mut mem = Memory::from_bytes([0; MEM_SIZE]);
         const I : usize = 0x600;
         const X: u8 = 0x4;
         const VAL: u8 = 0xAA;
@@ -977,10 +2042,51 @@ mod tests {
         assert_eq!(cpu.i, I as u16 + X as u16 + 1);
     }
 
+    #[test]
+    fn mem_increment() {
+        const I: u16 = 0x600;
+        const X: u16 = 0x3;
+        let table = [
+            (MemIncrement::Unchanged, I),
+            (MemIncrement::IncrementByX, I + X),
+            (MemIncrement::IncrementByXPlus1, I + X + 1),
+        ];
+
+        for (mode, expected) in table.iter() {
+            for instr in [0xF055 | X << 8, 0xF065 | X << 8].iter() {
+                let mut cpu = Cpu::new(CpuConfig { mem_increment: *mode, ..Default::default() });
+                let mut mem = Memory::from_bytes([0; MEM_SIZE]);
+                cpu.i = I;
+                assert!(cpu.decode(*instr, None, Some(&mut mem), None).is_ok());
+                assert_eq!(cpu.i, *expected, "{:?} {:04X}", mode, instr);
+            }
+        }
+    }
+
+    #[test]
+    // A transfer at the top of the 64KB XO-CHIP memory wraps I around.
+    fn mem_increment_wraps() {
+        const X: u16 = 0x1;
+        let table = [
+            (MemIncrement::IncrementByX, 0xFFFF),
+            (MemIncrement::IncrementByXPlus1, 0x0000),
+        ];
+
+        for (mode, expected) in table.iter() {
+            for instr in [0xF055 | X << 8, 0xF065 | X << 8].iter() {
+                let mut cpu = Cpu::new(CpuConfig { mem_increment: *mode, ..Default::default() });
+                let mut mem = Memory::new_xo_chip();
+                cpu.i = 0xFFFE;
+                assert!(cpu.decode(*instr, None, Some(&mut mem), None).is_ok());
+                assert_eq!(cpu.i, *expected, "{:?} {:04X}", mode, instr);
+            }
+        }
+    }
+
     #[test]
     fn load() {
-        let mut cpu = Cpu::new(false, false, false);
-        let mut mem = Memory { mem: [0; 4096] };
+        let mut cpu = Cpu::new(CpuConfig::default());
+        let mut mem = Memory::from_bytes([0; MEM_SIZE]);
         const I : usize = 0x600;
         const X: u8 = 0x4;
         const VAL: u8 = 0xAA;
@@ -1006,8 +2112,8 @@ mod tests {
 
     #[test]
     fn load_quirk() {
-        let mut cpu = Cpu::new(true, false, false);
-        let mut mem = Memory { mem: [0; 4096] };
+        let mut cpu = Cpu::new(CpuConfig { mem_increment: MemIncrement::IncrementByXPlus1, ..Default::default() });
+        let mut mem = Memory::from_bytes([0; MEM_SIZE]);
         const I : usize = 0x600;
         const X: u8 = 0x4;
         const VAL: u8 = 0xAA;
@@ -1031,10 +2137,199 @@ mod tests {
         assert_eq!(cpu.i, I as u16 + X as u16 + 1);
     }
 
+    #[test]
+    // Accesses running past the end of memory should fail rather than panic.
+    fn load_store_out_of_bounds() {
+        let mut cpu = Cpu::new(CpuConfig::default());
+        let mut mem = Memory::from_bytes([0; MEM_SIZE]);
+        const X: u8 = 0x4;
+        cpu.i = 4094;
+
+        let instr = (0xF << 12) | (X as u16) << 8 | 0x55;
+        assert!(cpu.decode(instr, None, Some(&mut mem), None).is_err());
+
+        let instr = (0xF << 12) | (X as u16) << 8 | 0x65;
+        assert!(cpu.decode(instr, None, Some(&mut mem), None).is_err());
+
+        // I can be pushed past the 12 bit address space by FX1E.
+        cpu.i = 0xFFFF;
+        let instr = (0xF << 12) | (X as u16) << 8 | 0x55;
+        assert!(cpu.decode(instr, None, Some(&mut mem), None).is_err());
+    }
+
+    #[test]
+    fn bcd_out_of_bounds() {
+        let mut cpu = Cpu::new(CpuConfig::default());
+        let mut mem = Memory::from_bytes([0; MEM_SIZE]);
+        cpu.i = 4094;
+
+        assert!(cpu.decode(0xF433, None, Some(&mut mem), None).is_err());
+    }
+
+    #[test]
+    fn get_sprite_out_of_bounds() {
+        let mut cpu = Cpu::new(CpuConfig::default());
+        let memory = Memory::from_bytes([0; MEM_SIZE]);
+        cpu.i = 4094;
+
+        assert!(cpu.get_sprite(0xD015, &memory, WIDTH, HEIGHT, 1).is_err());
+        assert!(cpu.get_sprite(0xD012, &memory, WIDTH, HEIGHT, 1).is_ok());
+    }
+
+    #[test]
+    // FN01 selects the planes, and DXYN then reads N bytes for each selected plane.
+    fn select_planes() {
+        let mut cpu = Cpu::new(CpuConfig::default());
+        let mut mem = Memory::new();
+        let disp = Display::new(true);
+        for (ind, byte) in [0xFF, 0x0F].iter().enumerate() {
+            mem.write(0x300 + ind, *byte).unwrap();
+        }
+        cpu.i = 0x300;
+
+        assert!(cpu.decode(0xF301, Some(&disp), Some(&mut mem), None).is_ok());
+        assert_eq!(Display::plane_count(&disp), 2);
+        assert!(cpu.decode(0xD011, Some(&disp), Some(&mut mem), None).is_ok());
+        assert_eq!(cpu.v[0xF], 0);
+
+        // Drawing again only to plane 2 collides with the second byte.
+        assert!(cpu.decode(0xF201, Some(&disp), Some(&mut mem), None).is_ok());
+        cpu.i = 0x301;
+        assert!(cpu.decode(0xD011, Some(&disp), Some(&mut mem), None).is_ok());
+        assert_eq!(cpu.v[0xF], 1);
+
+        assert!(cpu.decode(0xF401, Some(&disp), Some(&mut mem), None).is_err());
+    }
+
+    #[test]
+    fn timers() {
+        let mut cpu = Cpu::new(CpuConfig::default());
+        let mut timer = Timer::new(true, AudioConfig::default());
+        cpu.v[0x3] = 0x2A;
+        cpu.v[0x4] = 0x10;
+
+        assert!(cpu.decode(0xF318, None, None, Some(&mut timer)).is_ok());
+        assert_eq!(Timer::get_sound(&timer), 0x2A);
+        assert!(cpu.decode(0xF415, None, None, Some(&mut timer)).is_ok());
+        assert_eq!(Timer::get_delay(&timer), 0x10);
+
+        assert!(cpu.decode(0xF507, None, None, Some(&mut timer)).is_ok());
+        assert_eq!(cpu.v[0x5], 0x10);
+    }
+
+    #[test]
+    fn audio_pattern() {
+        let mut cpu = Cpu::new(CpuConfig::default());
+        let mut mem = Memory::new();
+        let mut timer = Timer::new(true, AudioConfig::default());
+        for ind in 0..16 {
+            mem.write(0x300 + ind, ind as u8 * 0x11).unwrap();
+        }
+        cpu.i = 0x300;
+
+        assert_eq!(Timer::get_audio_pattern(&timer), None);
+        assert!(cpu.decode(0xF002, None, Some(&mut mem), Some(&mut timer)).is_ok());
+        let pattern = Timer::get_audio_pattern(&timer).unwrap();
+        for (ind, byte) in pattern.iter().enumerate() {
+            assert_eq!(*byte, ind as u8 * 0x11);
+        }
+
+        cpu.v[0x5] = 0x70;
+        assert!(cpu.decode(0xF53A, None, Some(&mut mem), Some(&mut timer)).is_ok());
+        assert_eq!(Timer::get_pitch(&timer), Some(0x70));
+
+        // The pattern must lie entirely within memory.
+        cpu.i = 4090;
+        assert!(cpu.decode(0xF002, None, Some(&mut mem), Some(&mut timer)).is_err());
+    }
+
+    #[test]
+    fn rpl_flags() {
+        let mut cpu = Cpu::new(CpuConfig::default());
+        for ind in 0..8 {
+            cpu.v[ind] = 0x10 + ind as u8;
+        }
+
+        // Only V0-V3 are stored.
+        assert!(cpu.decode(0xF375, None, None, None).is_ok());
+        assert_eq!(cpu.rpl_flags(), [0x10, 0x11, 0x12, 0x13, 0, 0, 0, 0]);
+
+        cpu.v = [0; 16];
+        assert!(cpu.decode(0xF785, None, None, None).is_ok());
+        assert_eq!(cpu.v[..8], [0x10, 0x11, 0x12, 0x13, 0, 0, 0, 0]);
+
+        // Flags set from outside, e.g. loaded from a file, are restored as well.
+        cpu.set_rpl_flags([1, 2, 3, 4, 5, 6, 7, 8]);
+        assert!(cpu.decode(0xF785, None, None, None).is_ok());
+        assert_eq!(cpu.v[..8], [1, 2, 3, 4, 5, 6, 7, 8]);
+
+        // There are only 8 flags.
+        assert_eq!(cpu.decode(0xF875, None, None, None), Err(Chip8Error::UnknownOpcode(0xF875)));
+        assert_eq!(cpu.decode(0xF885, None, None, None), Err(Chip8Error::UnknownOpcode(0xF885)));
+    }
+
+    #[test]
+    fn draw_wait_vblank() {
+        let mut mem = Memory::new();
+        let disp = Display::new(true);
+        mem.write(0x300, 0x80).unwrap();
+
+        for quirk in [false, true] {
+            let mut cpu = Cpu::new(CpuConfig { draw_wait_vblank: quirk, ..Default::default() });
+            Display::clear(&disp);
+            cpu.i = 0x300;
+            // Each draw flips the top left pixel, which shows up as a collision every other draw.
+            let program = [0xD0, 0x01, 0xD0, 0x01];
+            for (ind, byte) in program.iter().enumerate() {
+                mem.write(PROGRAM_ADDRESS as usize + ind, *byte).unwrap();
+            }
+
+            let instr = cpu.fetch(&mem).unwrap();
+            assert!(cpu.decode(instr, Some(&disp), Some(&mut mem), None).is_ok());
+            assert_eq!(cpu.pc, PROGRAM_ADDRESS + 2);
+
+            // The second draw is held back until the next frame with the quirk.
+            let instr = cpu.fetch(&mem).unwrap();
+            assert!(cpu.decode(instr, Some(&disp), Some(&mut mem), None).is_ok());
+            if quirk {
+                assert_eq!(cpu.pc, PROGRAM_ADDRESS + 2);
+                assert_eq!(cpu.v[0xF], 0);
+
+                Display::tick_frame(&disp);
+                let instr = cpu.fetch(&mem).unwrap();
+                assert!(cpu.decode(instr, Some(&disp), Some(&mut mem), None).is_ok());
+            }
+            assert_eq!(cpu.pc, PROGRAM_ADDRESS + 4);
+            assert_eq!(cpu.v[0xF], 1);
+        }
+    }
+
+    #[test]
+    fn load_i_long() {
+        let mut cpu = Cpu::new(CpuConfig::default());
+        let mut mem = Memory::new_xo_chip();
+        let program = vec![0xF0, 0x00, 0xAB, 0xCD, 0xF2, 0x65];
+        assert!(mem.load_program(&program, PROGRAM_ADDRESS as usize).is_ok());
+        mem.write(0xABCD, 0x11).unwrap();
+        mem.write(0xABCE, 0x22).unwrap();
+        mem.write(0xABCF, 0x33).unwrap();
+
+        let instr = cpu.fetch(&mem).unwrap();
+        assert!(cpu.decode(instr, None, Some(&mut mem), None).is_ok());
+        assert_eq!(cpu.i, 0xABCD);
+        // The PC should have skipped past the address word.
+        assert_eq!(cpu.pc, PROGRAM_ADDRESS + 4);
+
+        // Loads from the extended range work as usual.
+        let instr = cpu.fetch(&mem).unwrap();
+        assert!(cpu.decode(instr, None, Some(&mut mem), None).is_ok());
+        assert_eq!(cpu.v[0..3], [0x11, 0x22, 0x33]);
+    }
+
     #[test]
     fn bcd() {
-        let mut cpu = Cpu::new(false, false, false);
-        let mut mem = Memory { mem: [0; 4096]};
+        let mut cpu = Cpu::new(CpuConfig::default());
+        let mut mem = Memory::from_bytes([0; MEM_SIZE]);
         const I: usize = 0x500;
         const X: u8 = 0x4;
         const VAL: u8 = 139;
@@ -1052,7 +2347,7 @@ mod tests {
 
     #[test]
     fn increment_i() {
-        let mut cpu = Cpu::new(false, false, false);
+        let mut cpu = Cpu::new(CpuConfig::default());
 
         const I: usize = 0x500;
         const X: u8 = 0x4;
@@ -1066,9 +2361,34 @@ mod tests {
         assert_eq!(cpu.i, (I + VAL as usize) as u16);
     }
 
+    #[test]
+    fn increment_i_vf() {
+        const X: u8 = 0x4;
+        let instr = (0xF << 12) | (X as u16) << 8 | 0x1E;
+
+        // (fx1e_sets_vf, I, VX, expected VF)
+        let table = [
+            (false, 0xFFE, 0x4, 0x7),
+            (false, 0x500, 0x4, 0x7),
+            (true, 0xFFE, 0x4, 0x1),
+            (true, 0xFFC, 0x4, 0x1),
+            (true, 0x500, 0x4, 0x0),
+        ];
+        for (sets_vf, i, val, vf) in table.iter() {
+            let mut cpu = Cpu::new(CpuConfig { fx1e_sets_vf: *sets_vf, ..Default::default() });
+            cpu.i = *i;
+            cpu.v[X as usize] = *val;
+            cpu.v[0xF] = 0x7;
+
+            assert!(cpu.decode(instr, None, None, None).is_ok());
+            assert_eq!(cpu.i, *i + *val as u16);
+            assert_eq!(cpu.v[0xF], *vf, "{} {:03X}", sets_vf, i);
+        }
+    }
+
     #[test]
     fn branch() {
-        let mut cpu = Cpu::new(false, false, false);
+        let mut cpu = Cpu::new(CpuConfig::default());
 
         let NNN = 0x456;
 
@@ -1079,39 +2399,244 @@ mod tests {
 
         assert!(cpu.decode(instr, None, None, None).is_ok());
         assert_eq!(cpu.pc, NNN + 0x23);
+
+        // VX shouldn't matter without the quirk.
+        cpu.v[0x4] = 0x10;
+        assert!(cpu.decode(instr, None, None, None).is_ok());
+        assert_eq!(cpu.pc, NNN + 0x23);
+
+        // The target address wraps around within the 12 bit address space.
+        cpu.v[ind as usize] = 0x20;
+        assert!(cpu.decode(0xBFF0, None, None, None).is_ok());
+        assert_eq!(cpu.pc, 0x010);
+    }
+
+    #[test]
+    fn branch_quirk() {
+        let mut cpu = Cpu::new(CpuConfig { jump_quirk: true, ..Default::default() });
+
+        // BXNN: X is the high nibble of NNN, and VX is used as the offset.
+        cpu.v[0] = 0x23;
+        cpu.v[0x4] = 0x10;
+        assert!(cpu.decode(0xB456, None, None, None).is_ok());
+        assert_eq!(cpu.pc, 0x456 + 0x10);
+
+        cpu.v[0xF] = 0x20;
+        assert!(cpu.decode(0xBFF0, None, None, None).is_ok());
+        assert_eq!(cpu.pc, 0x010);
     }
 
     #[test]
     fn check_key_state() {
-        let mut cpu = Cpu::new(false, false, false);
+        let mut cpu = Cpu::new(CpuConfig::default());
         const X: u8 = 0x4;
         let instr = 0xF << 12 | (X as u16) << 8 | 0x0A;
 
         let mut pressed = HashMap::new();
         // Update a key stroke.
         pressed.insert(0xA, true);
-        cpu.check_key_state(pressed.clone(), instr);
+        cpu.check_key_state(pressed.clone(), 0, instr);
 
         assert_eq!(cpu.v[X as usize], 0);
 
         // Press another key but don't release the first one.
         pressed.insert(0xB, true);
-        cpu.check_key_state(pressed.clone(), instr);
+        cpu.check_key_state(pressed.clone(), 0, instr);
 
         assert_eq!(cpu.v[X as usize], 0);
 
         // Release the first key.
         pressed.insert(0xA, false);
-        cpu.check_key_state(pressed.clone(), instr);
+        cpu.check_key_state(pressed.clone(), 0, instr);
 
         assert_eq!(cpu.v[X as usize], 0xA);
+
+        // The second key is still tracked, so releasing it completes the next "Get Key".
+        cpu.v[X as usize] = 0;
+        pressed.insert(0xB, false);
+        cpu.check_key_state(pressed.clone(), 0, instr);
+        assert_eq!(cpu.v[X as usize], 0xB);
+        assert!(cpu.pressed.is_empty());
+    }
+
+    #[test]
+    fn key_wait_timed_out() {
+        assert!(!Cpu::key_wait_timed_out(1000, None));
+        assert!(!Cpu::key_wait_timed_out(2, Some(3)));
+        assert!(Cpu::key_wait_timed_out(3, Some(3)));
+        assert!(Cpu::key_wait_timed_out(0, Some(0)));
+    }
+
+    #[test]
+    fn check_key_timeout() {
+        let mut cpu = Cpu::new(CpuConfig { key_timeout: Some(3), ..CpuConfig::default() });
+        const X: u8 = 0x6;
+        let instr = 0xF << 12 | (X as u16) << 8 | 0x0A;
+        let mut pressed: HashMap<u8, bool> = (0..=0xF).map(|key| (key, false)).collect();
+
+        // Nothing is pressed, so we keep waiting until the timeout is reached.
+        for _ in 0..3 {
+            cpu.pc = PROGRAM_ADDRESS + 2;
+            cpu.check_key_state(pressed.clone(), 0, instr);
+            assert_eq!(cpu.pc, PROGRAM_ADDRESS);
+        }
+        cpu.pc = PROGRAM_ADDRESS + 2;
+        cpu.check_key_state(pressed.clone(), 0, instr);
+        assert_eq!(cpu.pc, PROGRAM_ADDRESS + 2);
+        assert_eq!(cpu.v[X as usize], KEY_TIMEOUT_VALUE);
+
+        // A key released before the timeout is returned as usual, and the count
+        // starts over for the next "Get Key".
+        cpu.check_key_state(pressed.clone(), 0, instr);
+        pressed.insert(0x9, true);
+        cpu.check_key_state(pressed.clone(), 0, instr);
+        pressed.insert(0x9, false);
+        cpu.check_key_state(pressed.clone(), 0, instr);
+        assert_eq!(cpu.v[X as usize], 0x9);
+        assert_eq!(cpu.key_wait_cycles, 0);
+    }
+
+    #[test]
+    fn check_key_state_partial_map() {
+        let mut cpu = Cpu::new(CpuConfig::default());
+        const X: u8 = 0x5;
+        let instr = 0xF << 12 | (X as u16) << 8 | 0x0A;
+
+        // Only a couple of keys are reported.
+        let mut pressed = HashMap::new();
+        pressed.insert(0x1, true);
+        pressed.insert(0xC, true);
+        cpu.check_key_state(pressed, 0, instr);
+        assert_eq!(cpu.v[X as usize], 0);
+
+        // 0xC is dropped from the map entirely, which counts as released.
+        let mut pressed = HashMap::new();
+        pressed.insert(0x1, true);
+        cpu.check_key_state(pressed, 0, instr);
+        assert_eq!(cpu.v[X as usize], 0xC);
+
+        // 0x1 is still tracked and an empty map releases it.
+        cpu.check_key_state(HashMap::new(), 0, instr);
+        assert_eq!(cpu.v[X as usize], 0x1);
+        assert!(cpu.pressed.is_empty());
+    }
+
+    #[test]
+    fn check_key_state_within_tick() {
+        let mut cpu = Cpu::new(CpuConfig::default());
+        const X: u8 = 0x2;
+        let instr = 0xF << 12 | (X as u16) << 8 | 0x0A;
+        let released: HashMap<u8, bool> = (0..=0xF).map(|key| (key, false)).collect();
+
+        // A release from before the instruction started waiting is ignored.
+        cpu.pc = PROGRAM_ADDRESS + 2;
+        cpu.check_key_state(released.clone(), 1 << 0x3, instr);
+        assert_eq!(cpu.pc, PROGRAM_ADDRESS);
+        assert_eq!(cpu.v[X as usize], 0);
+
+        // The key is pressed and released again before the next check.
+        cpu.pc = PROGRAM_ADDRESS + 2;
+        cpu.check_key_state(released.clone(), 1 << 0x7, instr);
+        assert_eq!(cpu.pc, PROGRAM_ADDRESS + 2);
+        assert_eq!(cpu.v[X as usize], 0x7);
+        assert!(cpu.pressed.is_empty());
+    }
+
+    #[test]
+    fn check_key_state_pc_stable() {
+        let mut cpu = Cpu::new(CpuConfig::default());
+        const X: u8 = 0x3;
+        let instr = 0xF << 12 | (X as u16) << 8 | 0x0A;
+        let mut pressed: HashMap<u8, bool> = (0..=0xF).map(|key| (key, false)).collect();
+
+        // The first poll goes back to the instruction, and later ones stay there
+        // even though nothing fetched it again in between.
+        cpu.pc = PROGRAM_ADDRESS + 2;
+        cpu.check_key_state(pressed.clone(), 0, instr);
+        assert!(cpu.waiting_for_key);
+        assert_eq!(cpu.pc, PROGRAM_ADDRESS);
+        pressed.insert(0x5, true);
+        for _ in 0..3 {
+            cpu.check_key_state(pressed.clone(), 0, instr);
+            assert_eq!(cpu.pc, PROGRAM_ADDRESS);
+        }
+
+        // The release moves past the instruction exactly once.
+        pressed.insert(0x5, false);
+        cpu.check_key_state(pressed.clone(), 0, instr);
+        assert_eq!(cpu.v[X as usize], 0x5);
+        assert_eq!(cpu.pc, PROGRAM_ADDRESS + 2);
+        assert!(!cpu.waiting_for_key);
+    }
+
+    #[test]
+    // Moving the PC during a "Get Key" abandons it, so a later one doesn't
+    // return to where the first one was.
+    fn key_wait_cancelled() {
+        const X: u8 = 0x3;
+        const ADDR: u16 = 0x300;
+        let instr = 0xF << 12 | (X as u16) << 8 | 0x0A;
+        let mut pressed: HashMap<u8, bool> = (0..=0xF).map(|key| (key, false)).collect();
+        let mem = Memory::from_bytes([0; MEM_SIZE]);
+        let snapshot = Cpu::new_with_state(CpuConfig::default(), [0; 16], 0, ADDR).snapshot();
+
+        for restore in [false, true] {
+            let mut cpu = Cpu::new(CpuConfig::default());
+            cpu.pc = PROGRAM_ADDRESS + 2;
+            cpu.check_key_state(pressed.clone(), 0, instr);
+            assert!(cpu.waiting_for_key);
+
+            if restore {
+                assert_eq!(cpu.restore(&snapshot), Ok(0));
+            } else {
+                assert_eq!(cpu.set_pc(ADDR, &mem), Ok(0));
+            }
+            assert!(!cpu.waiting_for_key);
+
+            // Another "Get Key" at the new address finishes after itself.
+            cpu.pc = ADDR + 2;
+            pressed.insert(0x5, true);
+            cpu.check_key_state(pressed.clone(), 0, instr);
+            assert_eq!(cpu.pc, ADDR);
+            pressed.insert(0x5, false);
+            cpu.check_key_state(pressed.clone(), 0, instr);
+            assert_eq!(cpu.v[X as usize], 0x5);
+            assert_eq!(cpu.pc, ADDR + 2);
+        }
+    }
+
+    #[test]
+    fn get_key_fetch_decode() {
+        const X: u8 = 0x1;
+        let instr = 0xF << 12 | (X as u16) << 8 | 0x0A;
+        let mut mem_buf = [0; MEM_SIZE];
+        mem_buf[PROGRAM_ADDRESS as usize] = (instr >> 8) as u8;
+        mem_buf[PROGRAM_ADDRESS as usize + 1] = (instr & 0xFF) as u8;
+        let memory = Memory::from_bytes(mem_buf);
+        let mut cpu = Cpu::new(CpuConfig::default());
+        let mut pressed: HashMap<u8, bool> = (0..=0xF).map(|key| (key, false)).collect();
+
+        for round in 0..4 {
+            if round == 1 {
+                pressed.insert(0xE, true);
+            }
+            let fetched = cpu.fetch(&memory);
+            assert_eq!(fetched.ok(), Some(instr));
+            cpu.check_key_state(pressed.clone(), 0, instr);
+            assert_eq!(cpu.pc, PROGRAM_ADDRESS);
+        }
+
+        pressed.insert(0xE, false);
+        assert!(cpu.fetch(&memory).is_ok());
+        cpu.check_key_state(pressed, 0, instr);
+        assert_eq!(cpu.v[X as usize], 0xE);
+        assert_eq!(cpu.pc, PROGRAM_ADDRESS + 2);
     }
 
     #[test]
     fn get_sprite() {
-        let mut cpu = Cpu::new(false, false, false);
-        // TODO: Find a way to use MEM_SIZE constant.
-        let mut mem_buf = [0; 4096];
+        let mut cpu = Cpu::new(CpuConfig::default());
+        let mut mem_buf = [0; MEM_SIZE];
 
         // Fill up a buffer with a sprite:
         const I: u16 = 0x400;
@@ -1121,7 +2646,7 @@ mod tests {
             mem_buf[I as usize + i as usize] = expected_sprite[i as usize];
         }
 
-        let memory = Memory { mem: mem_buf };
+        let memory = Memory::from_bytes(mem_buf);
 
         // Set up CPU registers
         let x = 34;
@@ -1133,10 +2658,88 @@ mod tests {
         cpu.i = I;
 
         let instr: u16 = (N as u16) | (y_reg << 4) as u16 | (x_reg << 8) as u16 | (0xD << 12) as u16;
-        let (ret_x,ret_y, vec) = cpu.get_sprite(instr, &memory);
+        let (ret_x,ret_y, vec) = cpu.get_sprite(instr, &memory, WIDTH, HEIGHT, 1).unwrap();
         assert_eq!(ret_x, x);
         assert_eq!(ret_y, y);
         assert_eq!(&vec[..], &expected_sprite[..]);
     }
 
+    #[test]
+    // The sprite coordinates wrap around based on the active resolution.
+    fn get_sprite_wrap() {
+        let mut cpu = Cpu::new(CpuConfig::default());
+        let memory = Memory::from_bytes([0; MEM_SIZE]);
+
+        let x_reg = 4;
+        let y_reg = 8;
+        cpu.v[x_reg] = 100;
+        cpu.v[y_reg] = 40;
+
+        let instr: u16 = 0x1 | (y_reg << 4) as u16 | (x_reg << 8) as u16 | (0xD << 12) as u16;
+        let (ret_x, ret_y, _) = cpu.get_sprite(instr, &memory, WIDTH, HEIGHT, 1).unwrap();
+        assert_eq!(ret_x, 100 % WIDTH as u8);
+        assert_eq!(ret_y, 40 % HEIGHT as u8);
+
+        let (ret_x, ret_y, _) = cpu.get_sprite(instr, &memory, HIRES_WIDTH, HIRES_HEIGHT, 1).unwrap();
+        assert_eq!(ret_x, 100);
+        assert_eq!(ret_y, 40);
+    }
+
+    #[test]
+    // DXY0 reads a 16x16 sprite in hi-res mode, and nothing in lo-res mode.
+    fn get_sprite_16x16() {
+        let mut cpu = Cpu::new(CpuConfig::default());
+        let mut mem_buf = [0; MEM_SIZE];
+
+        const I: u16 = 0x400;
+        for i in 0..32 {
+            mem_buf[I as usize + i] = i as u8 + 1;
+        }
+        let memory = Memory::from_bytes(mem_buf);
+        cpu.i = I;
+
+        let instr: u16 = 0xD120;
+        let (_, _, vec) = cpu.get_sprite(instr, &memory, HIRES_WIDTH, HIRES_HEIGHT, 1).unwrap();
+        assert_eq!(&vec[..], &memory.mem[I as usize..I as usize + 32]);
+
+        let (_, _, vec) = cpu.get_sprite(instr, &memory, WIDTH, HEIGHT, 1).unwrap();
+        assert!(vec.is_empty());
+    }
+
+    #[test]
+    fn decode_scroll() {
+        let mut cpu = Cpu::new(CpuConfig::default());
+        let disp = Display::new(true);
+        let mut mem = Memory::from_bytes([0; MEM_SIZE]);
+
+        // Draw a single pixel at (8, 8).
+        mem.mem[0x300] = 0x80;
+        cpu.i = 0x300;
+        cpu.v[0] = 8;
+        assert!(cpu.decode(0xD001, Some(&disp), Some(&mut mem), None).is_ok());
+
+        assert!(cpu.decode(0x00C5, Some(&disp), None, None).is_ok());
+        assert!(cpu.decode(0x00FB, Some(&disp), None, None).is_ok());
+        assert!(cpu.decode(0x00FB, Some(&disp), None, None).is_ok());
+        assert!(cpu.decode(0x00FC, Some(&disp), None, None).is_ok());
+
+        // Drawing at the new location should collide with the scrolled pixel.
+        cpu.v[0] = 12;
+        cpu.v[1] = 13;
+        assert!(cpu.decode(0xD011, Some(&disp), Some(&mut mem), None).is_ok());
+        assert_eq!(cpu.v[0xF], 1);
+    }
+
+    #[test]
+    fn decode_resolution() {
+        let mut cpu = Cpu::new(CpuConfig::default());
+        let disp = Display::new(true);
+
+        assert!(cpu.decode(0x00FF, Some(&disp), None, None).is_ok());
+        assert_eq!(Display::resolution(&disp), (HIRES_WIDTH, HIRES_HEIGHT));
+
+        assert!(cpu.decode(0x00FE, Some(&disp), None, None).is_ok());
+        assert_eq!(Display::resolution(&disp), (WIDTH, HEIGHT));
+    }
+
 }
\ No newline at end of file
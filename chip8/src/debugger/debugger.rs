@@ -0,0 +1,177 @@
+use std::collections::HashSet;
+
+use crate::{disasm::disasm::disassemble, mem::mem::Memory, error::error::Chip8Error};
+
+// Commands accepted at the debugger prompt.
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Step,
+    Continue,
+    Quit,
+    Breakpoint(u16),
+    // Continue execution from the given address.
+    Goto(u16),
+}
+
+pub struct Debugger {
+    breakpoints: HashSet<u16>,
+    // Stop before every instruction, rather than only at breakpoints.
+    stepping: bool,
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        return Debugger::new();
+    }
+}
+
+impl Debugger {
+    // The debugger starts out stepping, so it stops before the first instruction.
+    pub fn new() -> Self {
+        return Debugger {
+            breakpoints: HashSet::new(),
+            stepping: true,
+        };
+    }
+
+    // Parses a line entered at the prompt: "s", "c", "q", "b NNN" or "g NNNN",
+    // where the N's are a hex address with an optional 0x prefix.
+    pub fn parse_command(line: &str) -> Result<Command, String> {
+        let mut words = line.split_whitespace();
+        let cmd = match words.next() {
+            Some("s") => Command::Step,
+            Some("c") => Command::Continue,
+            Some("q") => Command::Quit,
+            // Addresses are checked against the memory size once they're applied.
+            Some("b") => {
+                let addr = words.next().ok_or(String::from("Breakpoint requires an address."))?;
+                let addr = addr.trim_start_matches("0x");
+                match u16::from_str_radix(addr, 16) {
+                    Ok(addr) => Command::Breakpoint(addr),
+                    _ => return Err(format!("Invalid breakpoint address: {}", addr)),
+                }
+            },
+            Some("g") => {
+                let addr = words.next().ok_or(String::from("Goto requires an address."))?;
+                let addr = addr.trim_start_matches("0x");
+                match u16::from_str_radix(addr, 16) {
+                    Ok(addr) => Command::Goto(addr),
+                    _ => return Err(format!("Invalid goto address: {}", addr)),
+                }
+            },
+            Some(other) => return Err(format!("Unknown command: {}", other)),
+            None => return Err(String::from("No command entered.")),
+        };
+
+        if words.next().is_some() {
+            return Err(format!("Unexpected arguments: {}", line.trim()));
+        }
+
+        return Ok(cmd);
+    }
+
+    // Breakpoints have to lie within |mem|, which is bigger with XO-CHIP.
+    pub fn apply(&mut self, cmd: &Command, mem: &Memory) -> Result<i32, Chip8Error> {
+        match cmd {
+            Command::Step => self.stepping = true,
+            Command::Continue => self.stepping = false,
+            Command::Breakpoint(addr) => {
+                if *addr as usize + 1 >= mem.size() {
+                    return Err(Chip8Error::InvalidAddress((*addr).into()));
+                }
+                self.breakpoints.insert(*addr);
+            },
+            Command::Goto(_) | Command::Quit => {},
+        }
+        return Ok(0);
+    }
+
+    // Whether execution stops before every instruction.
+    pub fn is_stepping(&self) -> bool {
+        return self.stepping;
+    }
+
+    // Whether execution should stop before the instruction at |pc|.
+    pub fn should_break(&self, pc: u16) -> bool {
+        return self.stepping || self.breakpoints.contains(&pc);
+    }
+
+    pub fn format_state(pc: u16, instr: u16, registers: &[u8; 16]) -> String {
+        let regs: Vec<String> = registers.iter().enumerate()
+            .map(|(ind, val)| format!("V{:X}={:02X}", ind, val))
+            .collect();
+        return format!("0x{:03X}: {:04X} {}\n{}", pc, instr, disassemble(instr), regs.join(" "));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mem::mem::Memory;
+
+    use super::{Command, Debugger};
+
+    #[test]
+    fn parse_command() {
+        assert_eq!(Debugger::parse_command("s"), Ok(Command::Step));
+        assert_eq!(Debugger::parse_command(" c \n"), Ok(Command::Continue));
+        assert_eq!(Debugger::parse_command("q"), Ok(Command::Quit));
+        assert_eq!(Debugger::parse_command("b 2A4"), Ok(Command::Breakpoint(0x2A4)));
+        assert_eq!(Debugger::parse_command("b 0x300"), Ok(Command::Breakpoint(0x300)));
+        assert_eq!(Debugger::parse_command("g 2A4"), Ok(Command::Goto(0x2A4)));
+        assert_eq!(Debugger::parse_command("g 0xFFFE"), Ok(Command::Goto(0xFFFE)));
+
+        assert!(Debugger::parse_command("").is_err());
+        assert!(Debugger::parse_command("b").is_err());
+        assert!(Debugger::parse_command("b xyz").is_err());
+        assert_eq!(Debugger::parse_command("b 0xF000"), Ok(Command::Breakpoint(0xF000)));
+        assert!(Debugger::parse_command("b 10000").is_err());
+        assert!(Debugger::parse_command("g").is_err());
+        assert!(Debugger::parse_command("g 10000").is_err());
+        assert!(Debugger::parse_command("g 200 4").is_err());
+        assert!(Debugger::parse_command("s 2").is_err());
+        assert!(Debugger::parse_command("x").is_err());
+    }
+
+    #[test]
+    fn breakpoints() {
+        let mut debugger = Debugger::new();
+        let mem = Memory::new();
+        // Stepping stops everywhere.
+        assert!(debugger.is_stepping());
+        assert!(debugger.should_break(0x200));
+        assert!(debugger.should_break(0x202));
+
+        assert!(debugger.apply(&Command::Breakpoint(0x204), &mem).is_ok());
+        assert!(debugger.apply(&Command::Continue, &mem).is_ok());
+        assert!(!debugger.is_stepping());
+        assert!(!debugger.should_break(0x200));
+        assert!(!debugger.should_break(0x202));
+        assert!(debugger.should_break(0x204));
+
+        assert!(debugger.apply(&Command::Step, &mem).is_ok());
+        assert!(debugger.should_break(0x206));
+    }
+
+    #[test]
+    fn breakpoint_range() {
+        let mut debugger = Debugger::new();
+        debugger.apply(&Command::Continue, &Memory::new()).unwrap();
+
+        // Only XO-CHIP programs can run above 4KB.
+        assert!(debugger.apply(&Command::Breakpoint(0x1200), &Memory::new()).is_err());
+        assert!(!debugger.should_break(0x1200));
+        assert!(debugger.apply(&Command::Breakpoint(0x1200), &Memory::new_xo_chip()).is_ok());
+        assert!(debugger.should_break(0x1200));
+        assert!(debugger.apply(&Command::Breakpoint(0xFFFF), &Memory::new_xo_chip()).is_err());
+    }
+
+    #[test]
+    fn format_state() {
+        let mut registers = [0; 16];
+        registers[0xA] = 0x3C;
+        let state = Debugger::format_state(0x204, 0x6A3C, &registers);
+        assert_eq!(state, "0x204: 6A3C LD VA, 0x3C\n\
+                           V0=00 V1=00 V2=00 V3=00 V4=00 V5=00 V6=00 V7=00 \
+                           V8=00 V9=00 VA=3C VB=00 VC=00 VD=00 VE=00 VF=00");
+    }
+}
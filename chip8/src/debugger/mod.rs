@@ -0,0 +1 @@
+pub mod debugger;
\ No newline at end of file
@@ -0,0 +1,177 @@
+// Converts an opcode into a human readable mnemonic, mostly following the
+// naming used in Cowgod's CHIP-8 technical reference. Opcodes which the
+// emulator doesn't support are emitted as raw data words.
+pub fn disassemble(instr: u16) -> String {
+    let x = (instr >> 8) & 0xF;
+    let y = (instr >> 4) & 0xF;
+    let n = instr & 0xF;
+    let nn = instr & 0xFF;
+    let nnn = instr & 0xFFF;
+
+    match instr {
+        0x00E0 => return String::from("CLS"),
+        0x00EE => return String::from("RET"),
+        0x00FB => return String::from("SCR"),
+        0x00FC => return String::from("SCL"),
+        0x00FE => return String::from("LOW"),
+        0x00FF => return String::from("HIGH"),
+        // The address is held in the word that follows.
+        0xF000 => return String::from("LD I, LONG"),
+        0xF002 => return String::from("AUDIO"),
+        _ if (instr & 0xFFF0) == 0x00C0 => return format!("SCD {}", n),
+        _ => {},
+    }
+
+    let mnemonic = match (instr >> 12) & 0xF {
+        0x0 => format!("SYS 0x{:03X}", nnn),
+        0x1 => format!("JP 0x{:03X}", nnn),
+        0x2 => format!("CALL 0x{:03X}", nnn),
+        0x3 => format!("SE V{:X}, 0x{:02X}", x, nn),
+        0x4 => format!("SNE V{:X}, 0x{:02X}", x, nn),
+        0x5 if n == 0 => format!("SE V{:X}, V{:X}", x, y),
+        0x6 => format!("LD V{:X}, 0x{:02X}", x, nn),
+        0x7 => format!("ADD V{:X}, 0x{:02X}", x, nn),
+        0x8 => match n {
+            0x0 => format!("LD V{:X}, V{:X}", x, y),
+            0x1 => format!("OR V{:X}, V{:X}", x, y),
+            0x2 => format!("AND V{:X}, V{:X}", x, y),
+            0x3 => format!("XOR V{:X}, V{:X}", x, y),
+            0x4 => format!("ADD V{:X}, V{:X}", x, y),
+            0x5 => format!("SUB V{:X}, V{:X}", x, y),
+            0x6 => format!("SHR V{:X}, V{:X}", x, y),
+            0x7 => format!("SUBN V{:X}, V{:X}", x, y),
+            0xE => format!("SHL V{:X}, V{:X}", x, y),
+            _ => format!("DW 0x{:04X}", instr),
+        },
+        0x9 if n == 0 => format!("SNE V{:X}, V{:X}", x, y),
+        0xA => format!("LD I, 0x{:03X}", nnn),
+        0xB => format!("JP V0, 0x{:03X}", nnn),
+        0xC => format!("RND V{:X}, 0x{:02X}", x, nn),
+        0xD => format!("DRW V{:X}, V{:X}, {}", x, y, n),
+        0xE => match nn {
+            0x9E => format!("SKP V{:X}", x),
+            0xA1 => format!("SKNP V{:X}", x),
+            _ => format!("DW 0x{:04X}", instr),
+        },
+        0xF => match nn {
+            0x01 => format!("PLANE {}", x),
+            0x07 => format!("LD V{:X}, DT", x),
+            0x0A => format!("LD V{:X}, K", x),
+            0x15 => format!("LD DT, V{:X}", x),
+            0x18 => format!("LD ST, V{:X}", x),
+            0x1E => format!("ADD I, V{:X}", x),
+            0x29 => format!("LD F, V{:X}", x),
+            0x30 => format!("LD HF, V{:X}", x),
+            0x3A => format!("PITCH V{:X}", x),
+            0x33 => format!("LD B, V{:X}", x),
+            0x55 => format!("LD [I], V{:X}", x),
+            0x65 => format!("LD V{:X}, [I]", x),
+            0x75 if x < 8 => format!("LD R, V{:X}", x),
+            0x85 if x < 8 => format!("LD V{:X}, R", x),
+            _ => format!("DW 0x{:04X}", instr),
+        },
+        _ => format!("DW 0x{:04X}", instr),
+    };
+
+    return mnemonic;
+}
+
+// Walks the program two bytes at a time, and returns a line with the address and
+// mnemonic for each instruction. A trailing odd byte is emitted as a data byte.
+pub fn disassemble_program(program: &[u8], start_addr: usize) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    for (ind, chunk) in program.chunks(2).enumerate() {
+        let addr = start_addr + 2 * ind;
+        if chunk.len() == 2 {
+            let instr = ((chunk[0] as u16) << 8) | chunk[1] as u16;
+            lines.push(format!("0x{:03X}: {}", addr, disassemble(instr)));
+        } else {
+            lines.push(format!("0x{:03X}: DB 0x{:02X}", addr, chunk[0]));
+        }
+    }
+
+    return lines;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{disassemble, disassemble_program};
+
+    #[test]
+    fn disassemble_table() {
+        let table: [(u16, &str); 53] = [
+            (0x00E0, "CLS"),
+            (0x00EE, "RET"),
+            (0x00C4, "SCD 4"),
+            (0x00FB, "SCR"),
+            (0x00FC, "SCL"),
+            (0x00FE, "LOW"),
+            (0x00FF, "HIGH"),
+            (0x0123, "SYS 0x123"),
+            (0x1200, "JP 0x200"),
+            (0x2ABC, "CALL 0xABC"),
+            (0x3A12, "SE VA, 0x12"),
+            (0x4B34, "SNE VB, 0x34"),
+            (0x5120, "SE V1, V2"),
+            (0x5121, "DW 0x5121"),
+            (0x620C, "LD V2, 0x0C"),
+            (0x7FFF, "ADD VF, 0xFF"),
+            (0x8120, "LD V1, V2"),
+            (0x8121, "OR V1, V2"),
+            (0x8122, "AND V1, V2"),
+            (0x8123, "XOR V1, V2"),
+            (0x8124, "ADD V1, V2"),
+            (0x8125, "SUB V1, V2"),
+            (0x8126, "SHR V1, V2"),
+            (0x8127, "SUBN V1, V2"),
+            (0x812E, "SHL V1, V2"),
+            (0x8128, "DW 0x8128"),
+            (0x9340, "SNE V3, V4"),
+            (0xA22A, "LD I, 0x22A"),
+            (0xB300, "JP V0, 0x300"),
+            (0xC0FF, "RND V0, 0xFF"),
+            (0xD015, "DRW V0, V1, 5"),
+            (0xE59E, "SKP V5"),
+            (0xE5A1, "SKNP V5"),
+            (0xE5A2, "DW 0xE5A2"),
+            (0xF301, "PLANE 3"),
+            (0xF607, "LD V6, DT"),
+            (0xF60A, "LD V6, K"),
+            (0xF615, "LD DT, V6"),
+            (0xF618, "LD ST, V6"),
+            (0xF61E, "ADD I, V6"),
+            (0xF629, "LD F, V6"),
+            (0xF730, "LD HF, V7"),
+            (0xF633, "LD B, V6"),
+            (0xF655, "LD [I], V6"),
+            (0xF665, "LD V6, [I]"),
+            (0xF000, "LD I, LONG"),
+            (0xF002, "AUDIO"),
+            (0xF63A, "PITCH V6"),
+            (0xF675, "LD R, V6"),
+            (0xF685, "LD V6, R"),
+            (0xF875, "DW 0xF875"),
+            (0xF6FF, "DW 0xF6FF"),
+            (0x0000, "SYS 0x000"),
+        ];
+
+        for (instr, expected) in table.iter() {
+            assert_eq!(disassemble(*instr), *expected, "opcode 0x{:04X}", instr);
+        }
+    }
+
+    #[test]
+    fn disassemble_rom() {
+        let program = vec![0x00, 0xE0, 0x60, 0x05, 0xFF, 0xFF, 0x12, 0x00, 0xAB];
+        let lines = disassemble_program(&program, 0x200);
+        assert_eq!(lines, vec![
+            "0x200: CLS",
+            "0x202: LD V0, 0x05",
+            "0x204: DW 0xFFFF",
+            "0x206: JP 0x200",
+            "0x208: DB 0xAB",
+        ]);
+
+        assert!(disassemble_program(&[], 0x200).is_empty());
+    }
+}
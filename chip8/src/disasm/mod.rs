@@ -0,0 +1 @@
+pub mod disasm;
\ No newline at end of file
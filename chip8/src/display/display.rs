@@ -1,322 +1,2484 @@
-use std::{sync::{Arc, Mutex}, thread, time::Duration, collections::HashMap};
+use std::{sync::{Arc, Mutex, MutexGuard, LockResult, atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering}},
+          thread::{self, JoinHandle},
+          time::{Duration, Instant},
+          collections::{HashMap, VecDeque}, io::{self, Read, Write}};
 
-use show_image::{ImageView, ImageInfo, create_window, WindowProxy, event::ElementState};
+use show_image::{ImageView, ImageInfo, create_window, WindowOptions, WindowProxy, event::{ElementState, WindowEvent}};
+
+use crate::error::error::Chip8Error;
+use crate::timer::timer::{Clock, RealClock, Ticker};
 
 pub const WIDTH: usize = 64;
 pub const HEIGHT: usize = 32;
 
+// SUPER-CHIP high resolution mode dimensions.
+pub const HIRES_WIDTH: usize = 128;
+pub const HIRES_HEIGHT: usize = 64;
+
 const ON_PIXEL: u8 = 0xFF;
 const OFF_PIXEL: u8 = 0x0;
+// Shades used when rendering pixels that are only lit in the second XO-CHIP
+// plane, or lit in both planes.
+const PLANE2_PIXEL: u8 = 0x55;
+const BOTH_PLANES_PIXEL: u8 = 0xAA;
+
+// Colors are specified as (red, green, blue) triples.
+pub type Rgb = [u8; 3];
+const DEFAULT_FG: Rgb = [0xFF, 0xFF, 0xFF];
+const DEFAULT_BG: Rgb = [0x0, 0x0, 0x0];
+
+// XO-CHIP has 2 display planes, selected with a bitmask.
+const NUM_PLANES: usize = 2;
+const DEFAULT_PLANES: u8 = 0x1;
+
+// The window starts out this many times larger than the low resolution screen.
+pub const DEFAULT_SCALE: u32 = 10;
 
 const THREAD_LOOP_SLEEP_US: u64 = 1666;
+// Key presses and releases queued up beyond this drop the oldest ones, for when
+// nothing is draining the queue.
+const KEY_EVENT_QUEUE_LEN: usize = 64;
+// In terminal mode the screen is redrawn at roughly 60Hz.
+const TERMINAL_FRAME_US: u64 = 16666;
+// Terminals only report key presses, so keys are released after being held this long.
+const TERMINAL_KEY_HOLD_MS: u64 = 100;
+// The window is rendered at 60Hz, whether or not the image has changed.
+const RENDER_PERIOD: Duration = Duration::from_micros(16666);
+// Number of rendered frames the FPS is averaged over.
+const FPS_WINDOW: usize = 30;
+// With fading enabled, how much a switched off pixel dims every 60Hz frame.
+const FADE_STEP: u8 = 0x10;
+
+// Size of the glyphs in the built-in font, which are used to draw the HUD. Each
+// glyph row is a byte, with the pixels in the upper nibble.
+const GLYPH_WIDTH: usize = 4;
+const GLYPH_HEIGHT: usize = 5;
+// Characters are drawn with a column of spacing between them.
+const GLYPH_ADVANCE: usize = GLYPH_WIDTH + 1;
+
+// Scancode of the "P" key, which toggles pausing the emulation.
+const PAUSE_SCANCODE: u32 = 25;
+// Scancode of the "M" key, which toggles muting the sound.
+const MUTE_SCANCODE: u32 = 50;
+// Scancode of the "F12" key, which saves a screenshot.
+const SCREENSHOT_SCANCODE: u32 = 88;
+// Scancode of the "Tab" key, which runs the emulation faster while held.
+const TURBO_SCANCODE: u32 = 15;
+// Scancode of the "F2" key, which saves the memory contents.
+const DUMP_SCANCODE: u32 = 60;
+// Scancode of the "F5" key, which resets the machine.
+const RESET_SCANCODE: u32 = 63;
+// Scancodes of the "1" to "9" keys, which switch between programs while Ctrl is held.
+const FIRST_ROM_SCANCODE: u32 = 2;
+const LAST_ROM_SCANCODE: u32 = 10;
+
+// Details of a sprite draw, for debugging.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DrawResult {
+    pub vf: u8, // What VF gets set to.
+    pub pixels_flipped: usize, // Pixels toggled on or off, across all the selected planes.
+    pub rows_drawn: usize, // Sprite rows which weren't clipped at the bottom edge.
+}
+
+// What drawing a sprite did to a single plane.
+#[derive(Debug, Default, PartialEq)]
+struct PlaneDraw {
+    collided_rows: u32, // Bit N is set if a pixel in row N of the sprite got switched off.
+    pixels_flipped: usize,
+    rows_drawn: usize,
+}
+
+// Pixel buffer, along with the dimensions of the resolution it currently represents.
+// |pixels| is the first plane, which is the only one used outside of XO-CHIP.
+#[derive(Clone)]
+struct FrameBuffer {
+    pixels: Vec<u8>,
+    pixels2: Vec<u8>,
+    width: usize,
+    height: usize,
+    // Bitmask of the planes that drawing, clearing and scrolling act on.
+    planes: u8,
+}
+
+impl FrameBuffer {
+    fn new(width: usize, height: usize) -> Self {
+        FrameBuffer {
+            pixels: vec![OFF_PIXEL; width * height],
+            pixels2: vec![OFF_PIXEL; width * height],
+            width,
+            height,
+            planes: DEFAULT_PLANES,
+        }
+    }
+
+    fn plane_mut(&mut self, plane: usize) -> &mut Vec<u8> {
+        if plane == 0 {
+            return &mut self.pixels;
+        }
+
+        return &mut self.pixels2;
+    }
+
+    // Indices of the currently selected planes.
+    fn selected_planes(&self) -> Vec<usize> {
+        return (0..NUM_PLANES).filter(|plane| self.planes & (1 << plane) != 0).collect();
+    }
+
+    // Whether each pixel is lit in either plane.
+    fn lit_pixels(&self) -> Vec<bool> {
+        return self.pixels.iter().zip(self.pixels2.iter())
+            .map(|(pxl1, pxl2)| *pxl1 == ON_PIXEL || *pxl2 == ON_PIXEL)
+            .collect();
+    }
+
+    // Combine both planes into a single mono8 image.
+    fn composed_pixels(&self) -> Vec<u8> {
+        return self.pixels.iter().zip(self.pixels2.iter())
+            .map(|(pxl1, pxl2)| match (*pxl1 == ON_PIXEL, *pxl2 == ON_PIXEL) {
+                (true, true) => BOTH_PLANES_PIXEL,
+                (true, false) => ON_PIXEL,
+                (false, true) => PLANE2_PIXEL,
+                (false, false) => OFF_PIXEL,
+            })
+            .collect();
+    }
+}
+
+// Puts stdin into raw mode, which hands over key presses as they happen without
+// echoing them. The original settings are restored when dropped.
+struct RawTerminal {
+    original: libc::termios,
+}
+
+impl RawTerminal {
+    fn new() -> io::Result<RawTerminal> {
+        // SAFETY: termios is plain data, which tcgetattr() fills in.
+        let mut original: libc::termios = unsafe { std::mem::zeroed() };
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut original) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut raw = original;
+        unsafe { libc::cfmakeraw(&mut raw) };
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        return Ok(RawTerminal { original });
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.original) } != 0 {
+            eprintln!("Failed to restore the terminal: {}", io::Error::last_os_error());
+        }
+    }
+}
+
+// A Mutex which keeps count of how many times it has been locked, so that the
+// contention on the frame buffer can be measured.
+struct CountedMutex<T> {
+    inner: Mutex<T>,
+    locks: AtomicU64,
+}
+
+impl<T> CountedMutex<T> {
+    fn new(val: T) -> Self {
+        CountedMutex {
+            inner: Mutex::new(val),
+            locks: AtomicU64::new(0),
+        }
+    }
+
+    fn lock(&self) -> LockResult<MutexGuard<'_, T>> {
+        self.locks.fetch_add(1, Ordering::Relaxed);
+        return self.inner.lock();
+    }
+
+    #[cfg(test)]
+    fn lock_count(&self) -> u64 {
+        return self.locks.load(Ordering::Relaxed);
+    }
+}
 
-// We implement the display using a linear vector of 32 bit values.
+// We implement the display using a linear vector of 8 bit values.
 pub struct Display {
-    buf: Mutex<[u8; WIDTH * HEIGHT]>,
+    // The frame which gets rendered.
+    buf: CountedMutex<FrameBuffer>,
+    // When batching, the emulation draws here instead and the frame is copied
+    // over to |buf| once per frame, so that it doesn't contend with rendering.
+    shadow: CountedMutex<FrameBuffer>,
+    batched: AtomicBool,
+    // The rendered frame changed since the window was last updated.
+    dirty: AtomicBool,
+    // The batched frame changed since it was last flushed.
+    shadow_dirty: AtomicBool,
+    // Sprites wrap around to the opposite edge instead of being clipped, set
+    // separately for the horizontal and vertical edges.
+    wrap_x: AtomicBool,
+    wrap_y: AtomicBool,
+    // Draws report the number of sprite rows with a collision, rather than just 1.
+    count_collision_rows: AtomicBool,
     window: Option<Mutex<WindowProxy>>,
     // Maintain state whether the key is currently pressed or not.
     keys_state: Mutex<HashMap<u8, bool>>,
+    // Presses (true) and releases (false) of keys in the order they happened,
+    // so that a quick tap isn't lost between two reads of |keys_state|.
+    key_events: Mutex<VecDeque<(u8, bool)>>,
+    // Maps keyboard scancodes to CHIP-8 keys.
+    key_mapping: Mutex<HashMap<u32, u8>>,
+    paused: Mutex<bool>,
+    muted: Mutex<bool>,
+    turbo: Mutex<bool>,
+    // Set when the reset key is pressed, until the main loop acts on it.
+    reset_requested: Mutex<bool>,
+    // Set once the user asks to quit, for the main loop to wind down.
+    quit_requested: AtomicBool,
+    // Set when the memory dump key is pressed, until the main loop acts on it.
+    dump_requested: Mutex<bool>,
+    // Index of the program to switch to, until the main loop acts on it.
+    rom_requested: Mutex<Option<usize>>,
+    // Foreground and background colors used when rendering.
+    palette: Mutex<(Rgb, Rgb)>,
+    // Swap lit and unlit pixels when rendering.
+    inverted: Mutex<bool>,
+    // Where screenshots are written to, if enabled.
+    screenshot_path: Mutex<Option<String>>,
+    // When fading is enabled, holds the intensity of each rendered pixel and
+    // the frame it was last dimmed in.
+    fade: Mutex<Option<(Vec<u8>, u64)>>,
+    // Number of 60Hz frames the emulation has gone through.
+    frame: Mutex<u64>,
+    // When the HUD is enabled, holds the font glyphs it's drawn with.
+    hud_font: Mutex<Option<Vec<u8>>>,
+    // Frames per second rendered by the display thread, stored as the bits of
+    // an f32 so that reading it doesn't contend with the render loop.
+    fps: AtomicU32,
+    // Factor the low resolution screen is scaled by to size the window.
+    scale: u32,
+    // Number of draws which switched off a pixel, for debugging sprite logic.
+    collisions: AtomicU64,
+    // Called whenever the screen is cleared, for front-ends to hook into.
+    clear_callback: Mutex<Option<Box<dyn Fn() + Send>>>,
+    // Rendering to the terminal rather than a window.
+    terminal: AtomicBool,
+    // When in terminal mode, when each currently held key was pressed.
+    terminal_presses: Mutex<HashMap<u8, Instant>>,
+    // Keeps stdin in raw mode while in terminal mode.
+    raw_terminal: Mutex<Option<RawTerminal>>,
+    // Tells the display thread to exit.
+    shutdown: AtomicBool,
+    thread: Mutex<Option<JoinHandle<()>>>,
 }
 
 impl Display {
     pub fn new(for_test: bool) -> Arc<Display> {
+        return Display::with_scale(for_test, DEFAULT_SCALE);
+    }
+
+    pub fn with_scale(for_test: bool, scale: u32) -> Arc<Display> {
         let disp = Arc::new(Display {
-            buf: Mutex::new([OFF_PIXEL; WIDTH * HEIGHT]),
+            buf: CountedMutex::new(FrameBuffer::new(WIDTH, HEIGHT)),
+            shadow: CountedMutex::new(FrameBuffer::new(WIDTH, HEIGHT)),
+            batched: AtomicBool::new(false),
+            dirty: AtomicBool::new(true),
+            shadow_dirty: AtomicBool::new(false),
+            wrap_x: AtomicBool::new(false),
+            wrap_y: AtomicBool::new(false),
+            count_collision_rows: AtomicBool::new(false),
             window: if !for_test {
-                    Some(Mutex::new(create_window("image", Default::default())
+                    Some(Mutex::new(create_window("image", Display::window_options(scale))
                                     .unwrap_or_else(|e| {
                     panic!("{}", e);})))
                 } else {
                     None
                 },
             keys_state: Mutex::new(HashMap::new()),
+            key_events: Mutex::new(VecDeque::with_capacity(KEY_EVENT_QUEUE_LEN)),
+            key_mapping: Mutex::new(Display::default_key_mapping()),
+            paused: Mutex::new(false),
+            muted: Mutex::new(false),
+            turbo: Mutex::new(false),
+            reset_requested: Mutex::new(false),
+            quit_requested: AtomicBool::new(false),
+            dump_requested: Mutex::new(false),
+            rom_requested: Mutex::new(None),
+            palette: Mutex::new((DEFAULT_FG, DEFAULT_BG)),
+            inverted: Mutex::new(false),
+            screenshot_path: Mutex::new(None),
+            fade: Mutex::new(None),
+            frame: Mutex::new(0),
+            hud_font: Mutex::new(None),
+            fps: AtomicU32::new(0),
+            scale,
+            collisions: AtomicU64::new(0),
+            clear_callback: Mutex::new(None),
+            terminal: AtomicBool::new(false),
+            terminal_presses: Mutex::new(HashMap::new()),
+            raw_terminal: Mutex::new(None),
+            shutdown: AtomicBool::new(false),
+            thread: Mutex::new(None),
         });
 
-        let disp_clone = Arc::clone(&disp); // Create a clone of the Arc
-
         if !for_test {
-            thread::spawn(move || {
-                Display::thread_loop(disp_clone);
-            });
+            Display::spawn_thread(&disp);
         }
 
         disp
     }
 
-    fn scancode_to_key(scancode: u32) -> Result<u8, String> {
-        match scancode {
-            2 => return Ok(0x1),
-            3 => return Ok(0x2),
-            4 => return Ok(0x3),
-            5 => return Ok(0xC),
-            16 => return Ok(0x4),
-            17 => return Ok(0x5),
-            18 => return Ok(0x6),
-            19 => return Ok(0xD),
-            30 => return Ok(0x7),
-            31 => return Ok(0x8),
-            32 => return Ok(0x9),
-            33 => return Ok(0xE),
-            44 => return Ok(0xA),
-            45 => return Ok(0x0),
-            46 => return Ok(0xB),
-            47 => return Ok(0xF),
-            _ => return Err(format!("Invalid keypress: {}", scancode)),
-        }
-    }
-
-    fn set_key_state(disp: &Arc<Display>, scan_code: u32, state: ElementState) -> Result<i32, String> {
-        let key_code = Display::scancode_to_key(scan_code)?;
-
-        let mut keys_state = disp.keys_state.lock().unwrap();
-        match state {
-            ElementState::Pressed => { keys_state.insert(key_code, true); },
-            ElementState::Released => { keys_state.insert(key_code, false); },
+    // Render to stdout using block characters instead of opening a window, for
+    // when there's no graphical environment. Input is read from stdin, which
+    // is switched to raw mode until shutdown.
+    pub fn new_terminal() -> Arc<Display> {
+        let disp = Display::with_scale(true, DEFAULT_SCALE);
+        disp.terminal.store(true, Ordering::Relaxed);
+        match RawTerminal::new() {
+            Ok(raw) => *disp.raw_terminal.lock().unwrap() = Some(raw),
+            Err(e) => eprintln!("Failed to set up the terminal: {}", e),
         }
 
-        return Ok(0);
+        let disp_clone = Arc::clone(&disp);
+        *disp.thread.lock().unwrap() = Some(thread::spawn(move || {
+            Display::terminal_loop(disp_clone);
+        }));
+
+        // Reading stdin blocks, so this thread can't be joined and is left to
+        // end along with the process.
+        let disp_clone = Arc::clone(&disp);
+        thread::spawn(move || {
+            Display::terminal_input_loop(disp_clone);
+        });
+
+        return disp;
     }
 
-    pub fn get_key_state(disp: &Arc<Display>, key: u8) -> Result<bool, String> {
-        if key > 0xF {
-            return Err(format!("Invalid key provided: {}", key));
-        } else {
-            let keys_state = disp.keys_state.lock().unwrap();
-            match keys_state.get(&key) {
-                Some(val) => return Ok(*val),
-                None => return Ok(false),
+    fn spawn_thread(disp: &Arc<Display>) {
+        Display::spawn_thread_with_clock(disp, Arc::new(RealClock));
+    }
+
+    fn spawn_thread_with_clock(disp: &Arc<Display>, clock: Arc<dyn Clock>) {
+        let disp_clone = Arc::clone(disp); // Create a clone of the Arc
+        *disp.thread.lock().unwrap() = Some(thread::spawn(move || {
+            Display::thread_loop(disp_clone, clock);
+        }));
+    }
+
+    // Stop the display thread and wait for it to exit. The window is no longer
+    // updated afterwards.
+    pub fn shutdown(disp: &Arc<Display>) {
+        disp.shutdown.store(true, Ordering::Relaxed);
+        let handle = disp.thread.lock().unwrap().take();
+        if let Some(handle) = handle {
+            if handle.join().is_err() {
+                eprintln!("Display thread panicked");
             }
         }
+
+        // Dropping the guard puts the terminal back the way it was.
+        disp.raw_terminal.lock().unwrap().take();
     }
 
-    fn handle_window_events(disp: &Arc<Display>, window: &mut WindowProxy) {
-        if let Ok(event) = window.event_channel() {
-            match event.recv_timeout(Duration::from_micros(THREAD_LOOP_SLEEP_US)) {
-                Ok(wevent) => {
-                    match wevent {
-                        show_image::event::WindowEvent::KeyboardInput(kb_input) => {
-                            match Display::set_key_state(disp, kb_input.input.scan_code, kb_input.input.state) {
-                                Err(e) => eprintln!("Set key state failed: {}", e),
-                                _ => {},
-                            }
-                        },
-                        show_image::event::WindowEvent::CloseRequested(_) => std::process::exit(0),
-                        _ => {},
-                    }
-                }
-                Err(_) => {},
+    fn terminal_loop(disp: Arc<Display>) {
+        let mut stdout = io::stdout();
+        // Clear the screen and hide the cursor.
+        let _ = write!(stdout, "\x1b[2J\x1b[?25l");
+        let mut ticker = Ticker::new(Duration::from_micros(TERMINAL_FRAME_US));
+        while !disp.shutdown.load(Ordering::Relaxed) {
+            let (pixels, width, height) = {
+                let buf = disp.buf.lock().unwrap();
+                (buf.lit_pixels(), buf.width, buf.height)
+            };
+            // Go back to the top left corner and draw over the last frame. Raw
+            // mode needs an explicit carriage return on every line.
+            let frame = Display::to_ascii(&pixels, width, height).join("\r\n");
+            if write!(stdout, "\x1b[H{}", frame).and_then(|_| stdout.flush()).is_err() {
+                break;
             }
+
+            Display::release_terminal_keys(&disp, Duration::from_millis(TERMINAL_KEY_HOLD_MS));
+            ticker.sleep();
         }
+        let _ = write!(stdout, "\x1b[?25h\r\n");
+        let _ = stdout.flush();
     }
 
-    fn thread_loop(disp: Arc<Display>) {
-        loop {
-            if let Some(window_mutex) = &disp.window {
-                if let Ok(mut window_lock) = window_mutex.lock() {
-                    let window = &mut *window_lock;
-                    if let Err(err) = window.set_image("image", ImageView::new(
-                        ImageInfo::mono8(WIDTH as u32, HEIGHT as u32),
-                        &*disp.buf.lock().unwrap(),
-                    )) {
-                        eprintln!("Failed to set image: {}", err);
-                    }
-
-                    Display::handle_window_events(&disp, window);
-                }
+    fn terminal_input_loop(disp: Arc<Display>) {
+        for byte in io::stdin().lock().bytes() {
+            match byte {
+                Ok(byte) => Display::handle_terminal_input(&disp, byte),
+                Err(_) => break,
+            }
+            if Display::quit_requested(&disp) {
+                break;
             }
         }
     }
 
-    pub fn clear(disp: &Arc<Display>) {
-        Display::clear_buf(&disp.buf);
+    // Handles a character typed in terminal mode, using the same keyboard
+    // layout as the window.
+    fn handle_terminal_input(disp: &Arc<Display>, byte: u8) {
+        let key = match byte.to_ascii_lowercase() {
+            // Raw mode swallows Ctrl+C, so pass it on ourselves.
+            0x03 => return Display::request_quit(disp),
+            b'p' => return Display::toggle_pause(disp),
+            b'm' => return Display::set_muted(disp, !Display::is_muted(disp)),
+            b'1' => 0x1, b'2' => 0x2, b'3' => 0x3, b'4' => 0xC,
+            b'q' => 0x4, b'w' => 0x5, b'e' => 0x6, b'r' => 0xD,
+            b'a' => 0x7, b's' => 0x8, b'd' => 0x9, b'f' => 0xE,
+            b'z' => 0xA, b'x' => 0x0, b'c' => 0xB, b'v' => 0xF,
+            _ => return,
+        };
+
+        if Display::press_key(disp, key).is_ok() {
+            disp.terminal_presses.lock().unwrap().insert(key, Instant::now());
+        }
     }
 
-    fn clear_buf(buf:&Mutex<[u8; WIDTH * HEIGHT]>) {
-        let mut buf_unlocked = buf.lock().unwrap();
-        for pxl in buf_unlocked.iter_mut() {
-            *pxl = 0;
+    // Release the keys which were pressed in the terminal more than |hold| ago.
+    fn release_terminal_keys(disp: &Arc<Display>, hold: Duration) {
+        let mut presses = disp.terminal_presses.lock().unwrap();
+        let expired: Vec<u8> = presses.iter()
+            .filter(|(_, pressed_at)| pressed_at.elapsed() >= hold)
+            .map(|(key, _)| *key)
+            .collect();
+        for key in expired {
+            presses.remove(&key);
+            let _ = Display::release_key(disp, key);
         }
     }
 
-    pub fn draw(disp: &Arc<Display>, x: u8, y: u8, sprite: &Vec<u8>) -> u8 {
-        let vf = Display::update_buf_sprite(&disp.buf, x, y, sprite);
+    // Convert the lit |pixels| into lines of text. Every character covers two
+    // rows, using half blocks for when only one of them is lit.
+    fn to_ascii(pixels: &[bool], width: usize, height: usize) -> Vec<String> {
+        let mut lines: Vec<String> = Vec::with_capacity(height.div_ceil(2));
+        for y in (0..height).step_by(2) {
+            let line: String = (0..width).map(|x| {
+                let top = pixels[y * width + x];
+                let bottom = y + 1 < height && pixels[(y + 1) * width + x];
+                match (top, bottom) {
+                    (true, true) => '█',
+                    (true, false) => '▀',
+                    (false, true) => '▄',
+                    (false, false) => ' ',
+                }
+            }).collect();
+            lines.push(line);
+        }
 
-        return vf;
+        return lines;
     }
 
-    // Performs the draw of the sprite, and returns
-    // what the eventual value of F register should be.
-    fn update_buf_sprite(buf: &Mutex<[u8; WIDTH * HEIGHT]>, x: u8, y:u8, sprite: &Vec<u8>) -> u8 {
-        let mut vf: u8 = 0;
-        let mut buf_unlocked = buf.lock().unwrap();
-        for (i, cur_byte) in sprite.iter().enumerate() {
-            // Stop if you've reach the vertical edge.
-            let cur_y = y + (i as u8);
-            if cur_y == (HEIGHT as u8) {
-                break;
-            }
+    // The image is stretched to fill the window, so keep its aspect ratio when
+    // the window is resized.
+    fn window_options(scale: u32) -> WindowOptions {
+        return WindowOptions::new()
+            .set_preserve_aspect_ratio(true)
+            .set_size([WIDTH as u32 * scale, HEIGHT as u32 * scale]);
+    }
 
-            for x_ind in 0..8 {
-                let cur_x = x + x_ind;
-                // Stop if we've reached the edge.
-                if cur_x == (WIDTH as u8) {
-                    break;
-                }
+    pub fn scale(disp: &Arc<Display>) -> u32 {
+        return disp.scale;
+    }
 
-                let bit = (cur_byte >> (7 - x_ind)) & 1;
-                if bit == 0 {
-                    continue;
-                }
+    // With batching enabled, drawing goes to a separate buffer which only shows
+    // up on screen once flush() is called, typically at the end of each frame.
+    pub fn set_batched(disp: &Arc<Display>, batched: bool) {
+        if batched {
+            let frame = disp.buf.lock().unwrap().clone();
+            *disp.shadow.lock().unwrap() = frame;
+        } else {
+            // The selected planes live in the frame too, so copy it back even
+            // if nothing was drawn.
+            disp.shadow_dirty.store(true, Ordering::Relaxed);
+            Display::flush(disp);
+        }
+        disp.batched.store(batched, Ordering::Relaxed);
+    }
 
-                let buf_ind: usize = (WIDTH * cur_y as usize) + cur_x as usize;
-                if buf_unlocked[buf_ind] == ON_PIXEL {
-                    buf_unlocked[buf_ind] = OFF_PIXEL;
-                    vf = 1;
-                } else {
-                    buf_unlocked[buf_ind] = ON_PIXEL;
-                }
-            }
+    // Copy the batched frame over to the rendered one, if it changed.
+    pub fn flush(disp: &Arc<Display>) {
+        if !disp.batched.load(Ordering::Relaxed) || !disp.shadow_dirty.swap(false, Ordering::Relaxed) {
+            return;
         }
 
-        return vf;
-     }
-}
+        let shadow = disp.shadow.lock().unwrap();
+        disp.buf.lock().unwrap().clone_from(&shadow);
+        disp.dirty.store(true, Ordering::Relaxed);
+    }
 
-#[cfg(test)]
-mod tests {
-    use show_image::event::ElementState;
+    // Note that the emulation changed the frame, so that it gets rendered again.
+    fn mark_changed(disp: &Arc<Display>) {
+        if disp.batched.load(Ordering::Relaxed) {
+            disp.shadow_dirty.store(true, Ordering::Relaxed);
+        } else {
+            disp.dirty.store(true, Ordering::Relaxed);
+        }
+    }
+
+    // Whether the window needs updating, clearing the flag.
+    fn take_dirty(disp: &Arc<Display>) -> bool {
+        return disp.dirty.swap(false, Ordering::Relaxed);
+    }
 
-    use super::{Display, WIDTH, HEIGHT, ON_PIXEL, OFF_PIXEL};
+    // The HUD and fading change the image from one frame to the next by themselves.
+    fn animated(disp: &Arc<Display>) -> bool {
+        return disp.hud_font.lock().unwrap().is_some() || disp.fade.lock().unwrap().is_some();
+    }
 
-    #[test]
-    fn check_clear_buf() {
-        let disp_arc = Display::new(true);
-        Display::clear_buf(&disp_arc.buf);
-        for pxl in disp_arc.buf.lock().unwrap().iter() {
-            assert_eq!(*pxl, 0);
+    // The buffer the emulation reads and writes.
+    fn target(disp: &Arc<Display>) -> &CountedMutex<FrameBuffer> {
+        if disp.batched.load(Ordering::Relaxed) {
+            return &disp.shadow;
         }
+
+        return &disp.buf;
     }
 
-    #[test]
-    fn update_buf_sprite_normal() {
-        let disp_arc = Display::new(true);
-        // Use a sprite for the letter "F"
-        let sprite = vec![0xF0, 0x80, 0xF0, 0x80, 0x80];
+    // The default layout maps the 1234/QWER/ASDF/ZXCV block of a US-QWERTY
+    // keyboard onto the CHIP-8 keypad.
+    fn default_key_mapping() -> HashMap<u32, u8> {
+        return HashMap::from([
+            (2, 0x1),
+            (3, 0x2),
+            (4, 0x3),
+            (5, 0xC),
+            (16, 0x4),
+            (17, 0x5),
+            (18, 0x6),
+            (19, 0xD),
+            (30, 0x7),
+            (31, 0x8),
+            (32, 0x9),
+            (33, 0xE),
+            (44, 0xA),
+            (45, 0x0),
+            (46, 0xB),
+            (47, 0xF),
+        ]);
+    }
 
-        let x = 32;
-        let y = 16;
-        let vf = Display::update_buf_sprite(&disp_arc.buf, x, y, &sprite);
-        assert_eq!(vf, 0);
+    // Replace the scancode to CHIP-8 key mapping.
+    pub fn set_key_mapping(disp: &Arc<Display>, map: HashMap<u32, u8>) -> Result<i32, Chip8Error> {
+        if let Some(key) = map.values().find(|key| **key > 0xF) {
+            return Err(Chip8Error::InvalidKey(*key));
+        }
 
-        // Check the buffer pixel values are equal to the sprite.
-        // This is the normal case so we don't care about overflow.
-        for (j, byte) in sprite.iter().enumerate() {
-            let cur_y = y as usize + j;
-            for i in 0..8 {
-                let bit = (byte >> (7 - i)) & 1;
-                let buf_ind: usize = (WIDTH * cur_y) + (x + i) as usize;
-                if bit == 1 {
-                    assert_eq!(disp_arc.buf.lock().unwrap()[buf_ind], ON_PIXEL);
-                } else {
-                    assert_eq!(disp_arc.buf.lock().unwrap()[buf_ind], OFF_PIXEL);
-                }
-            }
+        *disp.key_mapping.lock().unwrap() = map;
+        return Ok(0);
+    }
+
+    fn scancode_to_key(disp: &Arc<Display>, scancode: u32) -> Result<u8, Chip8Error> {
+        match disp.key_mapping.lock().unwrap().get(&scancode) {
+            Some(key) => return Ok(*key),
+            None => return Err(Chip8Error::UnknownScancode(scancode)),
         }
     }
 
-    #[test]
-    // Test the sprite doesn't wrap around.
-    fn update_buf_edge() {
-        let disp_arc = Display::new(true);
-        // Use a sprite for the letter "F"
-        let sprite = vec![0xF0, 0x80, 0xF0, 0x80, 0x80];
+    fn set_key_state(disp: &Arc<Display>, scan_code: u32, state: ElementState) -> Result<i32, Chip8Error> {
+        let key_code = Display::scancode_to_key(disp, scan_code)?;
 
-        let x = 60;
-        let y = 29;
-        let vf = Display::update_buf_sprite(&disp_arc.buf, x, y, &sprite);
-        assert_eq!(vf, 0);
+        match state {
+            ElementState::Pressed => return Display::press_key(disp, key_code),
+            ElementState::Released => return Display::release_key(disp, key_code),
+        }
+    }
 
-        // First check that the edge *is* filled
-        for (j, byte) in sprite[..(HEIGHT-y as usize)].iter().enumerate() {
-            let cur_y = y as usize + j;
-            for i in 0..(WIDTH-x as usize) {
-                let bit = (byte >> (7 - i)) & 1;
-                let buf_ind: usize = (WIDTH * cur_y) + (x as usize + i);
-                if bit == 1 {
-                    assert_eq!(disp_arc.buf.lock().unwrap()[buf_ind], ON_PIXEL);
-                } else {
-                    assert_eq!(disp_arc.buf.lock().unwrap()[buf_ind], OFF_PIXEL);
-                }
-            }
+    // Press a CHIP-8 key directly, for when input doesn't come from the window.
+    pub fn press_key(disp: &Arc<Display>, key: u8) -> Result<i32, Chip8Error> {
+        if key > 0xF {
+            return Err(Chip8Error::InvalidKey(key));
         }
 
-        // Now check that the rest i.e the wrapped around part isn't drawn.
-        for (j, _) in sprite[(HEIGHT-y as usize)..].iter().enumerate() {
-            let cur_y = y as usize + j;
-            for i in (WIDTH-x as usize)..8 {
-                let buf_ind: usize = (WIDTH * cur_y) + (x as usize + i);
-                assert_eq!(disp_arc.buf.lock().unwrap()[buf_ind], OFF_PIXEL);
-            }
+        // Held keys repeat their press, only the first one is an event.
+        if disp.keys_state.lock().unwrap().insert(key, true) != Some(true) {
+            Display::push_key_event(disp, key, true);
+        }
+        return Ok(0);
+    }
+
+    pub fn release_key(disp: &Arc<Display>, key: u8) -> Result<i32, Chip8Error> {
+        if key > 0xF {
+            return Err(Chip8Error::InvalidKey(key));
         }
 
+        if disp.keys_state.lock().unwrap().insert(key, false) == Some(true) {
+            Display::push_key_event(disp, key, false);
+        }
+        return Ok(0);
     }
 
-    #[test]
-    // Case where already on pixels are switched off by the sprite.
-    fn update_buf_sprite_vf_check() {
-        let disp_arc = Display::new(true);
-        // Use a sprite for the letter "F"
-        let sprite = vec![0xF0, 0x80, 0xF0, 0x80, 0x80];
+    fn push_key_event(disp: &Arc<Display>, key: u8, pressed: bool) {
+        let mut key_events = disp.key_events.lock().unwrap();
+        if key_events.len() >= KEY_EVENT_QUEUE_LEN {
+            key_events.pop_front();
+        }
+        key_events.push_back((key, pressed));
+    }
 
-        let x = 32;
-        let y = 16;
+    // Returns the oldest key press or release which hasn't been polled yet.
+    pub fn poll_key_event(disp: &Arc<Display>) -> Option<(u8, bool)> {
+        return disp.key_events.lock().unwrap().pop_front();
+    }
 
-        // Set the display buffer as if the sprite has already been drawn.
-        for (j, byte) in sprite.iter().enumerate() {
-            let cur_y = y as usize + j;
-            for i in 0..8 {
-                let bit = (byte >> (7 - i)) & 1;
-                let buf_ind: usize = (WIDTH * cur_y) + (x + i) as usize;
-                disp_arc.buf.lock().unwrap()[buf_ind] = if bit == 1 { ON_PIXEL } else { OFF_PIXEL };
+    pub fn get_key_state(disp: &Arc<Display>, key: u8) -> Result<bool, Chip8Error> {
+        if key > 0xF {
+            return Err(Chip8Error::InvalidKey(key));
+        } else {
+            let keys_state = disp.keys_state.lock().unwrap();
+            match keys_state.get(&key) {
+                Some(val) => return Ok(*val),
+                None => return Ok(false),
             }
         }
+    }
 
-        let vf = Display::update_buf_sprite(&disp_arc.buf, x, y, &sprite);
-        assert_eq!(vf, 1);
+    // All the keys which are currently held down, in ascending order.
+    pub fn pressed_keys(disp: &Arc<Display>) -> Vec<u8> {
+        let mut keys: Vec<u8> = disp.keys_state.lock().unwrap().iter()
+            .filter(|(_, pressed)| **pressed)
+            .map(|(key, _)| *key)
+            .collect();
+        keys.sort();
+        return keys;
+    }
 
-        // All the pixels should be switched off.
-        for (j, _) in sprite.iter().enumerate() {
-            let cur_y = y as usize + j;
-            for _ in 0..8 {
-                let buf_ind: usize = (WIDTH * cur_y) + (x + 1) as usize;
-                assert_eq!(disp_arc.buf.lock().unwrap()[buf_ind], OFF_PIXEL)
+    // Drains the key event queue, returning the mask of keys released since
+    // the last call.
+    pub fn take_released_keys(disp: &Arc<Display>) -> u16 {
+        let mut released: u16 = 0;
+        while let Some((key, pressed)) = Display::poll_key_event(disp) {
+            if !pressed {
+                released |= 1 << key;
             }
         }
+        return released;
     }
 
-    #[test]
-    fn key_state() {
-        let disp_arc = Display::new(true);
+    pub fn is_paused(disp: &Arc<Display>) -> bool {
+        return *disp.paused.lock().unwrap();
+    }
 
-        // Press a key.
-        assert!(Display::set_key_state(&disp_arc, 2, ElementState::Pressed).is_ok());
-        assert_eq!(Display::get_key_state(&disp_arc, 1).unwrap(), true);
+    pub fn toggle_pause(disp: &Arc<Display>) {
+        let mut paused = disp.paused.lock().unwrap();
+        *paused = !*paused;
+    }
 
-        // Gets pressed again.
-        assert!(Display::set_key_state(&disp_arc, 2, ElementState::Pressed).is_ok());
-        assert_eq!(Display::get_key_state(&disp_arc, 1).unwrap(), true);
+    pub fn is_muted(disp: &Arc<Display>) -> bool {
+        return *disp.muted.lock().unwrap();
+    }
 
-        // Release the key.
-        assert!(Display::set_key_state(&disp_arc, 2, ElementState::Released).is_ok());
-        assert_eq!(Display::get_key_state(&disp_arc, 1).unwrap(), false);
+    pub fn set_muted(disp: &Arc<Display>, muted: bool) {
+        *disp.muted.lock().unwrap() = muted;
+    }
 
-        // Press a invalid key
-        assert!(Display::set_key_state(&disp_arc, 93, ElementState::Pressed).is_err());
+    // Whether the turbo key is currently held down.
+    pub fn turbo_active(disp: &Arc<Display>) -> bool {
+        return *disp.turbo.lock().unwrap();
+    }
 
-        // Press two keys consecutively without releasing them, then make sure the first
-        // one is still reporting as pressed.
-        assert!(Display::set_key_state(&disp_arc, 2, ElementState::Pressed).is_ok());
-        assert!(Display::set_key_state(&disp_arc, 3, ElementState::Pressed).is_ok());
-        assert_eq!(Display::get_key_state(&disp_arc, 2).unwrap(), true);
+    // Returns whether a reset was requested since the last call.
+    pub fn take_reset_request(disp: &Arc<Display>) -> bool {
+        let mut reset_requested = disp.reset_requested.lock().unwrap();
+        let val = *reset_requested;
+        *reset_requested = false;
+        return val;
+    }
+
+    // Ask the main loop to stop, so that it exits the same way as when the
+    // program finishes.
+    pub fn request_quit(disp: &Arc<Display>) {
+        disp.quit_requested.store(true, Ordering::Relaxed);
+    }
+
+    pub fn quit_requested(disp: &Arc<Display>) -> bool {
+        return disp.quit_requested.load(Ordering::Relaxed);
+    }
+
+    // Returns whether a memory dump was requested since the last call.
+    pub fn take_dump_request(disp: &Arc<Display>) -> bool {
+        return std::mem::take(&mut *disp.dump_requested.lock().unwrap());
+    }
+
+    // Returns the index of the program requested since the last call, if any.
+    pub fn take_rom_request(disp: &Arc<Display>) -> Option<usize> {
+        return disp.rom_requested.lock().unwrap().take();
+    }
+
+    // Returns the screen to its power-on state, cleared in low resolution with
+    // only the first plane selected. Sprite wrapping and collision counting are
+    // user settings, so they're kept.
+    pub fn reset(disp: &Arc<Display>) {
+        *Display::target(disp).lock().unwrap() = FrameBuffer::new(WIDTH, HEIGHT);
+        Display::mark_changed(disp);
+    }
+
+    // Handles a keyboard event, for either one of the emulator's own keys or the keypad.
+    fn handle_key(disp: &Arc<Display>, scan_code: u32, state: ElementState) -> Result<i32, Chip8Error> {
+        match scan_code {
+            SCREENSHOT_SCANCODE => if state == ElementState::Pressed {
+                Display::save_screenshot(disp);
+            },
+            PAUSE_SCANCODE => if state == ElementState::Pressed {
+                Display::toggle_pause(disp);
+            },
+            MUTE_SCANCODE => if state == ElementState::Pressed {
+                let mut muted = disp.muted.lock().unwrap();
+                *muted = !*muted;
+            },
+            TURBO_SCANCODE => *disp.turbo.lock().unwrap() = state == ElementState::Pressed,
+            RESET_SCANCODE => if state == ElementState::Pressed {
+                *disp.reset_requested.lock().unwrap() = true;
+            },
+            DUMP_SCANCODE => if state == ElementState::Pressed {
+                *disp.dump_requested.lock().unwrap() = true;
+            },
+            _ => { Display::set_key_state(disp, scan_code, state)?; },
+        }
+
+        return Ok(0);
+    }
+
+    // Handles Ctrl+number, which switches programs. Returns whether the key was
+    // used up, in which case it doesn't reach the keypad.
+    fn handle_rom_key(disp: &Arc<Display>, scan_code: u32, state: ElementState, ctrl: bool) -> bool {
+        if !ctrl || !(FIRST_ROM_SCANCODE..=LAST_ROM_SCANCODE).contains(&scan_code) {
+            return false;
+        }
+
+        if state == ElementState::Pressed {
+            *disp.rom_requested.lock().unwrap() = Some((scan_code - FIRST_ROM_SCANCODE) as usize);
+        }
+        return true;
+    }
+
+    fn handle_window_event(disp: &Arc<Display>, wevent: WindowEvent) {
+        match wevent {
+            WindowEvent::KeyboardInput(kb_input) => {
+                let input = kb_input.input;
+                if Display::handle_rom_key(disp, input.scan_code, input.state, input.modifiers.ctrl()) {
+                    return;
+                }
+                if let Err(e) = Display::handle_key(disp, kb_input.input.scan_code, kb_input.input.state) {
+                    eprintln!("Set key state failed: {}", e);
+                }
+            },
+            // Let the main loop stop, so it still saves and cleans up on the way out.
+            WindowEvent::CloseRequested(_) => Display::request_quit(disp),
+            _ => {},
+        }
+    }
+
+    fn handle_window_events(disp: &Arc<Display>, window: &mut WindowProxy) {
+        if let Ok(event) = window.event_channel() {
+            if let Ok(wevent) = event.recv_timeout(Duration::from_micros(THREAD_LOOP_SLEEP_US)) {
+                Display::handle_window_event(disp, wevent);
+            }
+        }
+    }
+
+    // Send the frame to the window, unless it's static and has already been sent.
+    fn render(disp: &Arc<Display>) {
+        let window_mutex = match &disp.window {
+            Some(window_mutex) => window_mutex,
+            None => return,
+        };
+        // A window that's been closed can't be drawn to at all.
+        if Display::quit_requested(disp) || !(Display::take_dirty(disp) || Display::animated(disp)) {
+            return;
+        }
+
+        if let Ok(window) = window_mutex.lock() {
+            let (width, height) = Display::shown_resolution(disp);
+            if let Err(err) = window.set_image("image", ImageView::new(
+                ImageInfo::rgb8(width as u32, height as u32),
+                &Display::rgb_pixels(disp),
+            )) {
+                eprintln!("Failed to set image: {}", err);
+            }
+        }
+    }
+
+    fn thread_loop(disp: Arc<Display>, clock: Arc<dyn Clock>) {
+        let mut frame_times: VecDeque<Duration> = VecDeque::with_capacity(FPS_WINDOW);
+        let mut last_render = clock.now();
+        let mut next_render = last_render + RENDER_PERIOD;
+        while !disp.shutdown.load(Ordering::Relaxed) {
+            let now = clock.now();
+            if now >= next_render {
+                // Every render tick counts towards the FPS, so that a static
+                // screen still shows how fast the display is running.
+                frame_times.push_back(now - last_render);
+                if frame_times.len() > FPS_WINDOW {
+                    frame_times.pop_front();
+                }
+                disp.fps.store(Display::average_fps(&frame_times).to_bits(), Ordering::Relaxed);
+                last_render = now;
+                // If we've fallen behind, don't try to catch up.
+                next_render += RENDER_PERIOD;
+                if next_render <= now {
+                    next_render = now + RENDER_PERIOD;
+                }
+
+                Display::render(&disp);
+            }
+
+            if let Some(window_mutex) = &disp.window {
+                if let Ok(mut window) = window_mutex.lock() {
+                    Display::handle_window_events(&disp, &mut window);
+                }
+            } else {
+                // Without a window there are no events to wait on.
+                clock.sleep(next_render.saturating_duration_since(now));
+            }
+        }
+    }
+
+    // Pixels that get switched off fade out over several frames instead, which
+    // reduces the flicker from sprites being erased and redrawn.
+    pub fn set_fade(disp: &Arc<Display>, fade: bool) {
+        let frame = Display::frame_count(disp);
+        *disp.fade.lock().unwrap() = if fade { Some((Vec::new(), frame)) } else { None };
+        disp.dirty.store(true, Ordering::Relaxed);
+    }
+
+    // Returns the shade of each pixel to be rendered, applying the fade if enabled.
+    fn shades(disp: &Arc<Display>) -> Vec<u8> {
+        let target = disp.buf.lock().unwrap().composed_pixels();
+        let frame = Display::frame_count(disp);
+        let mut fade = disp.fade.lock().unwrap();
+        match &mut *fade {
+            Some((intensity, faded_frame)) => {
+                // Start over when the resolution changes.
+                if intensity.len() != target.len() {
+                    *intensity = target.clone();
+                }
+                // Dim by a step for each 60Hz frame since the last render, so the
+                // fade takes as long however often we're rendered.
+                let frames = std::cmp::min(frame.saturating_sub(*faded_frame), u8::MAX as u64) as u8;
+                *faded_frame = frame;
+                Display::decay(intensity, &target, FADE_STEP.saturating_mul(frames));
+                return intensity.clone();
+            },
+            None => return target,
+        }
+    }
+
+    // Move each pixel's intensity towards its target, brightening immediately but
+    // dimming by at most |step|.
+    fn decay(intensity: &mut [u8], target: &[u8], step: u8) {
+        for (pxl, target) in intensity.iter_mut().zip(target.iter()) {
+            if *target >= *pxl {
+                *pxl = *target;
+            } else {
+                *pxl = std::cmp::max(*target, pxl.saturating_sub(step));
+            }
+        }
+    }
+
+    // Overlay the FPS and the currently pressed keys in the top left corner,
+    // drawn using the 0-F glyphs in |font|. Passing None disables the HUD.
+    pub fn set_hud(disp: &Arc<Display>, font: Option<Vec<u8>>) {
+        *disp.hud_font.lock().unwrap() = font;
+        disp.dirty.store(true, Ordering::Relaxed);
+    }
+
+    // Rolling average of the rate the display is being rendered at.
+    pub fn fps(disp: &Arc<Display>) -> f32 {
+        return f32::from_bits(disp.fps.load(Ordering::Relaxed));
+    }
+
+    // Frames per second given the durations of the last few frames.
+    fn average_fps(frame_times: &VecDeque<Duration>) -> f32 {
+        let total: Duration = frame_times.iter().sum();
+        if total.is_zero() {
+            return 0.0;
+        }
+
+        return frame_times.len() as f32 / total.as_secs_f32();
+    }
+
+    // Draw the HUD on top of the rendered |pixels|. The frame buffer itself is
+    // left alone, so that the HUD doesn't affect sprite collisions.
+    fn draw_hud(disp: &Arc<Display>, pixels: &mut [u8]) {
+        let font = disp.hud_font.lock().unwrap();
+        let font = match &*font {
+            Some(font) => font,
+            None => return,
+        };
+
+        let (width, height) = Display::shown_resolution(disp);
+        // The resolution changed since the pixels were rendered.
+        if pixels.len() != width * height {
+            return;
+        }
+
+        let fps = format!("{}", Display::fps(disp).round() as u64);
+        let keys: String = Display::pressed_keys(disp).iter().map(|key| format!("{:X}", key)).collect();
+
+        Display::blit_text(pixels, (width, height), 0, 0, &fps, font);
+        Display::blit_text(pixels, (width, height), 0, GLYPH_HEIGHT + 1, &keys, font);
+    }
+
+    // Write |text| into |pixels| with its top left corner at (x, y), using the
+    // 0-F glyphs in |font|. Characters without a glyph are left blank, and
+    // anything past the edges of the screen is clipped.
+    fn blit_text(pixels: &mut [u8], (width, height): (usize, usize), x: usize, y: usize, text: &str,
+                 font: &[u8]) {
+        for (ind, c) in text.chars().enumerate() {
+            let glyph = match c.to_digit(16) {
+                Some(digit) => font.get(digit as usize * GLYPH_HEIGHT..(digit as usize + 1) * GLYPH_HEIGHT),
+                None => None,
+            };
+            let glyph = match glyph {
+                Some(glyph) => glyph,
+                None => continue,
+            };
+
+            for (row, bits) in glyph.iter().enumerate() {
+                for col in 0..GLYPH_WIDTH {
+                    let (px, py) = (x + ind * GLYPH_ADVANCE + col, y + row);
+                    if px >= width || py >= height {
+                        continue;
+                    }
+                    if (bits >> (7 - col)) & 0x1 == 0x1 {
+                        pixels[py * width + px] = ON_PIXEL;
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn set_palette(disp: &Arc<Display>, fg: Rgb, bg: Rgb) {
+        *disp.palette.lock().unwrap() = (fg, bg);
+        disp.dirty.store(true, Ordering::Relaxed);
+    }
+
+    // Render a lit background with dark sprites. Only the rendered image is
+    // inverted, the frame buffer and so collisions are unaffected.
+    pub fn set_inverted(disp: &Arc<Display>, inverted: bool) {
+        *disp.inverted.lock().unwrap() = inverted;
+        disp.dirty.store(true, Ordering::Relaxed);
+    }
+
+    // Expand the display contents into an rgb8 buffer using the palette. Shades
+    // in between on and off (used by the XO-CHIP planes) are blended between the
+    // background and foreground colors.
+    fn rgb_pixels(disp: &Arc<Display>) -> Vec<u8> {
+        let (fg, bg) = *disp.palette.lock().unwrap();
+        let mut shades = Display::shades(disp);
+        Display::draw_hud(disp, &mut shades);
+        if *disp.inverted.lock().unwrap() {
+            for shade in shades.iter_mut() {
+                *shade = ON_PIXEL - *shade;
+            }
+        }
+        let mut rgb: Vec<u8> = Vec::with_capacity(shades.len() * 3);
+        for shade in shades {
+            for (fg_chan, bg_chan) in fg.iter().zip(bg.iter()) {
+                let diff = *fg_chan as i32 - *bg_chan as i32;
+                rgb.push((*bg_chan as i32 + diff * shade as i32 / ON_PIXEL as i32) as u8);
+            }
+        }
+
+        return rgb;
+    }
+
+    // Returns a copy of the current frame, one byte per pixel in the active
+    // resolution, with both XO-CHIP planes combined.
+    pub fn snapshot_buffer(disp: &Arc<Display>) -> Vec<u8> {
+        return Display::target(disp).lock().unwrap().composed_pixels();
+    }
+
+    // Whether each pixel of the active resolution is lit, in either plane. Row
+    // major, like the rest of the frame accessors.
+    pub fn pixels(disp: &Arc<Display>) -> Vec<bool> {
+        return Display::target(disp).lock().unwrap().lit_pixels();
+    }
+
+    // Render the frame into a |width| x |height| image, one byte per pixel,
+    // without going through the window. The frame is stretched to fit with
+    // nearest neighbour sampling, so passing the active resolution returns it as is.
+    pub fn render_to(disp: &Arc<Display>, width: usize, height: usize) -> Vec<u8> {
+        let buf = Display::target(disp).lock().unwrap();
+        let pixels = buf.composed_pixels();
+        let mut image: Vec<u8> = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                image.push(pixels[(y * buf.height / height) * buf.width + x * buf.width / width]);
+            }
+        }
+
+        return image;
+    }
+
+    // Encodes a frame as a binary PGM image.
+    pub fn encode_pgm(pixels: &[u8], width: usize, height: usize) -> Vec<u8> {
+        let mut pgm = format!("P5\n{} {}\n255\n", width, height).into_bytes();
+        pgm.extend_from_slice(pixels);
+        return pgm;
+    }
+
+    pub fn set_screenshot_path(disp: &Arc<Display>, path: Option<String>) {
+        *disp.screenshot_path.lock().unwrap() = path;
+    }
+
+    fn save_screenshot(disp: &Arc<Display>) {
+        if let Some(path) = &*disp.screenshot_path.lock().unwrap() {
+            // Save what's on screen, rather than any batched drawing.
+            let buf = disp.buf.lock().unwrap();
+            let pgm = Display::encode_pgm(&buf.composed_pixels(), buf.width, buf.height);
+            match std::fs::write(path, pgm) {
+                Ok(_) => println!("Saved screenshot to {}", path),
+                Err(e) => eprintln!("Failed to save screenshot: {}", e),
+            }
+        }
+    }
+
+    pub fn clear(disp: &Arc<Display>) {
+        Display::clear_buf(Display::target(disp));
+        Display::mark_changed(disp);
+        if let Some(callback) = disp.clear_callback.lock().unwrap().as_ref() {
+            callback();
+        }
+    }
+
+    // Registers |callback| to be run after every clear, replacing any previous one.
+    // It runs on the emulation thread, and mustn't register a callback itself.
+    pub fn on_clear(disp: &Arc<Display>, callback: Box<dyn Fn() + Send>) {
+        *disp.clear_callback.lock().unwrap() = Some(callback);
+    }
+
+    // Only the selected planes are cleared.
+    fn clear_buf(buf: &CountedMutex<FrameBuffer>) {
+        let mut buf_unlocked = buf.lock().unwrap();
+        for plane in buf_unlocked.selected_planes() {
+            for pxl in buf_unlocked.plane_mut(plane).iter_mut() {
+                *pxl = 0;
+            }
+        }
+    }
+
+    // XO-CHIP FN01 instruction, selects the planes subsequent drawing operations act on.
+    pub fn set_planes(disp: &Arc<Display>, planes: u8) -> Result<i32, Chip8Error> {
+        if planes > 0x3 {
+            return Err(Chip8Error::InvalidPlanes(planes));
+        }
+
+        Display::target(disp).lock().unwrap().planes = planes;
+        return Ok(0);
+    }
+
+    // Sprites are clipped at the screen edges by default, some programs expect
+    // them to wrap around instead.
+    pub fn set_wrap_sprites(disp: &Arc<Display>, wrap: bool) {
+        Display::set_wrap(disp, wrap, wrap);
+    }
+
+    // Some interpreters wrap sprites around one pair of edges and clip them at the other.
+    pub fn set_wrap(disp: &Arc<Display>, wrap_x: bool, wrap_y: bool) {
+        disp.wrap_x.store(wrap_x, Ordering::Relaxed);
+        disp.wrap_y.store(wrap_y, Ordering::Relaxed);
+    }
+
+    // Returns whether sprites wrap around the (horizontal, vertical) edges.
+    fn wrap(disp: &Arc<Display>) -> (bool, bool) {
+        return (disp.wrap_x.load(Ordering::Relaxed), disp.wrap_y.load(Ordering::Relaxed));
+    }
+
+    // XO-CHIP style collisions, where a draw sets VF to the number of sprite
+    // rows which switched a pixel off instead of 1.
+    pub fn set_count_collision_rows(disp: &Arc<Display>, count: bool) {
+        disp.count_collision_rows.store(count, Ordering::Relaxed);
+    }
+
+    // Number of selected planes, which determines how many bytes a sprite spans.
+    pub fn plane_count(disp: &Arc<Display>) -> usize {
+        return Display::target(disp).lock().unwrap().selected_planes().len();
+    }
+
+    // Switch between the 64x32 and the SUPER-CHIP 128x64 resolutions.
+    // The display contents are cleared on a switch.
+    pub fn set_hires(disp: &Arc<Display>, hires: bool) {
+        Display::set_hires_buf(Display::target(disp), hires);
+        Display::mark_changed(disp);
+    }
+
+    fn set_hires_buf(buf: &CountedMutex<FrameBuffer>, hires: bool) {
+        let mut buf_unlocked = buf.lock().unwrap();
+        let planes = buf_unlocked.planes;
+        *buf_unlocked = if hires {
+            FrameBuffer::new(HIRES_WIDTH, HIRES_HEIGHT)
+        } else {
+            FrameBuffer::new(WIDTH, HEIGHT)
+        };
+        buf_unlocked.planes = planes;
+    }
+
+    // SUPER-CHIP scroll instructions. Vacated rows/columns are filled with off pixels.
+    pub fn scroll_down(disp: &Arc<Display>, n: usize) {
+        Display::scroll_buf(Display::target(disp), 0, n as isize);
+        Display::mark_changed(disp);
+    }
+
+    pub fn scroll_left(disp: &Arc<Display>) {
+        Display::scroll_buf(Display::target(disp), -4, 0);
+        Display::mark_changed(disp);
+    }
+
+    pub fn scroll_right(disp: &Arc<Display>) {
+        Display::scroll_buf(Display::target(disp), 4, 0);
+        Display::mark_changed(disp);
+    }
+
+    // Shift the buffer contents by |dx| columns and |dy| rows.
+    fn scroll_buf(buf: &CountedMutex<FrameBuffer>, dx: isize, dy: isize) {
+        let mut buf_unlocked = buf.lock().unwrap();
+        let width = buf_unlocked.width as isize;
+        let height = buf_unlocked.height as isize;
+
+        for plane in buf_unlocked.selected_planes() {
+            let pixels = buf_unlocked.plane_mut(plane);
+            let mut scrolled = vec![OFF_PIXEL; pixels.len()];
+            for y in 0..height {
+                for x in 0..width {
+                    let src_x = x - dx;
+                    let src_y = y - dy;
+                    if src_x < 0 || src_x >= width || src_y < 0 || src_y >= height {
+                        continue;
+                    }
+
+                    scrolled[(width * y + x) as usize] = pixels[(width * src_y + src_x) as usize];
+                }
+            }
+
+            *pixels = scrolled;
+        }
+    }
+
+    // Called at the start of each emulation frame, which stands in for the
+    // vertical blank of the original hardware.
+    pub fn tick_frame(disp: &Arc<Display>) {
+        *disp.frame.lock().unwrap() += 1;
+    }
+
+    pub fn frame_count(disp: &Arc<Display>) -> u64 {
+        return *disp.frame.lock().unwrap();
+    }
+
+    // Returns the (width, height) of the active resolution.
+    pub fn resolution(disp: &Arc<Display>) -> (usize, usize) {
+        let buf = Display::target(disp).lock().unwrap();
+        return (buf.width, buf.height);
+    }
+
+    // Resolution of the frame being rendered, which may lag behind when batching.
+    fn shown_resolution(disp: &Arc<Display>) -> (usize, usize) {
+        let buf = disp.buf.lock().unwrap();
+        return (buf.width, buf.height);
+    }
+
+    // Returns the new value of VF, which is non-zero if there was a collision.
+    pub fn draw(disp: &Arc<Display>, x: u8, y: u8, sprite: &[u8], sprite_width: u8) -> u8 {
+        return Display::draw_detailed(disp, x, y, sprite, sprite_width).vf;
+    }
+
+    // Same as draw(), but with the details of what got drawn.
+    pub fn draw_detailed(disp: &Arc<Display>, x: u8, y: u8, sprite: &[u8], sprite_width: u8) -> DrawResult {
+        let result = Display::update_buf_sprite_detailed(Display::target(disp), x, y, sprite, sprite_width,
+                                                         Display::wrap(disp),
+                                                         disp.count_collision_rows.load(Ordering::Relaxed));
+        if result.pixels_flipped > 0 {
+            Display::mark_changed(disp);
+        }
+        if result.vf > 0 {
+            disp.collisions.fetch_add(1, Ordering::Relaxed);
+        }
+
+        return result;
+    }
+
+    // Number of draws so far which had a collision.
+    pub fn collision_count(disp: &Arc<Display>) -> u64 {
+        return disp.collisions.load(Ordering::Relaxed);
+    }
+
+    // Same as update_buf_sprite_detailed() on the rendered frame, with the
+    // display's settings, returning just the value of VF.
+    #[cfg(test)]
+    fn update_buf_sprite(disp: &Arc<Display>, x: u8, y:u8, sprite: &[u8], sprite_width: u8) -> u8 {
+        return Display::update_buf_sprite_detailed(&disp.buf, x, y, sprite, sprite_width, Display::wrap(disp),
+                                                   disp.count_collision_rows.load(Ordering::Relaxed)).vf;
+    }
+
+    // Performs the draw of the sprite, and returns what the eventual
+    // value of F register should be along with what the draw changed.
+    // Sprites are normally 8 pixels wide, but SUPER-CHIP 16x16 sprites
+    // use 2 bytes per row, so |sprite_width| must be a multiple of 8.
+    // When both XO-CHIP planes are selected, the first half of |sprite| is drawn
+    // to the first plane and the second half to the second plane.
+    // VF is 1 if any pixel got switched off, or when counting collision rows, the
+    // number of rows in which one did (a row colliding in both planes counts once).
+    // |wrap| and |count_collision_rows| are the display's settings for these quirks.
+    fn update_buf_sprite_detailed(buf: &CountedMutex<FrameBuffer>, x: u8, y:u8, sprite: &[u8],
+                                  sprite_width: u8, wrap: (bool, bool), count_collision_rows: bool) -> DrawResult {
+        let mut result = DrawResult::default();
+        let mut collided_rows: u32 = 0;
+        let mut buf_unlocked = buf.lock().unwrap();
+        let width = buf_unlocked.width;
+        let height = buf_unlocked.height;
+        let planes = buf_unlocked.selected_planes();
+        if planes.is_empty() {
+            return result;
+        }
+
+        let plane_len = sprite.len() / planes.len();
+        for (ind, plane) in planes.iter().enumerate() {
+            let plane_sprite = &sprite[(ind * plane_len)..((ind + 1) * plane_len)];
+            let plane_draw = Display::xor_sprite(buf_unlocked.plane_mut(*plane), (width, height), x, y,
+                                                 plane_sprite, sprite_width, wrap);
+            collided_rows |= plane_draw.collided_rows;
+            result.pixels_flipped += plane_draw.pixels_flipped;
+            result.rows_drawn = std::cmp::max(result.rows_drawn, plane_draw.rows_drawn);
+        }
+
+        result.vf = if count_collision_rows {
+            u8::try_from(collided_rows.count_ones()).unwrap_or(u8::MAX)
+        } else {
+            (collided_rows != 0) as u8
+        };
+        return result;
+    }
+
+    // XOR the sprite into a single plane. Sprites are at most 16 rows high, so
+    // the rows which collided fit in the mask.
+    fn xor_sprite(pixels: &mut [u8], (width, height): (usize, usize), x: u8, y: u8, sprite: &[u8],
+                  sprite_width: u8, (wrap_x, wrap_y): (bool, bool)) -> PlaneDraw {
+        let mut result = PlaneDraw::default();
+        let bytes_per_row = (sprite_width / 8) as usize;
+        for (i, row) in sprite.chunks(bytes_per_row).enumerate() {
+            // Stop if you've reach the vertical edge, unless wrapping around.
+            let cur_y = y as usize + i;
+            if cur_y >= height && !wrap_y {
+                break;
+            }
+            let cur_y = cur_y % height;
+            result.rows_drawn += 1;
+
+            // Combine the row's bytes, with the MSB being the leftmost pixel.
+            let row_bits = row.iter().fold(0u16, |acc, byte| (acc << 8) | *byte as u16);
+            for x_ind in 0..sprite_width {
+                let cur_x = x as usize + x_ind as usize;
+                // Stop if we've reached the edge.
+                if cur_x >= width && !wrap_x {
+                    break;
+                }
+                let cur_x = cur_x % width;
+
+                let bit = (row_bits >> (sprite_width - 1 - x_ind)) & 1;
+                if bit == 0 {
+                    continue;
+                }
+
+                let buf_ind: usize = (width * cur_y) + cur_x;
+                result.pixels_flipped += 1;
+                if pixels[buf_ind] == ON_PIXEL {
+                    pixels[buf_ind] = OFF_PIXEL;
+                    result.collided_rows |= 1 << i;
+                } else {
+                    pixels[buf_ind] = ON_PIXEL;
+                }
+            }
+        }
+
+        return result;
+     }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::{HashMap, VecDeque}, sync::{Arc, atomic::{AtomicBool, AtomicU64, Ordering}},
+              time::Duration};
+
+    use show_image::{WindowId, event::{ElementState, WindowCloseRequestedEvent, WindowEvent}};
+
+    use super::{Display, WIDTH, HEIGHT, HIRES_WIDTH, HIRES_HEIGHT, ON_PIXEL, OFF_PIXEL, PLANE2_PIXEL,
+        BOTH_PLANES_PIXEL, FADE_STEP, DEFAULT_SCALE, GLYPH_WIDTH, GLYPH_HEIGHT, GLYPH_ADVANCE, FIRST_ROM_SCANCODE,
+        LAST_ROM_SCANCODE, KEY_EVENT_QUEUE_LEN, FPS_WINDOW, RENDER_PERIOD, Chip8Error, DrawResult, PlaneDraw};
+    use crate::timer::timer::FakeClock;
+
+    #[test]
+    fn scale() {
+        assert_eq!(Display::scale(&Display::new(true)), DEFAULT_SCALE);
+        assert_eq!(Display::scale(&Display::with_scale(true, 4)), 4);
+
+        let options = Display::window_options(4);
+        assert_eq!(options.size, Some([256, 128]));
+        assert!(options.preserve_aspect_ratio);
+    }
+
+    #[test]
+    fn on_clear() {
+        let disp_arc = Display::new(true);
+        let cleared = Arc::new(AtomicBool::new(false));
+        let cleared_clone = Arc::clone(&cleared);
+        Display::on_clear(&disp_arc, Box::new(move || cleared_clone.store(true, Ordering::Relaxed)));
+
+        Display::draw(&disp_arc, 0, 0, &[0x80], 8);
+        assert!(!cleared.load(Ordering::Relaxed));
+        Display::clear(&disp_arc);
+        assert!(cleared.load(Ordering::Relaxed));
+        assert!(Display::pixels(&disp_arc).iter().all(|pxl| !pxl));
+
+        // A new callback replaces the old one.
+        let count = Arc::new(AtomicU64::new(0));
+        let count_clone = Arc::clone(&count);
+        Display::on_clear(&disp_arc, Box::new(move || { count_clone.fetch_add(1, Ordering::Relaxed); }));
+        cleared.store(false, Ordering::Relaxed);
+        Display::clear(&disp_arc);
+        Display::clear(&disp_arc);
+        assert!(!cleared.load(Ordering::Relaxed));
+        assert_eq!(count.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn check_clear_buf() {
+        let disp_arc = Display::new(true);
+        Display::clear_buf(&disp_arc.buf);
+        for pxl in disp_arc.buf.lock().unwrap().pixels.iter() {
+            assert_eq!(*pxl, 0);
+        }
+    }
+
+    #[test]
+    fn update_buf_sprite_normal() {
+        let disp_arc = Display::new(true);
+        // Use a sprite for the letter "F"
+        let sprite = vec![0xF0, 0x80, 0xF0, 0x80, 0x80];
+
+        let x = 32;
+        let y = 16;
+        let vf = Display::update_buf_sprite(&disp_arc, x, y, &sprite, 8);
+        assert_eq!(vf, 0);
+
+        // Check the buffer pixel values are equal to the sprite.
+        // This is the normal case so we don't care about overflow.
+        for (j, byte) in sprite.iter().enumerate() {
+            let cur_y = y as usize + j;
+            for i in 0..8 {
+                let bit = (byte >> (7 - i)) & 1;
+                let buf_ind: usize = (WIDTH * cur_y) + (x + i) as usize;
+                if bit == 1 {
+                    assert_eq!(disp_arc.buf.lock().unwrap().pixels[buf_ind], ON_PIXEL);
+                } else {
+                    assert_eq!(disp_arc.buf.lock().unwrap().pixels[buf_ind], OFF_PIXEL);
+                }
+            }
+        }
+    }
+
+    #[test]
+    // Test the sprite doesn't wrap around.
+    fn update_buf_edge() {
+        let disp_arc = Display::new(true);
+        // Use a sprite for the letter "F"
+        let sprite = vec![0xF0, 0x80, 0xF0, 0x80, 0x80];
+
+        let x = 60;
+        let y = 29;
+        let vf = Display::update_buf_sprite(&disp_arc, x, y, &sprite, 8);
+        assert_eq!(vf, 0);
+
+        // First check that the edge *is* filled
+        for (j, byte) in sprite[..(HEIGHT-y as usize)].iter().enumerate() {
+            let cur_y = y as usize + j;
+            for i in 0..(WIDTH-x as usize) {
+                let bit = (byte >> (7 - i)) & 1;
+                let buf_ind: usize = (WIDTH * cur_y) + (x as usize + i);
+                if bit == 1 {
+                    assert_eq!(disp_arc.buf.lock().unwrap().pixels[buf_ind], ON_PIXEL);
+                } else {
+                    assert_eq!(disp_arc.buf.lock().unwrap().pixels[buf_ind], OFF_PIXEL);
+                }
+            }
+        }
+
+        // Now check that the rest i.e the wrapped around part isn't drawn.
+        for (j, _) in sprite[(HEIGHT-y as usize)..].iter().enumerate() {
+            let cur_y = y as usize + j;
+            for i in (WIDTH-x as usize)..8 {
+                let buf_ind: usize = (WIDTH * cur_y) + (x as usize + i);
+                assert_eq!(disp_arc.buf.lock().unwrap().pixels[buf_ind], OFF_PIXEL);
+            }
+        }
+
+    }
+
+    #[test]
+    // Draw a sprite straddling the bottom right corner, with and without wrapping.
+    fn update_buf_wrap() {
+        // A solid 8x4 block.
+        let sprite = vec![0xFF; 4];
+        let x = (WIDTH - 3) as u8;
+        let y = (HEIGHT - 2) as u8;
+
+        for wrap in [false, true] {
+            let disp_arc = Display::new(true);
+            Display::set_wrap_sprites(&disp_arc, wrap);
+            assert_eq!(Display::update_buf_sprite(&disp_arc, x, y, &sprite, 8), 0);
+
+            let buf = disp_arc.buf.lock().unwrap();
+            for row in 0..4 {
+                for col in 0..8 {
+                    let cur_x = x as usize + col;
+                    let cur_y = y as usize + row;
+                    let visible = cur_x < WIDTH && cur_y < HEIGHT;
+                    let buf_ind = WIDTH * (cur_y % HEIGHT) + (cur_x % WIDTH);
+                    let expected = if visible || wrap { ON_PIXEL } else { OFF_PIXEL };
+                    assert_eq!(buf.pixels[buf_ind], expected, "wrap {} pixel ({}, {})", wrap, cur_x, cur_y);
+                }
+            }
+
+            let on_pixels = buf.pixels.iter().filter(|pxl| **pxl == ON_PIXEL).count();
+            assert_eq!(on_pixels, if wrap { 32 } else { 6 });
+        }
+    }
+
+    #[test]
+    // Wrapping is decided separately for each axis.
+    fn update_buf_wrap_axes() {
+        // A solid 8x4 block straddling the bottom right corner.
+        let sprite = vec![0xFF; 4];
+        let x = (WIDTH - 3) as u8;
+        let y = (HEIGHT - 2) as u8;
+
+        // (wrap_x, wrap_y, number of lit pixels)
+        let table = [(false, false, 6), (true, false, 16), (false, true, 12), (true, true, 32)];
+        for (wrap_x, wrap_y, count) in table.iter() {
+            let disp_arc = Display::new(true);
+            Display::set_wrap(&disp_arc, *wrap_x, *wrap_y);
+            assert_eq!(Display::update_buf_sprite(&disp_arc, x, y, &sprite, 8), 0);
+
+            let buf = disp_arc.buf.lock().unwrap();
+            for row in 0..4 {
+                for col in 0..8 {
+                    let cur_x = x as usize + col;
+                    let cur_y = y as usize + row;
+                    let visible = (cur_x < WIDTH || *wrap_x) && (cur_y < HEIGHT || *wrap_y);
+                    let buf_ind = WIDTH * (cur_y % HEIGHT) + (cur_x % WIDTH);
+                    let expected = if visible { ON_PIXEL } else { OFF_PIXEL };
+                    assert_eq!(buf.pixels[buf_ind], expected, "wrap ({}, {}) pixel ({}, {})",
+                               wrap_x, wrap_y, cur_x, cur_y);
+                }
+            }
+
+            let on_pixels = buf.pixels.iter().filter(|pxl| **pxl == ON_PIXEL).count();
+            assert_eq!(on_pixels, *count, "wrap ({}, {})", wrap_x, wrap_y);
+        }
+    }
+
+    #[test]
+    // Case where already on pixels are switched off by the sprite.
+    fn update_buf_sprite_vf_check() {
+        let disp_arc = Display::new(true);
+        // Use a sprite for the letter "F"
+        let sprite = vec![0xF0, 0x80, 0xF0, 0x80, 0x80];
+
+        let x = 32;
+        let y = 16;
+
+        // Set the display buffer as if the sprite has already been drawn.
+        for (j, byte) in sprite.iter().enumerate() {
+            let cur_y = y as usize + j;
+            for i in 0..8 {
+                let bit = (byte >> (7 - i)) & 1;
+                let buf_ind: usize = (WIDTH * cur_y) + (x + i) as usize;
+                disp_arc.buf.lock().unwrap().pixels[buf_ind] = if bit == 1 { ON_PIXEL } else { OFF_PIXEL };
+            }
+        }
+
+        let vf = Display::update_buf_sprite(&disp_arc, x, y, &sprite, 8);
+        assert_eq!(vf, 1);
+
+        // All the pixels should be switched off.
+        for (j, _) in sprite.iter().enumerate() {
+            let cur_y = y as usize + j;
+            for _ in 0..8 {
+                let buf_ind: usize = (WIDTH * cur_y) + (x + 1) as usize;
+                assert_eq!(disp_arc.buf.lock().unwrap().pixels[buf_ind], OFF_PIXEL)
+            }
+        }
+    }
+
+    #[test]
+    fn update_buf_sprite_vf_clipped() {
+        let disp_arc = Display::new(true);
+        let sprite = vec![0xF0; 5];
+
+        // Light up the pixels the clipped rows would wrap around onto.
+        for row in 0..3 {
+            for col in 0..4 {
+                disp_arc.buf.lock().unwrap().pixels[row * WIDTH + 10 + col] = ON_PIXEL;
+            }
+        }
+
+        // Only the last 2 rows are on screen, and nothing collides there.
+        let vf = Display::update_buf_sprite(&disp_arc, 10, (HEIGHT - 2) as u8, &sprite, 8);
+        assert_eq!(vf, 0);
+        for row in 0..3 {
+            assert_eq!(disp_arc.buf.lock().unwrap().pixels[row * WIDTH + 10], ON_PIXEL);
+        }
+
+        // The same goes for the columns past the right edge.
+        let mut pixels = vec![OFF_PIXEL; WIDTH * HEIGHT];
+        pixels[0] = ON_PIXEL;
+        pixels[1] = ON_PIXEL;
+        let draw = Display::xor_sprite(&mut pixels, (WIDTH, HEIGHT), (WIDTH - 2) as u8, 0, &[0xF0], 8, (false, false));
+        assert_eq!(draw.collided_rows, 0);
+        assert_eq!(pixels[..2], [ON_PIXEL, ON_PIXEL]);
+        assert_eq!(pixels[WIDTH - 2..WIDTH], [ON_PIXEL, ON_PIXEL]);
+
+        // A collision in a row that is drawn still counts.
+        let draw = Display::xor_sprite(&mut pixels, (WIDTH, HEIGHT), (WIDTH - 2) as u8, 0, &[0xF0], 8, (false, false));
+        assert_eq!(draw.collided_rows, 1);
+
+        // Sprites starting entirely off screen draw nothing.
+        let mut pixels = vec![ON_PIXEL; WIDTH * HEIGHT];
+        let draw = Display::xor_sprite(&mut pixels, (WIDTH, HEIGHT), WIDTH as u8, 0, &[0xFF], 8, (false, false));
+        assert_eq!(draw, PlaneDraw { collided_rows: 0, pixels_flipped: 0, rows_drawn: 1 });
+        let draw = Display::xor_sprite(&mut pixels, (WIDTH, HEIGHT), 0, HEIGHT as u8, &[0xFF], 8, (false, false));
+        assert_eq!(draw, PlaneDraw::default());
+        assert!(pixels.iter().all(|pxl| *pxl == ON_PIXEL));
+    }
+
+    #[test]
+    fn update_buf_sprite_vf_multiple_rows() {
+        let disp_arc = Display::new(true);
+        let sprite = vec![0x80, 0x80, 0x80, 0x80];
+        // The first and third rows collide, the others don't.
+        disp_arc.buf.lock().unwrap().pixels[0] = ON_PIXEL;
+        disp_arc.buf.lock().unwrap().pixels[2 * WIDTH] = ON_PIXEL;
+
+        let vf = Display::update_buf_sprite(&disp_arc, 0, 0, &sprite, 8);
+        assert_eq!(vf, 1);
+        let column: Vec<u8> = (0..4).map(|row| disp_arc.buf.lock().unwrap().pixels[row * WIDTH]).collect();
+        assert_eq!(column, [OFF_PIXEL, ON_PIXEL, OFF_PIXEL, ON_PIXEL]);
+
+        // A collision on the last row alone is enough too.
+        let vf = Display::update_buf_sprite(&disp_arc, 0, 0, &[0x0, 0x0, 0x0, 0x80], 8);
+        assert_eq!(vf, 1);
+    }
+
+    #[test]
+    fn draw_detailed() {
+        let disp_arc = Display::new(true);
+        // Light up the pixels under the first two columns of the sprite.
+        for row in 0..3 {
+            disp_arc.buf.lock().unwrap().pixels[(HEIGHT - 3 + row) * WIDTH + 10] = ON_PIXEL;
+        }
+        disp_arc.buf.lock().unwrap().pixels[(HEIGHT - 2) * WIDTH + 11] = ON_PIXEL;
+
+        // 5 rows, of which the last 2 are clipped at the bottom edge.
+        let sprite = vec![0xF0, 0xC0, 0x80, 0xFF, 0xFF];
+        let result = Display::draw_detailed(&disp_arc, 10, (HEIGHT - 3) as u8, &sprite, 8);
+        assert_eq!(result, DrawResult { vf: 1, pixels_flipped: 7, rows_drawn: 3 });
+        let pixels = Display::pixels(&disp_arc);
+        let lit: Vec<bool> = (0..4).map(|x| pixels[(HEIGHT - 3) * WIDTH + 10 + x]).collect();
+        assert_eq!(lit, [false, true, true, true]);
+        assert_eq!(Display::collision_count(&disp_arc), 1);
+
+        // Drawing over an empty spot.
+        let result = Display::draw_detailed(&disp_arc, 0, 0, &[0x81], 8);
+        assert_eq!(result, DrawResult { vf: 0, pixels_flipped: 2, rows_drawn: 1 });
+        assert_eq!(Display::draw(&disp_arc, 0, 0, &[0x81], 8), 1);
+    }
+
+    #[test]
+    fn update_buf_sprite_vf_row_count() {
+        let disp_arc = Display::new(true);
+        Display::set_count_collision_rows(&disp_arc, true);
+        // Light up a 2 pixel wide column over rows 0, 2 and 3.
+        for row in [0, 2, 3].iter() {
+            disp_arc.buf.lock().unwrap().pixels[row * WIDTH] = ON_PIXEL;
+            disp_arc.buf.lock().unwrap().pixels[row * WIDTH + 1] = ON_PIXEL;
+        }
+
+        // Several collisions in the same row only count once.
+        let sprite = vec![0xC0, 0xC0, 0xC0, 0xC0, 0xC0];
+        assert_eq!(Display::update_buf_sprite(&disp_arc, 0, 0, &sprite, 8), 3);
+        // Only row 1 and 4 are lit now.
+        assert_eq!(Display::update_buf_sprite(&disp_arc, 0, 0, &sprite, 8), 2);
+        assert_eq!(Display::update_buf_sprite(&disp_arc, 0, 8, &sprite, 8), 0);
+
+        // The setting survives a reset, and draws report the count.
+        Display::reset(&disp_arc);
+        Display::draw(&disp_arc, 0, 0, &sprite, 8);
+        assert_eq!(Display::draw(&disp_arc, 0, 1, &sprite, 8), 4);
+
+        // Back to plain 0/1 collisions.
+        Display::set_count_collision_rows(&disp_arc, false);
+        assert_eq!(Display::update_buf_sprite(&disp_arc, 0, 0, &sprite, 8), 1);
+    }
+
+    #[test]
+    fn update_buf_sprite_vf_row_count_planes() {
+        let disp_arc = Display::new(true);
+        Display::set_count_collision_rows(&disp_arc, true);
+        assert!(Display::set_planes(&disp_arc, 3).is_ok());
+        {
+            let mut buf = disp_arc.buf.lock().unwrap();
+            buf.pixels[0] = ON_PIXEL;
+            buf.pixels2[0] = ON_PIXEL;
+            buf.pixels2[WIDTH] = ON_PIXEL;
+        }
+
+        // Row 0 collides in both planes, row 1 only in the second one.
+        let sprite = vec![0x80, 0x00, 0x80, 0x80];
+        assert_eq!(Display::update_buf_sprite(&disp_arc, 0, 0, &sprite, 8), 2);
+    }
+
+    #[test]
+    fn set_hires() {
+        let disp_arc = Display::new(true);
+        assert_eq!(Display::resolution(&disp_arc), (WIDTH, HEIGHT));
+
+        Display::set_hires(&disp_arc, true);
+        assert_eq!(Display::resolution(&disp_arc), (HIRES_WIDTH, HIRES_HEIGHT));
+        assert_eq!(disp_arc.buf.lock().unwrap().pixels.len(), HIRES_WIDTH * HIRES_HEIGHT);
+
+        Display::set_hires(&disp_arc, false);
+        assert_eq!(Display::resolution(&disp_arc), (WIDTH, HEIGHT));
+        assert_eq!(disp_arc.buf.lock().unwrap().pixels.len(), WIDTH * HEIGHT);
+    }
+
+    #[test]
+    // Draw the same sprite in both resolutions, and make sure the rows are laid out
+    // according to the active width.
+    fn update_buf_sprite_hires() {
+        let disp_arc = Display::new(true);
+        // Use a sprite for the letter "F"
+        let sprite = vec![0xF0, 0x80, 0xF0, 0x80, 0x80];
+
+        for (width, height) in [(WIDTH, HEIGHT), (HIRES_WIDTH, HIRES_HEIGHT)] {
+            Display::set_hires(&disp_arc, width == HIRES_WIDTH);
+
+            // Place the sprite past the lo-res bounds when in hi-res mode.
+            let x = (width - 10) as u8;
+            let y = (height - 10) as u8;
+            let vf = Display::update_buf_sprite(&disp_arc, x, y, &sprite, 8);
+            assert_eq!(vf, 0);
+
+            let buf = disp_arc.buf.lock().unwrap();
+            let on_pixels = buf.pixels.iter().filter(|pxl| **pxl == ON_PIXEL).count();
+            assert_eq!(on_pixels, 11);
+            for (j, byte) in sprite.iter().enumerate() {
+                let cur_y = y as usize + j;
+                for i in 0..8 {
+                    let bit = (byte >> (7 - i)) & 1;
+                    let buf_ind: usize = (width * cur_y) + x as usize + i;
+                    if bit == 1 {
+                        assert_eq!(buf.pixels[buf_ind], ON_PIXEL);
+                    } else {
+                        assert_eq!(buf.pixels[buf_ind], OFF_PIXEL);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    // 16x16 sprites are made up of 2 bytes per row.
+    fn update_buf_sprite_16x16() {
+        let disp_arc = Display::new(true);
+        Display::set_hires(&disp_arc, true);
+
+        let mut sprite: Vec<u8> = Vec::new();
+        for row in 0..16 {
+            // Left half lit on even rows, right half lit on odd rows.
+            if row % 2 == 0 {
+                sprite.extend_from_slice(&[0xFF, 0x00]);
+            } else {
+                sprite.extend_from_slice(&[0x00, 0xFF]);
+            }
+        }
+
+        let x = 40;
+        let y = 20;
+        let vf = Display::update_buf_sprite(&disp_arc, x, y, &sprite, 16);
+        assert_eq!(vf, 0);
+
+        let buf = disp_arc.buf.lock().unwrap();
+        for row in 0..16 {
+            let cur_y = y as usize + row;
+            for col in 0..16 {
+                let buf_ind: usize = (HIRES_WIDTH * cur_y) + x as usize + col;
+                let lit = (row % 2 == 0) == (col < 8);
+                assert_eq!(buf.pixels[buf_ind], if lit { ON_PIXEL } else { OFF_PIXEL });
+            }
+        }
+
+        let on_pixels = buf.pixels.iter().filter(|pxl| **pxl == ON_PIXEL).count();
+        assert_eq!(on_pixels, 16 * 8);
+    }
+
+    #[test]
+    fn scroll() {
+        let disp_arc = Display::new(true);
+        // Use a sprite for the letter "F"
+        let sprite = vec![0xF0, 0x80, 0xF0, 0x80, 0x80];
+
+        let x = 32;
+        let y = 16;
+        let on_pixels = |disp: &Display| -> Vec<usize> {
+            let buf = disp.buf.lock().unwrap();
+            buf.pixels.iter().enumerate()
+                .filter(|(_, pxl)| **pxl == ON_PIXEL)
+                .map(|(ind, _)| ind)
+                .collect()
+        };
+
+        Display::update_buf_sprite(&disp_arc, x, y, &sprite, 8);
+        let orig = on_pixels(&disp_arc);
+
+        Display::scroll_down(&disp_arc, 3);
+        let expected: Vec<usize> = orig.iter().map(|ind| ind + 3 * WIDTH).collect();
+        assert_eq!(on_pixels(&disp_arc), expected);
+
+        Display::scroll_right(&disp_arc);
+        let expected: Vec<usize> = orig.iter().map(|ind| ind + 3 * WIDTH + 4).collect();
+        assert_eq!(on_pixels(&disp_arc), expected);
+
+        Display::scroll_left(&disp_arc);
+        Display::scroll_left(&disp_arc);
+        let expected: Vec<usize> = orig.iter().map(|ind| ind + 3 * WIDTH - 4).collect();
+        assert_eq!(on_pixels(&disp_arc), expected);
+    }
+
+    #[test]
+    // Pixels scrolled past the edge are dropped, and the vacated area is cleared.
+    fn scroll_fill() {
+        let disp_arc = Display::new(true);
+        for pxl in disp_arc.buf.lock().unwrap().pixels.iter_mut() {
+            *pxl = ON_PIXEL;
+        }
+
+        Display::scroll_down(&disp_arc, 2);
+        Display::scroll_right(&disp_arc);
+        let buf = disp_arc.buf.lock().unwrap();
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                let expected = if y < 2 || x < 4 { OFF_PIXEL } else { ON_PIXEL };
+                assert_eq!(buf.pixels[WIDTH * y + x], expected);
+            }
+        }
+        drop(buf);
+
+        Display::scroll_left(&disp_arc);
+        let buf = disp_arc.buf.lock().unwrap();
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                let expected = if y < 2 || x >= WIDTH - 4 { OFF_PIXEL } else { ON_PIXEL };
+                assert_eq!(buf.pixels[WIDTH * y + x], expected);
+            }
+        }
+    }
+
+    #[test]
+    // Draw the same sprite with each plane selection, and check which planes change.
+    fn planes() {
+        // Use a sprite for the letter "F"
+        let sprite = vec![0xF0, 0x80, 0xF0, 0x80, 0x80];
+        let lit = |pixels: &Vec<u8>| pixels.iter().filter(|pxl| **pxl == ON_PIXEL).count();
+
+        // Plane 1 only, which is the default.
+        let disp_arc = Display::new(true);
+        assert_eq!(Display::plane_count(&disp_arc), 1);
+        assert_eq!(Display::update_buf_sprite(&disp_arc, 0, 0, &sprite, 8), 0);
+        assert_eq!(lit(&disp_arc.buf.lock().unwrap().pixels), 11);
+        assert_eq!(lit(&disp_arc.buf.lock().unwrap().pixels2), 0);
+
+        // Plane 2 only.
+        let disp_arc = Display::new(true);
+        assert!(Display::set_planes(&disp_arc, 0x2).is_ok());
+        assert_eq!(Display::plane_count(&disp_arc), 1);
+        assert_eq!(Display::update_buf_sprite(&disp_arc, 0, 0, &sprite, 8), 0);
+        assert_eq!(lit(&disp_arc.buf.lock().unwrap().pixels), 0);
+        assert_eq!(lit(&disp_arc.buf.lock().unwrap().pixels2), 11);
+
+        // Both planes, the first half of the sprite goes to plane 1 and the second
+        // half to plane 2.
+        let disp_arc = Display::new(true);
+        assert!(Display::set_planes(&disp_arc, 0x3).is_ok());
+        assert_eq!(Display::plane_count(&disp_arc), 2);
+        let both = vec![0xFF, 0x0F];
+        assert_eq!(Display::update_buf_sprite(&disp_arc, 0, 0, &both, 8), 0);
+        {
+            let buf = disp_arc.buf.lock().unwrap();
+            assert_eq!(lit(&buf.pixels), 8);
+            assert_eq!(lit(&buf.pixels2), 4);
+            let composed = buf.composed_pixels();
+            assert_eq!(composed[0], ON_PIXEL);
+            assert_eq!(composed[4], BOTH_PLANES_PIXEL);
+            assert_eq!(composed[8], OFF_PIXEL);
+        }
+
+        // A collision in either plane sets VF.
+        assert_eq!(Display::update_buf_sprite(&disp_arc, 0, 0, &[0x00, 0x01], 8), 1);
+        assert_eq!(lit(&disp_arc.buf.lock().unwrap().pixels2), 3);
+
+        // Clearing only touches the selected planes.
+        assert!(Display::set_planes(&disp_arc, 0x1).is_ok());
+        Display::clear(&disp_arc);
+        assert_eq!(lit(&disp_arc.buf.lock().unwrap().pixels), 0);
+        assert_eq!(lit(&disp_arc.buf.lock().unwrap().pixels2), 3);
+        assert_eq!(disp_arc.buf.lock().unwrap().composed_pixels()[4], PLANE2_PIXEL);
+
+        // No planes selected means nothing is drawn.
+        assert!(Display::set_planes(&disp_arc, 0x0).is_ok());
+        assert_eq!(Display::plane_count(&disp_arc), 0);
+        assert_eq!(Display::update_buf_sprite(&disp_arc, 0, 0, &sprite, 8), 0);
+        assert_eq!(lit(&disp_arc.buf.lock().unwrap().pixels), 0);
+
+        assert_eq!(Display::set_planes(&disp_arc, 0x4), Err(Chip8Error::InvalidPlanes(0x4)));
+    }
+
+    #[test]
+    fn snapshot_buffer() {
+        let disp_arc = Display::new(true);
+        // Use a sprite for the letter "F"
+        let sprite = vec![0xF0, 0x80, 0xF0, 0x80, 0x80];
+        Display::draw(&disp_arc, 2, 1, &sprite, 8);
+
+        let snapshot = Display::snapshot_buffer(&disp_arc);
+        assert_eq!(snapshot.len(), WIDTH * HEIGHT);
+        let on_pixels: Vec<usize> = snapshot.iter().enumerate()
+            .filter(|(_, pxl)| **pxl == ON_PIXEL)
+            .map(|(ind, _)| ind)
+            .collect();
+        let row = |y: usize, cols: &[usize]| -> Vec<usize> { cols.iter().map(|x| WIDTH * y + x).collect() };
+        let expected = [row(1, &[2, 3, 4, 5]), row(2, &[2]), row(3, &[2, 3, 4, 5]), row(4, &[2]), row(5, &[2])].concat();
+        assert_eq!(on_pixels, expected);
+
+        // The snapshot follows the active resolution.
+        Display::set_hires(&disp_arc, true);
+        assert_eq!(Display::snapshot_buffer(&disp_arc).len(), HIRES_WIDTH * HIRES_HEIGHT);
+
+        let pgm = Display::encode_pgm(&[0x00, 0xFF], 2, 1);
+        assert_eq!(pgm, b"P5\n2 1\n255\n\x00\xFF");
+    }
+
+    #[test]
+    fn decay() {
+        let mut intensity = vec![ON_PIXEL, OFF_PIXEL, 0x40];
+        let target = vec![OFF_PIXEL, ON_PIXEL, 0x38];
+
+        Display::decay(&mut intensity, &target, 0x10);
+        assert_eq!(intensity, vec![ON_PIXEL - 0x10, ON_PIXEL, 0x38]);
+        Display::decay(&mut intensity, &target, 0x10);
+        assert_eq!(intensity, vec![ON_PIXEL - 0x20, ON_PIXEL, 0x38]);
+
+        // Pixels fade all the way out eventually.
+        for _ in 0..16 {
+            Display::decay(&mut intensity, &target, 0x10);
+        }
+        assert_eq!(intensity, target);
+    }
+
+    #[test]
+    fn fade() {
+        let disp_arc = Display::new(true);
+        Display::set_fade(&disp_arc, true);
+        disp_arc.buf.lock().unwrap().pixels[0] = ON_PIXEL;
+        assert_eq!(Display::shades(&disp_arc)[0], ON_PIXEL);
+
+        // Switched off pixels linger, dimming once per frame rather than per render.
+        disp_arc.buf.lock().unwrap().pixels[0] = OFF_PIXEL;
+        assert_eq!(Display::shades(&disp_arc)[0], ON_PIXEL);
+        Display::tick_frame(&disp_arc);
+        assert_eq!(Display::shades(&disp_arc)[0], ON_PIXEL - FADE_STEP);
+        assert_eq!(Display::shades(&disp_arc)[0], ON_PIXEL - FADE_STEP);
+        Display::tick_frame(&disp_arc);
+        Display::tick_frame(&disp_arc);
+        assert_eq!(Display::shades(&disp_arc)[0], ON_PIXEL - 3 * FADE_STEP);
+        assert_eq!(Display::snapshot_buffer(&disp_arc)[0], OFF_PIXEL);
+
+        Display::set_fade(&disp_arc, false);
+        assert_eq!(Display::shades(&disp_arc)[0], OFF_PIXEL);
+    }
+
+    #[test]
+    fn blit_text() {
+        const FONT_A: [u8; 5] = [0xF0, 0x90, 0xF0, 0x90, 0x90];
+        let mut font = vec![0; 16 * GLYPH_HEIGHT];
+        font[0xA * GLYPH_HEIGHT..0xB * GLYPH_HEIGHT].copy_from_slice(&FONT_A);
+
+        let mut pixels = vec![OFF_PIXEL; WIDTH * HEIGHT];
+        Display::blit_text(&mut pixels, (WIDTH, HEIGHT), 2, 3, "A", &font);
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                let on = (3..3 + GLYPH_HEIGHT).contains(&y) && (2..2 + GLYPH_WIDTH).contains(&x) &&
+                    (FONT_A[y - 3] >> (7 - (x - 2))) & 0x1 == 0x1;
+                let expected = if on { ON_PIXEL } else { OFF_PIXEL };
+                assert_eq!(pixels[y * WIDTH + x], expected, "pixel {},{}", x, y);
+            }
+        }
+
+        // Characters past the edge are clipped, and unknown ones are skipped.
+        let mut pixels = vec![OFF_PIXEL; WIDTH * HEIGHT];
+        Display::blit_text(&mut pixels, (WIDTH, HEIGHT), WIDTH - 2, HEIGHT - 1, "A", &font);
+        assert_eq!(pixels[(HEIGHT - 1) * WIDTH + WIDTH - 2..], [ON_PIXEL, ON_PIXEL]);
+        let mut pixels = vec![OFF_PIXEL; WIDTH * HEIGHT];
+        Display::blit_text(&mut pixels, (WIDTH, HEIGHT), 0, 0, " ?", &font);
+        assert!(pixels.iter().all(|pxl| *pxl == OFF_PIXEL));
+    }
+
+    #[test]
+    fn hud() {
+        let disp_arc = Display::new(true);
+        let font = vec![0xF0; 16 * GLYPH_HEIGHT];
+        // Nothing is drawn until the HUD is enabled.
+        let mut pixels = vec![OFF_PIXEL; WIDTH * HEIGHT];
+        Display::draw_hud(&disp_arc, &mut pixels);
+        assert_eq!(pixels[0], OFF_PIXEL);
+
+        Display::set_hud(&disp_arc, Some(font));
+        disp_arc.fps.store(59.6f32.to_bits(), Ordering::Relaxed);
+        disp_arc.keys_state.lock().unwrap().insert(0x5, true);
+        Display::draw_hud(&disp_arc, &mut pixels);
+        // Two digits of FPS on the first line, a single key on the second.
+        let line = |y: usize, pixels: &Vec<u8>| -> Vec<bool> {
+            pixels[y * WIDTH..y * WIDTH + 2 * GLYPH_ADVANCE].iter().map(|p| *p == ON_PIXEL).collect()
+        };
+        assert_eq!(line(0, &pixels), [true, true, true, true, false, true, true, true, true, false]);
+        assert_eq!(line(GLYPH_HEIGHT + 1, &pixels), [true, true, true, true, false, false, false, false, false, false]);
+
+        // The frame buffer is left untouched.
+        assert!(Display::snapshot_buffer(&disp_arc).iter().all(|pxl| *pxl == OFF_PIXEL));
+    }
+
+    #[test]
+    fn average_fps() {
+        let disp_arc = Display::new(true);
+        assert_eq!(Display::fps(&disp_arc), 0.0);
+
+        let mut frame_times: VecDeque<Duration> = VecDeque::new();
+        assert_eq!(Display::average_fps(&frame_times), 0.0);
+
+        frame_times.extend([Duration::from_millis(20); 10]);
+        assert_eq!(Display::average_fps(&frame_times), 50.0);
+
+        // Uneven frames are averaged over the total time taken.
+        frame_times.clear();
+        frame_times.extend([Duration::from_millis(10), Duration::from_millis(30), Duration::from_millis(60),
+            Duration::from_millis(100)]);
+        assert_eq!(Display::average_fps(&frame_times), 20.0);
+    }
+
+    #[test]
+    fn static_screen_fps() {
+        let disp_arc = Display::new(true);
+        let clock = Arc::new(FakeClock::new());
+        Display::spawn_thread_with_clock(&disp_arc, clock.clone());
+
+        // Nothing is drawn, but every render tick still counts.
+        for ind in 1..=FPS_WINDOW as u32 {
+            clock.wait_for_sleeps(ind);
+            clock.advance(RENDER_PERIOD);
+        }
+        clock.wait_for_sleeps(FPS_WINDOW as u32 + 1);
+        assert!((Display::fps(&disp_arc) - 60.0).abs() < 0.1);
+
+        disp_arc.shutdown.store(true, Ordering::Relaxed);
+        clock.advance(RENDER_PERIOD);
+        Display::shutdown(&disp_arc);
+    }
+
+    #[test]
+    fn palette() {
+        let disp_arc = Display::new(true);
+        disp_arc.buf.lock().unwrap().pixels[1] = ON_PIXEL;
+
+        // Defaults to white on black.
+        let rgb = Display::rgb_pixels(&disp_arc);
+        assert_eq!(rgb.len(), WIDTH * HEIGHT * 3);
+        assert_eq!(rgb[0..3], [0x0, 0x0, 0x0]);
+        assert_eq!(rgb[3..6], [0xFF, 0xFF, 0xFF]);
+
+        // Amber on dark blue.
+        Display::set_palette(&disp_arc, [0xFF, 0xB0, 0x00], [0x00, 0x00, 0x40]);
+        let rgb = Display::rgb_pixels(&disp_arc);
+        assert_eq!(rgb[0..3], [0x00, 0x00, 0x40]);
+        assert_eq!(rgb[3..6], [0xFF, 0xB0, 0x00]);
+        assert_eq!(rgb[6..9], [0x00, 0x00, 0x40]);
+    }
+
+    #[test]
+    fn inverted() {
+        let disp_arc = Display::new(true);
+        {
+            let mut buf = disp_arc.buf.lock().unwrap();
+            buf.pixels[1] = ON_PIXEL;
+            buf.pixels[WIDTH + 5] = ON_PIXEL;
+            buf.pixels2[2] = ON_PIXEL;
+        }
+        let normal = Display::rgb_pixels(&disp_arc);
+
+        Display::set_inverted(&disp_arc, true);
+        let inverted = Display::rgb_pixels(&disp_arc);
+        assert_eq!(inverted.len(), normal.len());
+        assert!(normal.iter().zip(inverted.iter()).all(|(pxl, inv)| *inv == !*pxl));
+        assert_eq!(inverted[0..3], [0xFF, 0xFF, 0xFF]);
+        assert_eq!(inverted[3..6], [0x0, 0x0, 0x0]);
+
+        // The frame buffer itself is left alone.
+        assert_eq!(disp_arc.buf.lock().unwrap().pixels[1], ON_PIXEL);
+        assert_eq!(disp_arc.buf.lock().unwrap().pixels[0], OFF_PIXEL);
+
+        Display::set_inverted(&disp_arc, false);
+        assert_eq!(Display::rgb_pixels(&disp_arc), normal);
+    }
+
+    #[test]
+    fn shutdown() {
+        let disp_arc = Display::new(true);
+        let clock = Arc::new(FakeClock::new());
+        Display::spawn_thread_with_clock(&disp_arc, clock.clone());
+
+        // Each pass of the loop sleeps until the next render tick.
+        for sleeps in 1..=2 {
+            clock.wait_for_sleeps(sleeps);
+            clock.advance(RENDER_PERIOD);
+        }
+        clock.wait_for_sleeps(3);
+
+        // The thread only notices the shutdown once it wakes up, and then
+        // exits without going round the loop again.
+        disp_arc.shutdown.store(true, Ordering::Relaxed);
+        clock.advance(RENDER_PERIOD);
+        Display::shutdown(&disp_arc);
+        assert!(disp_arc.thread.lock().unwrap().is_none());
+        assert_eq!(clock.sleeps(), 3);
+
+        clock.advance(RENDER_PERIOD * 10);
+        assert_eq!(clock.sleeps(), 3);
+
+        // Shutting down again is harmless.
+        Display::shutdown(&disp_arc);
+    }
+
+    #[test]
+    fn batched() {
+        let sprite = vec![0xFF, 0x81, 0x81, 0xFF];
+        let draw_frame = |disp: &Arc<Display>| {
+            Display::clear(disp);
+            for i in 0..50u8 {
+                Display::draw(disp, i, i % 28, &sprite, 8);
+            }
+        };
+
+        let direct = Display::new(true);
+        draw_frame(&direct);
+        let direct_locks = direct.buf.lock_count();
+
+        let batched = Display::new(true);
+        Display::set_batched(&batched, true);
+        let start_locks = batched.buf.lock_count();
+        draw_frame(&batched);
+
+        // Nothing shows up until the frame is flushed.
+        assert!(batched.buf.lock().unwrap().pixels.iter().all(|pxl| *pxl == OFF_PIXEL));
+        Display::flush(&batched);
+        let batched_locks = batched.buf.lock_count() - start_locks - 1;
+
+        assert_eq!(direct_locks, 51);
+        assert_eq!(batched_locks, 1);
+        assert_eq!(batched.buf.lock().unwrap().pixels, direct.buf.lock().unwrap().pixels);
+        assert_eq!(Display::snapshot_buffer(&batched), Display::snapshot_buffer(&direct));
+
+        // Turning batching off flushes whatever was drawn since.
+        Display::clear(&batched);
+        Display::set_batched(&batched, false);
+        assert!(batched.buf.lock().unwrap().pixels.iter().all(|pxl| *pxl == OFF_PIXEL));
+    }
+
+    #[test]
+    fn dirty() {
+        let disp_arc = Display::new(true);
+        // The first frame always gets shown.
+        assert!(Display::take_dirty(&disp_arc));
+        assert!(!Display::take_dirty(&disp_arc));
+
+        Display::draw(&disp_arc, 0, 0, &[0x80], 8);
+        assert!(Display::take_dirty(&disp_arc));
+        assert!(!Display::take_dirty(&disp_arc));
+        Display::clear(&disp_arc);
+        assert!(Display::take_dirty(&disp_arc));
+        // Sprites which don't touch any pixels change nothing.
+        Display::draw(&disp_arc, 0, 0, &[0x00, 0x00], 8);
+        assert!(!Display::take_dirty(&disp_arc));
+        Display::set_inverted(&disp_arc, true);
+        assert!(Display::take_dirty(&disp_arc));
+
+        // When batching, the frame only changes once flushed.
+        Display::set_batched(&disp_arc, true);
+        Display::draw(&disp_arc, 0, 0, &[0x80], 8);
+        Display::scroll_down(&disp_arc, 1);
+        assert!(!Display::take_dirty(&disp_arc));
+        let locks = disp_arc.buf.lock_count();
+        Display::flush(&disp_arc);
+        assert!(Display::take_dirty(&disp_arc));
+        assert!(Display::pixels(&disp_arc)[WIDTH]);
+
+        // Flushing an unchanged frame doesn't copy it.
+        Display::flush(&disp_arc);
+        assert!(!Display::take_dirty(&disp_arc));
+        assert_eq!(disp_arc.buf.lock_count(), locks + 1);
+
+        // The HUD and fading need rendering every frame.
+        assert!(!Display::animated(&disp_arc));
+        Display::set_fade(&disp_arc, true);
+        assert!(Display::animated(&disp_arc));
+    }
+
+    #[test]
+    fn collision_count() {
+        let disp_arc = Display::new(true);
+        let sprite = vec![0xF0, 0x90, 0xF0];
+        assert_eq!(Display::collision_count(&disp_arc), 0);
+
+        assert_eq!(Display::draw(&disp_arc, 0, 0, &sprite, 8), 0);
+        assert_eq!(Display::collision_count(&disp_arc), 0);
+
+        // Drawing on top of the first sprite collides.
+        assert_eq!(Display::draw(&disp_arc, 2, 1, &sprite, 8), 1);
+        assert_eq!(Display::collision_count(&disp_arc), 1);
+
+        // Somewhere empty doesn't.
+        assert_eq!(Display::draw(&disp_arc, 20, 10, &sprite, 8), 0);
+        assert_eq!(Display::collision_count(&disp_arc), 1);
+    }
+
+    #[test]
+    fn pixels() {
+        let disp_arc = Display::new(true);
+        let sprite = vec![0xA0, 0x40];
+        Display::draw(&disp_arc, 10, 4, &sprite, 8);
+
+        let pixels = Display::pixels(&disp_arc);
+        assert_eq!(pixels.len(), WIDTH * HEIGHT);
+        let lit: Vec<usize> = (0..pixels.len()).filter(|ind| pixels[*ind]).collect();
+        assert_eq!(lit, [4 * WIDTH + 10, 4 * WIDTH + 12, 5 * WIDTH + 11]);
+
+        // Pixels only lit in the second plane count too.
+        assert!(Display::set_planes(&disp_arc, 0x2).is_ok());
+        Display::draw(&disp_arc, 0, 0, &[0x80], 8);
+        assert!(Display::pixels(&disp_arc)[0]);
+
+        Display::set_hires(&disp_arc, true);
+        assert_eq!(Display::pixels(&disp_arc).len(), HIRES_WIDTH * HIRES_HEIGHT);
+    }
+
+    #[test]
+    fn to_ascii() {
+        // 4x3, with the last row only half covering its line.
+        let pixels = [
+            true, false, true, false,
+            true, true, false, false,
+            false, true, true, false,
+        ];
+        assert_eq!(Display::to_ascii(&pixels, 4, 3), ["█▄▀ ", " ▀▀ "]);
+
+        // A blank screen is all spaces.
+        let lines = Display::to_ascii(&vec![false; WIDTH * HEIGHT], WIDTH, HEIGHT);
+        assert_eq!(lines.len(), HEIGHT / 2);
+        assert!(lines.iter().all(|line| line.chars().count() == WIDTH && line.trim().is_empty()));
+    }
+
+    #[test]
+    fn terminal_input() {
+        let disp_arc = Display::new(true);
+        Display::handle_terminal_input(&disp_arc, b'w');
+        Display::handle_terminal_input(&disp_arc, b'V');
+        Display::handle_terminal_input(&disp_arc, b'?');
+        assert!(Display::get_key_state(&disp_arc, 0x5).unwrap());
+        assert!(Display::get_key_state(&disp_arc, 0xF).unwrap());
+
+        Display::handle_terminal_input(&disp_arc, b'p');
+        assert!(Display::is_paused(&disp_arc));
+        Display::handle_terminal_input(&disp_arc, b'm');
+        assert!(Display::is_muted(&disp_arc));
+        assert!(!Display::quit_requested(&disp_arc));
+        Display::handle_terminal_input(&disp_arc, 0x03);
+        assert!(Display::quit_requested(&disp_arc));
+
+        // Keys are held for a while, since the terminal doesn't report releases.
+        Display::release_terminal_keys(&disp_arc, Duration::from_secs(60));
+        assert!(Display::get_key_state(&disp_arc, 0x5).unwrap());
+        Display::release_terminal_keys(&disp_arc, Duration::ZERO);
+        assert!(!Display::get_key_state(&disp_arc, 0x5).unwrap());
+        assert!(!Display::get_key_state(&disp_arc, 0xF).unwrap());
+        assert_eq!(Display::take_released_keys(&disp_arc), 1 << 0x5 | 1 << 0xF);
+    }
+
+    #[test]
+    fn close_requests_quit() {
+        let disp_arc = Display::new(true);
+        // Test windows are never opened, so any ID will do.
+        let window_id = unsafe { WindowId::dummy() };
+        Display::handle_window_event(&disp_arc, WindowEvent::CloseRequested(WindowCloseRequestedEvent { window_id }));
+        assert!(Display::quit_requested(&disp_arc));
+    }
+
+    #[test]
+    fn render_to() {
+        let disp_arc = Display::new(true);
+        // A 2x2 block in the top left corner and a single pixel on row 3.
+        Display::draw(&disp_arc, 0, 0, &[0xC0, 0xC0], 8);
+        Display::draw(&disp_arc, 5, 3, &[0x80], 8);
+
+        let mut expected = vec![OFF_PIXEL; WIDTH * HEIGHT];
+        for ind in [0, 1, WIDTH, WIDTH + 1, 3 * WIDTH + 5].iter() {
+            expected[*ind] = ON_PIXEL;
+        }
+        assert_eq!(Display::render_to(&disp_arc, WIDTH, HEIGHT), expected);
+
+        // Scaling up doubles every pixel in both directions.
+        let scaled = Display::render_to(&disp_arc, WIDTH * 2, HEIGHT * 2);
+        assert_eq!(scaled.len(), WIDTH * HEIGHT * 4);
+        for y in 0..HEIGHT * 2 {
+            for x in 0..WIDTH * 2 {
+                assert_eq!(scaled[y * WIDTH * 2 + x], expected[(y / 2) * WIDTH + x / 2], "({}, {})", x, y);
+            }
+        }
+
+        // Scaling down samples every other pixel.
+        let shrunk = Display::render_to(&disp_arc, WIDTH / 2, HEIGHT / 2);
+        assert_eq!(shrunk[0], ON_PIXEL);
+        assert_eq!(shrunk.iter().filter(|pxl| **pxl == ON_PIXEL).count(), 1);
+    }
+
+    #[test]
+    fn pause() {
+        let disp_arc = Display::new(true);
+        assert!(!Display::is_paused(&disp_arc));
+
+        Display::toggle_pause(&disp_arc);
+        assert!(Display::is_paused(&disp_arc));
+
+        Display::toggle_pause(&disp_arc);
+        assert!(!Display::is_paused(&disp_arc));
+    }
+
+    #[test]
+    fn rom_key() {
+        let disp_arc = Display::new(true);
+        assert_eq!(Display::take_rom_request(&disp_arc), None);
+
+        // Without Ctrl, the number keys go to the keypad.
+        assert!(!Display::handle_rom_key(&disp_arc, FIRST_ROM_SCANCODE, ElementState::Pressed, false));
+        assert_eq!(Display::take_rom_request(&disp_arc), None);
+
+        // Ctrl+2 picks the second program, and the release is swallowed as well.
+        assert!(Display::handle_rom_key(&disp_arc, FIRST_ROM_SCANCODE + 1, ElementState::Pressed, true));
+        assert!(Display::handle_rom_key(&disp_arc, FIRST_ROM_SCANCODE + 1, ElementState::Released, true));
+        assert_eq!(Display::take_rom_request(&disp_arc), Some(1));
+        assert_eq!(Display::take_rom_request(&disp_arc), None);
+
+        assert!(Display::handle_rom_key(&disp_arc, LAST_ROM_SCANCODE, ElementState::Pressed, true));
+        assert_eq!(Display::take_rom_request(&disp_arc), Some(8));
+
+        // Other keys aren't affected by Ctrl.
+        assert!(!Display::handle_rom_key(&disp_arc, LAST_ROM_SCANCODE + 1, ElementState::Pressed, true));
+    }
+
+    #[test]
+    fn turbo() {
+        let disp_arc = Display::new(true);
+        assert!(!Display::turbo_active(&disp_arc));
+
+        // Turbo is only active while the key is held.
+        assert!(Display::handle_key(&disp_arc, 15, ElementState::Pressed).is_ok());
+        assert!(Display::turbo_active(&disp_arc));
+        assert!(Display::handle_key(&disp_arc, 15, ElementState::Released).is_ok());
+        assert!(!Display::turbo_active(&disp_arc));
+
+        // The pause key toggles on press only.
+        assert!(Display::handle_key(&disp_arc, 25, ElementState::Pressed).is_ok());
+        assert!(Display::handle_key(&disp_arc, 25, ElementState::Released).is_ok());
+        assert!(Display::is_paused(&disp_arc));
+
+        // As does the mute key.
+        Display::set_muted(&disp_arc, true);
+        assert!(Display::handle_key(&disp_arc, 50, ElementState::Pressed).is_ok());
+        assert!(Display::handle_key(&disp_arc, 50, ElementState::Released).is_ok());
+        assert!(!Display::is_muted(&disp_arc));
+        assert!(Display::handle_key(&disp_arc, 50, ElementState::Pressed).is_ok());
+        assert!(Display::is_muted(&disp_arc));
+
+        // Everything else goes to the keypad.
+        assert!(Display::handle_key(&disp_arc, 2, ElementState::Pressed).is_ok());
+        assert!(Display::get_key_state(&disp_arc, 1).unwrap());
+        assert!(Display::handle_key(&disp_arc, 93, ElementState::Pressed).is_err());
+    }
+
+    #[test]
+    fn reset() {
+        let disp_arc = Display::new(true);
+        Display::set_wrap_sprites(&disp_arc, true);
+        Display::set_hires(&disp_arc, true);
+        assert!(Display::set_planes(&disp_arc, 0x3).is_ok());
+        Display::draw(&disp_arc, 0, 0, &[0xFF, 0xFF], 8);
+
+        assert!(!Display::take_reset_request(&disp_arc));
+        assert!(Display::handle_key(&disp_arc, 63, ElementState::Pressed).is_ok());
+        assert!(Display::take_reset_request(&disp_arc));
+        assert!(!Display::take_reset_request(&disp_arc));
+
+        Display::reset(&disp_arc);
+        assert_eq!(Display::resolution(&disp_arc), (WIDTH, HEIGHT));
+        assert_eq!(Display::plane_count(&disp_arc), 1);
+        assert!(Display::snapshot_buffer(&disp_arc).iter().all(|pxl| *pxl == OFF_PIXEL));
+        assert_eq!(Display::wrap(&disp_arc), (true, true));
+    }
+
+    #[test]
+    fn dump_request() {
+        let disp_arc = Display::new(true);
+        assert!(!Display::take_dump_request(&disp_arc));
+
+        // Only the press counts, and it isn't passed on to the keypad.
+        assert!(Display::handle_key(&disp_arc, 60, ElementState::Pressed).is_ok());
+        assert!(Display::handle_key(&disp_arc, 60, ElementState::Released).is_ok());
+        assert!(Display::take_dump_request(&disp_arc));
+        assert!(!Display::take_dump_request(&disp_arc));
+    }
+
+    #[test]
+    fn key_state() {
+        let disp_arc = Display::new(true);
+
+        // Press a key.
+        assert!(Display::set_key_state(&disp_arc, 2, ElementState::Pressed).is_ok());
+        assert_eq!(Display::get_key_state(&disp_arc, 1).unwrap(), true);
+
+        // Gets pressed again.
+        assert!(Display::set_key_state(&disp_arc, 2, ElementState::Pressed).is_ok());
+        assert_eq!(Display::get_key_state(&disp_arc, 1).unwrap(), true);
+
+        // Release the key.
+        assert!(Display::set_key_state(&disp_arc, 2, ElementState::Released).is_ok());
+        assert_eq!(Display::get_key_state(&disp_arc, 1).unwrap(), false);
+
+        // Press a invalid key
+        assert_eq!(Display::set_key_state(&disp_arc, 93, ElementState::Pressed), Err(Chip8Error::UnknownScancode(93)));
+
+        // Press two keys consecutively without releasing them, then make sure the first
+        // one is still reporting as pressed.
+        assert!(Display::set_key_state(&disp_arc, 2, ElementState::Pressed).is_ok());
+        assert!(Display::set_key_state(&disp_arc, 3, ElementState::Pressed).is_ok());
+        assert_eq!(Display::get_key_state(&disp_arc, 2).unwrap(), true);
+    }
+
+    #[test]
+    fn press_key() {
+        let disp_arc = Display::new(true);
+        assert!(Display::press_key(&disp_arc, 0xA).is_ok());
+        assert!(Display::press_key(&disp_arc, 0x3).is_ok());
+        assert!(Display::get_key_state(&disp_arc, 0xA).unwrap());
+        assert!(Display::get_key_state(&disp_arc, 0x3).unwrap());
+
+        assert!(Display::release_key(&disp_arc, 0xA).is_ok());
+        assert!(!Display::get_key_state(&disp_arc, 0xA).unwrap());
+        assert!(Display::get_key_state(&disp_arc, 0x3).unwrap());
+        assert_eq!(Display::take_released_keys(&disp_arc), 1 << 0xA);
+
+        assert_eq!(Display::press_key(&disp_arc, 0x10), Err(Chip8Error::InvalidKey(0x10)));
+        assert_eq!(Display::release_key(&disp_arc, 0xFF), Err(Chip8Error::InvalidKey(0xFF)));
+    }
+
+    #[test]
+    fn pressed_keys() {
+        let disp_arc = Display::new(true);
+        assert!(Display::pressed_keys(&disp_arc).is_empty());
+
+        assert!(Display::press_key(&disp_arc, 0xA).is_ok());
+        assert!(Display::press_key(&disp_arc, 0x1).is_ok());
+        assert!(Display::press_key(&disp_arc, 0x5).is_ok());
+        assert!(Display::release_key(&disp_arc, 0x5).is_ok());
+        assert_eq!(Display::pressed_keys(&disp_arc), vec![0x1, 0xA]);
+
+        assert!(Display::release_key(&disp_arc, 0x1).is_ok());
+        assert_eq!(Display::pressed_keys(&disp_arc), vec![0xA]);
+    }
+
+    #[test]
+    fn key_events() {
+        let disp_arc = Display::new(true);
+        assert_eq!(Display::poll_key_event(&disp_arc), None);
+
+        assert!(Display::press_key(&disp_arc, 0x5).is_ok());
+        // Key repeat doesn't add another press.
+        assert!(Display::press_key(&disp_arc, 0x5).is_ok());
+        assert!(Display::press_key(&disp_arc, 0xE).is_ok());
+        assert!(Display::release_key(&disp_arc, 0x5).is_ok());
+        assert!(Display::release_key(&disp_arc, 0xE).is_ok());
+        assert!(Display::release_key(&disp_arc, 0xE).is_ok());
+
+        // The tap is there even though the level state shows nothing.
+        assert!(!Display::get_key_state(&disp_arc, 0x5).unwrap());
+        assert_eq!(Display::poll_key_event(&disp_arc), Some((0x5, true)));
+        assert_eq!(Display::poll_key_event(&disp_arc), Some((0xE, true)));
+        assert_eq!(Display::poll_key_event(&disp_arc), Some((0x5, false)));
+        assert_eq!(Display::poll_key_event(&disp_arc), Some((0xE, false)));
+        assert_eq!(Display::poll_key_event(&disp_arc), None);
+
+        // Only the newest events are kept when nothing drains the queue.
+        for _ in 0..KEY_EVENT_QUEUE_LEN {
+            assert!(Display::press_key(&disp_arc, 0x1).is_ok());
+            assert!(Display::release_key(&disp_arc, 0x1).is_ok());
+        }
+        assert!(Display::press_key(&disp_arc, 0x2).is_ok());
+        assert_eq!(disp_arc.key_events.lock().unwrap().len(), KEY_EVENT_QUEUE_LEN);
+        assert_eq!(Display::poll_key_event(&disp_arc), Some((0x1, false)));
+        assert_eq!(disp_arc.key_events.lock().unwrap().back(), Some(&(0x2, true)));
+    }
+
+    #[test]
+    fn released_keys() {
+        let disp_arc = Display::new(true);
+        assert_eq!(Display::take_released_keys(&disp_arc), 0);
+
+        // A key which was never pressed isn't counted as released.
+        assert!(Display::set_key_state(&disp_arc, 2, ElementState::Released).is_ok());
+        assert_eq!(Display::take_released_keys(&disp_arc), 0);
+
+        // Presses and releases are latched even if the key state is never read in between.
+        assert!(Display::set_key_state(&disp_arc, 2, ElementState::Pressed).is_ok());
+        assert!(Display::set_key_state(&disp_arc, 2, ElementState::Released).is_ok());
+        assert!(Display::set_key_state(&disp_arc, 45, ElementState::Pressed).is_ok());
+        assert!(Display::set_key_state(&disp_arc, 45, ElementState::Released).is_ok());
+        assert_eq!(Display::take_released_keys(&disp_arc), 1 << 0x1 | 1 << 0x0);
+        assert_eq!(Display::take_released_keys(&disp_arc), 0);
+    }
+
+    #[test]
+    fn key_mapping() {
+        let disp_arc = Display::new(true);
+
+        // Map the arrow keys to 2/4/6/8.
+        let map = HashMap::from([(103, 0x2), (105, 0x4), (106, 0x6), (108, 0x8)]);
+        assert!(Display::set_key_mapping(&disp_arc, map).is_ok());
+
+        assert!(Display::set_key_state(&disp_arc, 105, ElementState::Pressed).is_ok());
+        assert_eq!(Display::get_key_state(&disp_arc, 0x4).unwrap(), true);
+        assert!(Display::set_key_state(&disp_arc, 105, ElementState::Released).is_ok());
+        assert_eq!(Display::get_key_state(&disp_arc, 0x4).unwrap(), false);
+
+        // The default mapping is no longer in effect.
+        assert!(Display::set_key_state(&disp_arc, 2, ElementState::Pressed).is_err());
+
+        // Mappings to keys outside the keypad are rejected.
+        assert_eq!(Display::set_key_mapping(&disp_arc, HashMap::from([(2, 0x10)])), Err(Chip8Error::InvalidKey(0x10)));
+        assert!(Display::set_key_state(&disp_arc, 103, ElementState::Pressed).is_ok());
     }
 }
\ No newline at end of file
@@ -0,0 +1,189 @@
+use std::sync::Arc;
+
+use crate::{cpu::cpu::{Cpu, CpuConfig}, mem::mem::Memory, display::display::Display, timer::timer::Timer,
+            audio::audio::AudioConfig, error::error::Chip8Error};
+
+// Bundles all the machine components together so that programs can be run
+// without going through main(). The display and timer are created in test
+// mode, so no window or audio device is created.
+pub struct Emulator {
+    pub cpu: Cpu,
+    pub mem: Memory,
+    pub disp: Arc<Display>,
+    pub timer: Arc<Timer>,
+    // Copy of the loaded program, so that it can be reloaded on reset.
+    program: Vec<u8>,
+    // Instructions executed per display frame, and how many have run in the current one.
+    cycles_per_frame: usize,
+    frame_cycles: usize,
+}
+
+// Same as the default speed in main().
+const DEFAULT_CYCLES_PER_FRAME: usize = 11;
+
+impl Emulator {
+    pub fn new(config: CpuConfig) -> Self {
+        Emulator {
+            cpu: Cpu::new(config),
+            mem: Memory::new(),
+            disp: Display::new(true),
+            timer: Timer::new(true, AudioConfig::default()),
+            program: Vec::new(),
+            cycles_per_frame: DEFAULT_CYCLES_PER_FRAME,
+            frame_cycles: 0,
+        }
+    }
+
+    // Change how many instructions make up a display frame. There is no real
+    // time here, so frames are counted in instructions instead.
+    pub fn set_cycles_per_frame(&mut self, cycles: usize) {
+        self.cycles_per_frame = std::cmp::max(1, cycles);
+    }
+
+    pub fn load_program(&mut self, program: &[u8]) -> Result<i32, Chip8Error> {
+        self.mem.load_program(program, self.cpu.start_addr().into())?;
+        self.program = program.to_vec();
+        return Ok(0);
+    }
+
+    // Restart the loaded program from a clean machine state.
+    pub fn reset(&mut self) -> Result<i32, Chip8Error> {
+        self.cpu.reset();
+        self.mem.reset(&self.program, self.cpu.start_addr().into())?;
+        Display::reset(&self.disp);
+        Timer::reset(&self.timer);
+        self.frame_cycles = 0;
+        return Ok(0);
+    }
+
+    // Fetch and decode a single instruction, and return the opcode which was executed.
+    pub fn step(&mut self) -> Result<u16, Chip8Error> {
+        let instr = self.cpu.fetch(&self.mem)?;
+        self.cpu.decode(instr, Some(&self.disp), Some(&mut self.mem), Some(&mut self.timer))?;
+
+        // Move on to the next frame, which lets a draw held back by the vblank
+        // quirk go ahead.
+        self.frame_cycles += 1;
+        if self.frame_cycles >= self.cycles_per_frame {
+            self.frame_cycles = 0;
+            Display::tick_frame(&self.disp);
+        }
+
+        return Ok(instr);
+    }
+
+    pub fn run_cycles(&mut self, n: usize) -> Result<i32, Chip8Error> {
+        for _ in 0..n {
+            self.step()?;
+        }
+
+        return Ok(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{cpu::cpu::CpuConfig, display::display::Display, timer::timer::Timer, audio::audio::DEFAULT_PITCH};
+
+    use super::{Emulator, Chip8Error};
+
+    #[test]
+    fn step() {
+        let mut emu = Emulator::new(CpuConfig::default());
+        // V0 = 0x5, V0 += 0x3, jump back to the start.
+        let program = vec![0x60, 0x05, 0x70, 0x03, 0x12, 0x00];
+        assert!(emu.load_program(&program).is_ok());
+
+        assert_eq!(emu.step().unwrap(), 0x6005);
+        assert_eq!(emu.cpu.registers()[0], 0x5);
+        assert_eq!(emu.step().unwrap(), 0x7003);
+        assert_eq!(emu.cpu.registers()[0], 0x8);
+        assert_eq!(emu.step().unwrap(), 0x1200);
+
+        // We should be back at the start of the program.
+        assert_eq!(emu.step().unwrap(), 0x6005);
+        assert_eq!(emu.cpu.registers()[0], 0x5);
+    }
+
+    #[test]
+    fn run_cycles() {
+        let mut emu = Emulator::new(CpuConfig::default());
+        let program = vec![0x60, 0x05, 0x70, 0x03, 0x12, 0x02];
+        assert!(emu.load_program(&program).is_ok());
+
+        // The loop keeps adding 0x3 to V0.
+        assert!(emu.run_cycles(7).is_ok());
+        assert_eq!(emu.cpu.registers()[0], 0x5 + 0x3 * 3);
+    }
+
+    #[test]
+    fn draw_wait_vblank() {
+        let mut emu = Emulator::new(CpuConfig { draw_wait_vblank: true, ..CpuConfig::default() });
+        emu.set_cycles_per_frame(4);
+        // Draw twice, then V0 = 0x5 and loop forever.
+        let program = vec![0xD0, 0x01, 0xD0, 0x01, 0x60, 0x05, 0x12, 0x06];
+        assert!(emu.load_program(&program).is_ok());
+
+        // The second draw waits out the rest of the first frame.
+        assert!(emu.run_cycles(4).is_ok());
+        assert_eq!(Display::frame_count(&emu.disp), 1);
+        assert_eq!(emu.cpu.pc(), 0x202);
+        assert!(emu.run_cycles(2).is_ok());
+        assert_eq!(emu.cpu.registers()[0], 0x5);
+    }
+
+    #[test]
+    fn reset() {
+        let mut emu = Emulator::new(CpuConfig::default());
+        // V0 = 0x42, I = 0x300, store V0 at I, then load the audio pattern
+        // from there and set the pitch to V0.
+        let program = vec![0x60, 0x42, 0xA3, 0x00, 0xF0, 0x55, 0xF0, 0x02, 0xF0, 0x3A];
+        assert!(emu.load_program(&program).is_ok());
+        assert!(emu.run_cycles(5).is_ok());
+        assert_eq!(emu.mem.read(0x300).unwrap(), 0x42);
+        assert!(Timer::get_audio_pattern(&emu.timer).is_some());
+        assert_eq!(Timer::get_pitch(&emu.timer), Some(0x42));
+
+        assert!(emu.reset().is_ok());
+        assert_eq!(emu.cpu.pc(), 0x200);
+        assert_eq!(emu.cpu.registers(), [0; 16]);
+        assert_eq!(emu.mem.read(0x300).unwrap(), 0);
+        assert_eq!(Timer::get_audio_pattern(&emu.timer), None);
+        assert_eq!(Timer::get_pitch(&emu.timer), Some(DEFAULT_PITCH));
+
+        // The program runs again from the start.
+        assert_eq!(emu.step().unwrap(), 0x6042);
+    }
+
+    #[test]
+    fn step_invalid() {
+        let mut emu = Emulator::new(CpuConfig::default());
+        let program = vec![0x80, 0x08, 0x80, 0x0F];
+        assert!(emu.load_program(&program).is_ok());
+
+        assert_eq!(emu.step(), Err(Chip8Error::UnknownOpcode(0x8008)));
+        assert!(emu.run_cycles(1).is_err());
+
+        // Unused memory decodes as 0000, which is ignored like any other 0NNN.
+        assert!(emu.run_cycles(3).is_ok());
+    }
+
+    #[test]
+    fn start_addr() {
+        let mut emu = Emulator::new(CpuConfig { start_addr: 0x600, ..CpuConfig::default() });
+        // V0 = 0x5, jump back to the start.
+        let program = vec![0x60, 0x05, 0x16, 0x00];
+        assert!(emu.load_program(&program).is_ok());
+        assert_eq!(emu.mem.read(0x600).unwrap(), 0x60);
+        assert_eq!(emu.mem.read(0x200).unwrap(), 0);
+
+        assert_eq!(emu.cpu.pc(), 0x600);
+        assert_eq!(emu.step().unwrap(), 0x6005);
+        assert_eq!(emu.step().unwrap(), 0x1600);
+        assert_eq!(emu.cpu.pc(), 0x600);
+
+        assert!(emu.reset().is_ok());
+        assert_eq!(emu.cpu.pc(), 0x600);
+        assert_eq!(emu.step().unwrap(), 0x6005);
+    }
+}
@@ -0,0 +1 @@
+pub mod emulator;
\ No newline at end of file
@@ -0,0 +1,58 @@
+use std::fmt;
+
+// Errors returned by the emulator components.
+#[derive(Debug, PartialEq)]
+pub enum Chip8Error {
+    InvalidAddress(usize),
+    // A write to the interpreter's memory, while it's protected.
+    ProtectedAddress(usize),
+    UnknownOpcode(u16),
+    StackOverflow,
+    StackUnderflow,
+    ProgramTooLarge,
+    InvalidKey(u8),
+    UnknownScancode(u32),
+    InvalidPlanes(u8),
+    InvalidRegister(u8),
+    // The reason the snapshot couldn't be restored.
+    InvalidSnapshot(String),
+    // Reading or writing a file failed, with the reason why.
+    Io(String),
+    // An instruction needs a machine component which wasn't provided.
+    MissingComponent(&'static str),
+}
+
+impl fmt::Display for Chip8Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Chip8Error::InvalidAddress(addr) => write!(f, "Invalid memory address: 0x{:X}", addr),
+            Chip8Error::ProtectedAddress(addr) => write!(f, "Write to protected interpreter memory: 0x{:03X}", addr),
+            Chip8Error::UnknownOpcode(instr) => write!(f, "Unknown instruction: 0x{:04X}", instr),
+            Chip8Error::StackOverflow => write!(f, "Stack overflow, can't call subroutine."),
+            Chip8Error::StackUnderflow => write!(f, "Trying to pop an empty stack, can't return."),
+            Chip8Error::ProgramTooLarge => write!(f, "Program is too large."),
+            Chip8Error::InvalidKey(key) => write!(f, "Invalid key provided: {}", key),
+            Chip8Error::UnknownScancode(scancode) => write!(f, "Invalid keypress: {}", scancode),
+            Chip8Error::InvalidPlanes(planes) => write!(f, "Invalid plane selection: {}", planes),
+            Chip8Error::InvalidRegister(idx) => write!(f, "Invalid register: V{}", idx),
+            Chip8Error::InvalidSnapshot(reason) => write!(f, "Invalid snapshot: {}", reason),
+            Chip8Error::Io(reason) => write!(f, "I/O error: {}", reason),
+            Chip8Error::MissingComponent(name) => write!(f, "Instruction requires the {}, which wasn't provided.", name),
+        }
+    }
+}
+
+impl std::error::Error for Chip8Error {}
+
+#[cfg(test)]
+mod tests {
+    use super::Chip8Error;
+
+    #[test]
+    fn display() {
+        assert_eq!(Chip8Error::InvalidAddress(0x1000).to_string(), "Invalid memory address: 0x1000");
+        assert_eq!(Chip8Error::UnknownOpcode(0x8008).to_string(), "Unknown instruction: 0x8008");
+        assert_eq!(Chip8Error::InvalidSnapshot(String::from("too short")).to_string(),
+                   "Invalid snapshot: too short");
+    }
+}
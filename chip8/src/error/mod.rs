@@ -0,0 +1 @@
+pub mod error;
\ No newline at end of file
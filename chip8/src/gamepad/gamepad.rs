@@ -0,0 +1,113 @@
+use std::sync::Arc;
+
+use sdl2::{controller::{Button, GameController}, event::Event, EventPump, GameControllerSubsystem};
+
+use crate::display::display::Display;
+
+// Reads game controllers through SDL, as a second input source next to the
+// keyboard. SDL has to be polled from the thread which initialized it, so
+// this is owned and polled by the emulation loop rather than a thread of its own.
+pub struct Gamepad {
+    subsystem: GameControllerSubsystem,
+    event_pump: EventPump,
+    controllers: Vec<GameController>,
+}
+
+impl Gamepad {
+    pub fn new() -> Result<Self, String> {
+        let sdl_context = sdl2::init()?;
+        let subsystem = sdl_context.game_controller()?;
+        let event_pump = sdl_context.event_pump()?;
+
+        // Controllers which are already connected are reported as added on the
+        // first poll, so they're all opened in the same place.
+        return Ok(Gamepad {
+            subsystem,
+            event_pump,
+            controllers: Vec::new(),
+        });
+    }
+
+    // The CHIP-8 key a controller button presses. The D-pad is laid out like
+    // the 2/4/6/8 keys most programs move with, and A is 5 which is commonly
+    // used to fire.
+    pub fn button_to_key(button: Button) -> Option<u8> {
+        match button {
+            Button::DPadUp => return Some(0x2),
+            Button::DPadLeft => return Some(0x4),
+            Button::DPadRight => return Some(0x6),
+            Button::DPadDown => return Some(0x8),
+            Button::A => return Some(0x5),
+            Button::B => return Some(0x0),
+            Button::X => return Some(0x7),
+            Button::Y => return Some(0x9),
+            Button::LeftShoulder => return Some(0x1),
+            Button::RightShoulder => return Some(0x3),
+            _ => return None,
+        }
+    }
+
+    // Handle the controller events since the last call, updating the same key
+    // state the keyboard does.
+    pub fn poll(&mut self, disp: &Arc<Display>) {
+        for event in self.event_pump.poll_iter() {
+            match event {
+                Event::ControllerDeviceAdded { which, .. } => {
+                    match self.subsystem.open(which) {
+                        Ok(controller) => {
+                            println!("Connected controller: {}", controller.name());
+                            self.controllers.push(controller);
+                        },
+                        Err(e) => eprintln!("Failed to open controller {}: {}", which, e),
+                    }
+                },
+                Event::ControllerDeviceRemoved { which, .. } => {
+                    self.controllers.retain(|controller| controller.instance_id() != which);
+                },
+                Event::ControllerButtonDown { button, .. } => {
+                    if let Some(key) = Gamepad::button_to_key(button) {
+                        let _ = Display::press_key(disp, key);
+                    }
+                },
+                Event::ControllerButtonUp { button, .. } => {
+                    if let Some(key) = Gamepad::button_to_key(button) {
+                        let _ = Display::release_key(disp, key);
+                    }
+                },
+                _ => {},
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sdl2::controller::Button;
+
+    use super::Gamepad;
+
+    #[test]
+    fn button_to_key() {
+        assert_eq!(Gamepad::button_to_key(Button::DPadUp), Some(0x2));
+        assert_eq!(Gamepad::button_to_key(Button::DPadLeft), Some(0x4));
+        assert_eq!(Gamepad::button_to_key(Button::DPadRight), Some(0x6));
+        assert_eq!(Gamepad::button_to_key(Button::DPadDown), Some(0x8));
+        assert_eq!(Gamepad::button_to_key(Button::A), Some(0x5));
+        assert_eq!(Gamepad::button_to_key(Button::Start), None);
+        assert_eq!(Gamepad::button_to_key(Button::Guide), None);
+
+        // Every mapped button presses a different, valid key.
+        let buttons = [
+            Button::A, Button::B, Button::X, Button::Y, Button::Back, Button::Guide,
+            Button::Start, Button::LeftStick, Button::RightStick, Button::LeftShoulder,
+            Button::RightShoulder, Button::DPadUp, Button::DPadDown, Button::DPadLeft,
+            Button::DPadRight,
+        ];
+        let mut keys: Vec<u8> = buttons.iter().filter_map(|button| Gamepad::button_to_key(*button)).collect();
+        assert!(keys.iter().all(|key| *key <= 0xF));
+        let mapped = keys.len();
+        keys.sort();
+        keys.dedup();
+        assert_eq!(keys.len(), mapped);
+    }
+}
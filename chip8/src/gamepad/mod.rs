@@ -0,0 +1 @@
+pub mod gamepad;
\ No newline at end of file
@@ -0,0 +1,11 @@
+pub mod mem;
+pub mod cpu;
+pub mod display;
+pub mod timer;
+pub mod audio;
+pub mod emulator;
+pub mod disasm;
+pub mod debugger;
+pub mod error;
+pub mod rom;
+pub mod gamepad;
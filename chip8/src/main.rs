@@ -1,28 +1,531 @@
-use std::{env, process::exit, time::Duration, thread};
+use std::{env, io::{self, Read, Write}, process::exit, sync::Arc, time::Duration};
 
-mod mem;
-use mem::mem::Memory;
+use chip8::mem::mem::{Memory, FONT_SIZE};
+use chip8::cpu::cpu::{Cpu, CpuConfig, BadOpcodePolicy, MemIncrement, OpcodeHistogram, RPL_FLAGS};
+use chip8::display::display::{Display, Rgb, DEFAULT_SCALE};
+use chip8::timer::timer::{Ticker, Timer};
+use chip8::audio::audio::{AudioConfig, Waveform};
+use chip8::disasm::disasm::{disassemble, disassemble_program};
+use chip8::debugger::debugger::{Command, Debugger};
+use chip8::error::error::Chip8Error;
+use chip8::rom::rom::{detect_platform, Platform};
+use chip8::gamepad::gamepad::Gamepad;
 
-mod cpu;
-use cpu::cpu::Cpu;
+// The CPU executes a batch of instructions every 60Hz frame, which keeps it in
+// step with the delay and sound timers.
+const FRAME_DURATION_US: u64 = 16666;
+const FRAMES_PER_SECOND: u64 = 60;
+const DEFAULT_CYCLES_PER_FRAME: u64 = 11;
+// How much faster instructions execute while the turbo key is held.
+const TURBO_MULTIPLIER: u64 = 8;
 
-mod display;
-use display::display::Display;
-
-mod timer;
-use timer::timer::Timer;
+// Front-end settings that aren't part of the CPU configuration.
+struct Options {
+    cycles_per_frame: u64,
+    xo_chip: bool,
+    // Fail writes to the memory below 0x200.
+    protect_interpreter: bool,
+    palette: Option<(Rgb, Rgb)>,
+    wrap_sprites: bool,
+    count_collision_rows: bool,
+    audio_config: AudioConfig,
+    debug: bool,
+    // Replaces the default 0-F font glyphs.
+    font: Option<[u8; FONT_SIZE]>,
+    // File the SUPER-CHIP RPL flags are persisted in.
+    rpl_file: Option<String>,
+    screenshot: Option<String>,
+    // File the memory is written to on exit, or when F2 is pressed.
+    dump_path: Option<String>,
+    fade: bool,
+    invert: bool,
+    // Draw to stdout instead of opening a window.
+    terminal: bool,
+    // Read keys from game controllers as well.
+    gamepad: bool,
+    scale: u32,
+    hud: bool,
+    mute: bool,
+    // Stop after executing this many instructions.
+    max_instructions: Option<u64>,
+    // Log every executed instruction, to |trace_file| if given or stderr otherwise.
+    trace: bool,
+    trace_file: Option<String>,
+    // Print how often each instruction was executed on exit.
+    profile: bool,
+}
 
-mod audio;
+impl Default for Options {
+    fn default() -> Self {
+        return Options {
+            cycles_per_frame: DEFAULT_CYCLES_PER_FRAME,
+            xo_chip: false,
+            protect_interpreter: false,
+            palette: None,
+            wrap_sprites: false,
+            count_collision_rows: false,
+            audio_config: AudioConfig::default(),
+            debug: false,
+            font: None,
+            rpl_file: None,
+            screenshot: None,
+            dump_path: None,
+            fade: false,
+            invert: false,
+            terminal: false,
+            gamepad: false,
+            scale: DEFAULT_SCALE,
+            hud: false,
+            mute: false,
+            max_instructions: None,
+            trace: false,
+            trace_file: None,
+            profile: false,
+        };
+    }
+}
 
 fn print_help_text() {
-    println!("Usage is \"cargo run <filepath> <options>\"");
+    println!("Usage is \"cargo run <filepath>... <options>\"");
+    println!("Use - as the filepath to read the program from stdin. When several programs are given,");
+    println!("the first one is run and Ctrl+1 to Ctrl+9 switch between them.");
     println!("List of options:");
+    println!("--quirks <P> : Use the quirks of a platform, one of chip8, schip or xochip. Individual");
+    println!("               quirk flags can be given as well to enable additional quirks, or turned");
+    println!("               off with --no-<quirk> or --<quirk>=false. Use auto to guess the platform");
+    println!("               from the (first) program.");
     println!("--memory_quirk : Increment register I after load/store operations.");
+    println!("--memory-increment <M> : How load/store operations update I, one of unchanged, x or x+1.");
     println!("--vf_reset_quirk : Clear VF after AND/OR/XOR instructions.");
-    println!("--shift_quirk : Shift operations act on VY loaded into VX.")
+    println!("--shift_quirk : Shift operations act on VY loaded into VX.");
+    println!("--jump_quirk : Jump with offset (BXNN) uses VX instead of V0.");
+    println!("--fx1e_vf_quirk : Adding to I (FX1E) sets VF when I goes past 0xFFF.");
+    println!("--vblank_quirk : Limit drawing to one sprite per 60Hz frame.");
+    println!("--wrap_quirk : Sprites wrap around the screen edges instead of being clipped.");
+    println!("--collision_rows_quirk : Drawing sets VF to the number of sprite rows which collided, not just 1.");
+    println!("--on-bad-op <P> : On an unknown instruction, halt, skip it or error out (default is error).");
+    println!("--halt-on-self-jump : Halt when the program jumps to itself, instead of letting it spin.");
+    println!("--key-timeout <N> : Give up waiting for a key press after N instructions, setting VX to FF.");
+    println!("--clock-hz <N> : Execute roughly N instructions per second (default is 660).");
+    println!("--cycles-per-frame <N> : Execute N instructions per 60Hz frame (default is 11).");
+    println!("--beep-hz <N> : Frequency of the beep in Hz (default is 440).");
+    println!("--volume <V> : Volume of the beep, between 0.0 and 1.0 (default is 0.25).");
+    println!("--mute : Start with the sound muted.");
+    println!("--waveform <W> : Waveform of the beep, one of square, sine, triangle or noise (default is square).");
+    println!("--color <fg>,<bg> : Render using the given RRGGBB hex colors, e.g. FFB000,000000.");
+    println!("--start-addr <addr> : Load and start the program at the given hex address (default is 200).");
+    println!("--xo-chip : Use the extended 64KB XO-CHIP memory.");
+    println!("--protect-interpreter : Stop with an error when the program writes below 0x200.");
+    println!("--font <path> : Use the 80 byte 0-F font in the given file instead of the default one.");
+    println!("--rpl-file <path> : Load and save the SUPER-CHIP RPL user flags in the given file.");
+    println!("--fade : Fade out pixels over a few frames to reduce flicker.");
+    println!("--invert : Render dark sprites on a lit background.");
+    println!("--terminal : Draw the screen in the terminal instead of a window, reading keys from stdin.");
+    println!("--gamepad : Read keys from game controllers too. The D-pad presses 2/4/6/8, A is 5, B is 0,");
+    println!("            X is 7, Y is 9 and the left and right shoulders are 1 and 3.");
+    println!("--scale <N> : Open the window N times larger than the 64x32 screen (default is 10).");
+    println!("--hud : Show the frames per second and the pressed keys in the top left corner.");
+    println!("--screenshot <path> : Press F12 while running to save the screen as a PGM image.");
+    println!("--dump-on-exit <path> : Save the memory to the given file on exit, or when F2 is pressed.");
+    println!("--debug : Single step through the program. At the prompt, enter s to step, c to continue,");
+    println!("          q to quit, b <addr> to set a breakpoint at a hex address, or g <addr> to");
+    println!("          continue from a hex address.");
+    println!("--max-instructions <N> : Exit after executing N instructions, printing the final state.");
+    println!("--trace [path] : Log every executed instruction to the given file, or stderr.");
+    println!("--profile : Print how many times each instruction was executed on exit.");
+    println!("--disassemble : Print the disassembled program instead of running it.");
+    println!("Press P while running to pause/resume the emulation.");
+    println!("Press M while running to mute/unmute the sound.");
+    println!("Press F5 while running to reset the machine.");
+    println!("Hold Tab while running to fast-forward, the timers keep running at 60Hz.");
+}
+
+// Parse the value of a numeric option, bailing out if it isn't a positive integer.
+fn parse_positive_arg(option: &str, val: Option<&String>) -> u64 {
+    match val.and_then(|val| val.parse::<u64>().ok()) {
+        Some(n) if n > 0 => return n,
+        _ => {
+            eprintln!("{} requires a positive integer.", option);
+            print_help_text();
+            exit(1);
+        }
+    }
+}
+
+// Convert a clock rate to the whole number of instructions run each frame, at least one.
+fn clock_hz_cycles(hz: u64) -> u64 {
+    return std::cmp::max(1, hz / FRAMES_PER_SECOND);
+}
+
+// Parse a hex address, e.g. 600 or 0x600, which has to fit in the 16 bit address space.
+fn parse_addr_arg(option: &str, val: Option<&String>) -> u16 {
+    let addr = val.map(|val| val.trim_start_matches("0x"))
+        .and_then(|val| u16::from_str_radix(val, 16).ok());
+    match addr {
+        Some(addr) => return addr,
+        None => {
+            eprintln!("{} requires a hex address.", option);
+            print_help_text();
+            exit(1);
+        }
+    }
+}
+
+fn parse_path_arg(option: &str, val: Option<&String>) -> String {
+    match val {
+        Some(path) => return path.clone(),
+        None => {
+            eprintln!("{} requires a path.", option);
+            print_help_text();
+            exit(1);
+        }
+    }
+}
+
+// Read the font glyphs from the file at |path|, which has to hold exactly FONT_SIZE bytes.
+fn read_font(path: &str) -> Result<[u8; FONT_SIZE], Chip8Error> {
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) => return Err(Chip8Error::Io(format!("{}: {}", path, e))),
+    };
+
+    let len = bytes.len();
+    return bytes.try_into().map_err(|_| {
+        Chip8Error::Io(format!("{}: font is {} bytes instead of {}", path, len, FONT_SIZE))
+    });
+}
+
+fn parse_font_arg(option: &str, val: Option<&String>) -> [u8; FONT_SIZE] {
+    match read_font(&parse_path_arg(option, val)) {
+        Ok(font) => return font,
+        Err(e) => {
+            eprintln!("{} failed: {}", option, e);
+            exit(1);
+        }
+    }
+}
+
+// Parse the beep volume, which has to lie between 0.0 and 1.0.
+fn parse_volume_arg(option: &str, val: Option<&String>) -> f32 {
+    match val.and_then(|val| val.parse::<f32>().ok()) {
+        Some(volume) if (0.0..=1.0).contains(&volume) => return volume,
+        _ => {
+            eprintln!("{} requires a value between 0.0 and 1.0.", option);
+            print_help_text();
+            exit(1);
+        }
+    }
+}
+
+fn parse_waveform_arg(option: &str, val: Option<&String>) -> Waveform {
+    match val.map(|val| val.as_str()) {
+        Some("square") => return Waveform::Square,
+        Some("sine") => return Waveform::Sine,
+        Some("triangle") => return Waveform::Triangle,
+        Some("noise") => return Waveform::Noise,
+        _ => {
+            eprintln!("{} requires one of square, sine, triangle or noise.", option);
+            print_help_text();
+            exit(1);
+        }
+    }
+}
+
+fn parse_bad_op_arg(option: &str, val: Option<&String>) -> BadOpcodePolicy {
+    match val.map(|val| val.as_str()) {
+        Some("halt") => return BadOpcodePolicy::Halt,
+        Some("skip") => return BadOpcodePolicy::Skip,
+        Some("error") => return BadOpcodePolicy::Error,
+        _ => {
+            eprintln!("{} requires one of halt, skip or error.", option);
+            print_help_text();
+            exit(1);
+        }
+    }
+}
+
+fn parse_mem_increment_arg(option: &str, val: Option<&String>) -> MemIncrement {
+    match val.map(|val| val.as_str()) {
+        Some("unchanged") => return MemIncrement::Unchanged,
+        Some("x") => return MemIncrement::IncrementByX,
+        Some("x+1") => return MemIncrement::IncrementByXPlus1,
+        _ => {
+            eprintln!("{} requires one of unchanged, x or x+1.", option);
+            print_help_text();
+            exit(1);
+        }
+    }
+}
+
+// Parse a "RRGGBB,RRGGBB" pair of foreground and background colors.
+fn parse_color_arg(option: &str, val: Option<&String>) -> (Rgb, Rgb) {
+    let parse_rgb = |color: &str| -> Option<Rgb> {
+        if color.len() != 6 {
+            return None;
+        }
+
+        let val = u32::from_str_radix(color, 16).ok()?;
+        return Some([(val >> 16) as u8, (val >> 8) as u8, val as u8]);
+    };
+
+    let colors = val.and_then(|val| val.split_once(','))
+        .and_then(|(fg, bg)| Some((parse_rgb(fg)?, parse_rgb(bg)?)));
+    match colors {
+        Some(colors) => return colors,
+        None => {
+            eprintln!("{} requires a pair of RRGGBB hex colors.", option);
+            print_help_text();
+            exit(1);
+        }
+    }
+}
+
+// Settings making up a --quirks profile.
+struct QuirksProfile {
+    config: CpuConfig,
+    // Whether sprites wrap around the screen edges instead of being clipped.
+    wrap_sprites: bool,
+    // Whether the platform has the 64KB XO-CHIP memory and instructions.
+    xo_chip: bool,
+}
+
+// Quirk settings matching the named platform, following the community quirks
+// tables.
+fn quirks_profile(name: &str) -> Option<QuirksProfile> {
+    let default = CpuConfig::default();
+    match name {
+        "chip8" => return Some(QuirksProfile {
+            config: CpuConfig {
+                mem_increment: MemIncrement::IncrementByXPlus1,
+                logic_resets_vf: true,
+                draw_wait_vblank: true,
+                ..default
+            },
+            wrap_sprites: false,
+            xo_chip: false,
+        }),
+        "schip" => return Some(QuirksProfile {
+            config: CpuConfig {
+                shift_uses_vy: true,
+                jump_quirk: true,
+                ..default
+            },
+            wrap_sprites: false,
+            xo_chip: false,
+        }),
+        "xochip" => return Some(QuirksProfile {
+            config: CpuConfig {
+                mem_increment: MemIncrement::IncrementByXPlus1,
+                ..default
+            },
+            wrap_sprites: true,
+            xo_chip: true,
+        }),
+        _ => return None,
+    }
+}
+
+// Quirk flags can be turned off with a --no- prefix or an =false suffix, to
+// override a profile. Returns the plain flag and whether it's being enabled.
+fn quirk_override(arg: &str) -> (String, bool) {
+    if let Some(name) = arg.strip_prefix("--no-") {
+        return (format!("--{}", name), false);
+    }
+    if let Some(flag) = arg.strip_suffix("=false") {
+        return (flag.to_string(), false);
+    }
+    if let Some(flag) = arg.strip_suffix("=true") {
+        return (flag.to_string(), true);
+    }
+    return (arg.to_string(), true);
+}
+
+// Apply the quirk |flag|, returning false if it isn't a quirk flag.
+fn set_quirk(flag: &str, enabled: bool, config: &mut CpuConfig, options: &mut Options) -> bool {
+    match flag {
+        "--memory_quirk" => config.mem_increment = if enabled {
+            MemIncrement::IncrementByXPlus1
+        } else {
+            MemIncrement::Unchanged
+        },
+        "--vf_reset_quirk" => config.logic_resets_vf = enabled,
+        "--shift_quirk" => config.shift_uses_vy = enabled,
+        "--jump_quirk" => config.jump_quirk = enabled,
+        "--fx1e_vf_quirk" => config.fx1e_sets_vf = enabled,
+        "--vblank_quirk" => config.draw_wait_vblank = enabled,
+        "--wrap_quirk" => options.wrap_sprites = enabled,
+        "--collision_rows_quirk" => options.count_collision_rows = enabled,
+        _ => return false,
+    }
+    return true;
+}
+
+// Guess the platform of the program at |path|, for --quirks auto.
+fn detect_rom_platform(path: &str) -> Option<Platform> {
+    // Reading stdin here would leave nothing for the emulator to run.
+    if path == "-" {
+        eprintln!("Can't detect the platform of a program read from stdin, using the defaults.");
+        return None;
+    }
+
+    // Failing to read the program is reported once it gets loaded.
+    let bytes = std::fs::read(path).ok()?;
+    let platform = detect_platform(&bytes);
+    println!("Detected platform: {}", platform.name());
+    return Some(platform);
+}
+
+// Lines describing the instructions counted in |histogram|, skipping the ones
+// which never ran.
+fn format_histogram(histogram: &OpcodeHistogram) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    for (family, count) in histogram.families.iter().enumerate() {
+        if *count > 0 {
+            lines.push(format!("{:X}___: {}", family, count));
+        }
+    }
+    for (op, count) in histogram.logic.iter().enumerate() {
+        if *count > 0 {
+            lines.push(format!("8XY{:X}: {}", op, count));
+        }
+    }
+    let mut misc: Vec<(&u8, &u64)> = histogram.misc.iter().collect();
+    misc.sort();
+    for (op, count) in misc {
+        lines.push(format!("FX{:02X}: {}", op, count));
+    }
+
+    return lines;
+}
+
+fn dump_memory(mem: &Memory, path: &str) {
+    match std::fs::write(path, mem.dump()) {
+        Ok(_) => println!("Saved memory to {}", path),
+        Err(e) => eprintln!("Failed to save memory: {}", e),
+    }
+}
+
+// Save and report everything asked for once the frame loop stops, however
+// it was stopped.
+fn finish_run(disp: &Arc<Display>, mem: &Memory, cpu: &Cpu, options: &Options, trace: &mut Option<Box<dyn Write>>) {
+    // Show whatever was drawn in the frame we stopped in.
+    Display::flush(disp);
+
+    if let Some(path) = &options.dump_path {
+        dump_memory(mem, path);
+    }
+
+    if let Some(histogram) = cpu.opcode_histogram() {
+        println!("Executed instructions:");
+        for line in format_histogram(histogram) {
+            println!("  {}", line);
+        }
+    }
+
+    if let Some(out) = trace {
+        if let Err(e) = out.flush() {
+            eprintln!("Failed to write trace: {}", e);
+        }
+    }
+}
+
+// Whether the emulation should stop, after having executed |executed| instructions.
+fn instruction_limit_reached(executed: u64, max_instructions: Option<u64>) -> bool {
+    match max_instructions {
+        Some(max) => return executed >= max,
+        None => return false,
+    }
+}
+
+// Log an executed instruction to the trace.
+fn trace_instruction(out: &mut dyn Write, pc: u16, instr: u16) -> io::Result<()> {
+    return writeln!(out, "PC:{:03X} OP:{:04X} {}", pc, instr, disassemble(instr));
+}
+
+// Program paths come first, followed by the options. Returns the paths along
+// with the remaining arguments.
+fn split_rom_paths(args: &[String]) -> (Vec<String>, &[String]) {
+    let count = args.iter().take_while(|arg| !arg.starts_with("--")).count();
+    return (args[..count].to_vec(), &args[count..]);
+}
+
+// Read the program from |path|, or from |stdin| if the path is "-".
+fn read_program(path: &str, mut stdin: impl Read) -> io::Result<Vec<u8>> {
+    if path == "-" {
+        let mut program: Vec<u8> = Vec::new();
+        stdin.read_to_end(&mut program)?;
+        return Ok(program);
+    }
+
+    return std::fs::read(path);
+}
+
+// Load the program at |path| into |mem|, returning it so that it can be loaded
+// again on reset.
+fn load_rom(mem: &mut Memory, path: &str, addr: usize) -> Result<Vec<u8>, Chip8Error> {
+    if path == "-" {
+        let program = match read_program(path, io::stdin()) {
+            Ok(program) => program,
+            Err(e) => return Err(Chip8Error::Io(format!("stdin: {}", e))),
+        };
+        mem.load_program(&program, addr)?;
+        return Ok(program);
+    }
+
+    let len = mem.load_program_from_file(path, addr)?;
+    return mem.dump_range(addr, len);
+}
+
+// Stdin can only be read once, so it can't be given as more than one program.
+fn stdin_repeated(paths: &[String]) -> bool {
+    return paths.iter().filter(|path| *path == "-").count() > 1;
+}
+
+// What the main loop should do once the debugger prompt returns.
+#[derive(PartialEq)]
+enum PromptResult {
+    // Run the fetched instruction.
+    Resume,
+    // The PC was moved, so the fetched instruction shouldn't run.
+    Moved,
+    Quit,
+    // Reading the prompt failed, so stop with an error.
+    Failed,
+}
+
+// Block on the debugger prompt until the user asks to step, continue or quit.
+fn debugger_prompt(debugger: &mut Debugger, cpu: &mut Cpu, mem: &Memory) -> PromptResult {
+    loop {
+        let mut line = String::new();
+        match io::stdin().read_line(&mut line) {
+            // Treat end of input the same as quitting.
+            Ok(0) => return PromptResult::Quit,
+            Err(e) => {
+                eprintln!("Failed to read command: {}", e);
+                return PromptResult::Failed;
+            },
+            _ => {},
+        }
+
+        match Debugger::parse_command(&line) {
+            Ok(Command::Quit) => return PromptResult::Quit,
+            Ok(Command::Breakpoint(addr)) => match debugger.apply(&Command::Breakpoint(addr), mem) {
+                Ok(_) => println!("Breakpoint set at 0x{:03X}", addr),
+                Err(e) => eprintln!("{}", e),
+            },
+            Ok(Command::Goto(addr)) => match cpu.set_pc(addr, mem) {
+                Ok(_) => return PromptResult::Moved,
+                Err(e) => eprintln!("{}", e),
+            },
+            Ok(cmd) => match debugger.apply(&cmd, mem) {
+                Ok(_) => return PromptResult::Resume,
+                Err(e) => eprintln!("{}", e),
+            },
+            Err(e) => eprintln!("{}", e),
+        }
+    }
 }
 
-#[show_image::main]
 fn main() {
     let args: Vec<String> = env::args().collect();
     
@@ -32,24 +535,95 @@ fn main() {
         exit(1);
     }
 
-    // Read file into a vector.
-    let program = match std::fs::read(&args[1]) {
-        Ok(program) => program,
-        Err(_) => {
-            println!("Couldn't read file");
-            exit(1);
-        },
-    };
+    let (paths, flags) = split_rom_paths(&args[1..]);
+    if paths.is_empty() {
+        println!("No program given.");
+        print_help_text();
+        exit(1);
+    }
+    if stdin_repeated(&paths) {
+        println!("- can only be given once, stdin can't be read again.");
+        exit(1);
+    }
 
-    let mut memory_quirk = false;
-    let mut vf_reset_quirk = false;
-    let mut shift_quirk = true;
+    let mut config = CpuConfig::default();
+    let mut disassemble = false;
+    let mut options = Options::default();
+
+    // Apply the quirks profile first, so that individual quirk flags add to it
+    // regardless of where they appear.
+    if let Some(ind) = flags.iter().position(|arg| arg == "--quirks") {
+        let profile = match flags.get(ind + 1).map(|name| name.as_str()) {
+            Some("auto") => match detect_rom_platform(&paths[0]) {
+                Some(platform) => quirks_profile(platform.name()),
+                None => Some(QuirksProfile { config: CpuConfig::default(), wrap_sprites: false, xo_chip: false }),
+            },
+            Some(name) => quirks_profile(name),
+            None => None,
+        };
+        match profile {
+            Some(profile) => {
+                config = profile.config;
+                options.wrap_sprites = profile.wrap_sprites;
+                options.xo_chip = profile.xo_chip;
+            },
+            None => {
+                eprintln!("--quirks requires one of chip8, schip, xochip or auto.");
+                print_help_text();
+                exit(1);
+            },
+        }
+    }
+
+    let mut args_iter = flags.iter().peekable();
+    while let Some(arg) = args_iter.next() {
+        let (quirk, enabled) = quirk_override(arg);
+        if set_quirk(&quirk, enabled, &mut config, &mut options) {
+            continue;
+        }
 
-    for arg in &args[2..] {
         match arg.as_str() {
-            "--memory_quirk" => memory_quirk = true,
-            "--vf_reset_quirk" => vf_reset_quirk = true,
-            "--shift_quirk" => shift_quirk = true,
+            "--quirks" => { args_iter.next(); },
+            "--memory-increment" => config.mem_increment = parse_mem_increment_arg(arg, args_iter.next()),
+            "--clock-hz" => {
+                let hz = parse_positive_arg(arg, args_iter.next());
+                options.cycles_per_frame = clock_hz_cycles(hz);
+                let effective = options.cycles_per_frame * FRAMES_PER_SECOND;
+                if effective != hz {
+                    eprintln!("--clock-hz {} isn't a whole number of instructions per frame, running at {}Hz instead.", hz, effective);
+                }
+            },
+            "--cycles-per-frame" => options.cycles_per_frame = parse_positive_arg(arg, args_iter.next()),
+            "--disassemble" => disassemble = true,
+            "--debug" => options.debug = true,
+            "--trace" => {
+                options.trace = true;
+                // The path is optional, so only take the next argument if it isn't a flag.
+                options.trace_file = args_iter.next_if(|val| !val.starts_with("--")).cloned();
+            },
+            "--profile" => options.profile = true,
+            "--max-instructions" => options.max_instructions = Some(parse_positive_arg(arg, args_iter.next())),
+            "--font" => options.font = Some(parse_font_arg(arg, args_iter.next())),
+            "--rpl-file" => options.rpl_file = Some(parse_path_arg(arg, args_iter.next())),
+            "--fade" => options.fade = true,
+            "--invert" => options.invert = true,
+            "--terminal" => options.terminal = true,
+            "--gamepad" => options.gamepad = true,
+            "--hud" => options.hud = true,
+            "--scale" => options.scale = parse_positive_arg(arg, args_iter.next()) as u32,
+            "--mute" => options.mute = true,
+            "--screenshot" => options.screenshot = Some(parse_path_arg(arg, args_iter.next())),
+            "--dump-on-exit" => options.dump_path = Some(parse_path_arg(arg, args_iter.next())),
+            "--xo-chip" => options.xo_chip = true,
+            "--protect-interpreter" => options.protect_interpreter = true,
+            "--on-bad-op" => config.on_unknown_opcode = parse_bad_op_arg(arg, args_iter.next()),
+            "--halt-on-self-jump" => config.halt_on_self_jump = true,
+            "--key-timeout" => config.key_timeout = Some(parse_positive_arg(arg, args_iter.next())),
+            "--start-addr" => config.start_addr = parse_addr_arg(arg, args_iter.next()),
+            "--beep-hz" => options.audio_config.freq = parse_positive_arg(arg, args_iter.next()) as f32,
+            "--volume" => options.audio_config.volume = parse_volume_arg(arg, args_iter.next()),
+            "--waveform" => options.audio_config.waveform = parse_waveform_arg(arg, args_iter.next()),
+            "--color" => options.palette = Some(parse_color_arg(arg, args_iter.next())),
             _ => {
                     eprintln!("Invalid param: {}", arg);
                     print_help_text();
@@ -58,38 +632,441 @@ fn main() {
         }
     }
 
-    println!("Read in program of size: {} bytes", program.len());
+    if disassemble {
+        let program = match read_program(&paths[0], io::stdin()) {
+            Ok(program) => program,
+            Err(_) => {
+                println!("Couldn't read file");
+                exit(1);
+            },
+        };
+        for line in disassemble_program(&program, config.start_addr.into()) {
+            println!("{}", line);
+        }
+        exit(0);
+    }
 
-    let mut mem = Memory::new();
-    match mem.load_program(&program) {
-        Err(e) => println!("Load failed: {}", e),
-        _ => {},
+    // Terminal mode reads the keys from stdin, so nothing else can use it.
+    if options.terminal && (options.debug || paths.iter().any(|path| path == "-")) {
+        println!("--terminal reads keys from stdin, so it can't be used with --debug or - as the program.");
+        exit(1);
     }
 
-    let disp = Display::new(false);
+    if options.terminal {
+        run(paths, config, options);
+    } else {
+        // Only set up the windowing context once we know we're going to emulate.
+        show_image::run_context(move || run(paths, config, options));
+    }
+}
 
-    let mut cpu = Cpu::new(memory_quirk, vf_reset_quirk, shift_quirk);
+fn run(paths: Vec<String>, config: CpuConfig, options: Options) {
+    let mut mem = if options.xo_chip { Memory::new_xo_chip() } else { Memory::new() };
+    mem.set_protect_interpreter(options.protect_interpreter);
+    let addr: usize = config.start_addr.into();
+    // All the programs are read in up front, so that they can be switched
+    // between and reloaded on reset.
+    let mut roms: Vec<Vec<u8>> = Vec::new();
+    for path in paths.iter() {
+        match load_rom(&mut mem, path, addr) {
+            Ok(program) => roms.push(program),
+            Err(e) => {
+                println!("Load failed: {}", e);
+                exit(1);
+            },
+        }
+    }
+    // Start over with just the first program loaded.
+    let mut current = 0;
+    if let Err(e) = mem.reset(&roms[current], addr) {
+        println!("Load failed: {}", e);
+        exit(1);
+    }
+    if let Some(font) = &options.font {
+        mem.load_custom_font(font);
+    }
+    println!("Read in program of size: {} bytes", roms[current].len());
 
-    let mut timers = Timer::new(false);
-    // main loop
-    loop {
-        let instr = match cpu.fetch(&mem) {
-            Ok(instr) => instr,
+    let disp = if options.terminal {
+        Display::new_terminal()
+    } else {
+        Display::with_scale(false, options.scale)
+    };
+    if let Some((fg, bg)) = options.palette {
+        Display::set_palette(&disp, fg, bg);
+    }
+    Display::set_wrap_sprites(&disp, options.wrap_sprites);
+    Display::set_count_collision_rows(&disp, options.count_collision_rows);
+    Display::set_screenshot_path(&disp, options.screenshot.clone());
+    Display::set_fade(&disp, options.fade);
+    // Only hand the frame over to the display thread once per frame.
+    Display::set_batched(&disp, true);
+    Display::set_inverted(&disp, options.invert);
+    Display::set_muted(&disp, options.mute);
+    if options.hud {
+        Display::set_hud(&disp, Some(mem.font_glyphs()));
+    }
+
+    let mut cpu = Cpu::new(config);
+    cpu.set_profiling(options.profile);
+    // A missing or malformed flags file just leaves the flags cleared.
+    if let Some(path) = &options.rpl_file {
+        if let Some(flags) = std::fs::read(path).ok().and_then(|flags| flags.try_into().ok()) {
+            cpu.set_rpl_flags(flags);
+        }
+    }
+    let mut saved_rpl: [u8; RPL_FLAGS] = cpu.rpl_flags();
+
+    let mut timers = Timer::new(false, options.audio_config);
+    // Carry on with just the keyboard if the controllers can't be read.
+    let mut gamepad = if !options.gamepad {
+        None
+    } else {
+        match Gamepad::new() {
+            Ok(gamepad) => Some(gamepad),
             Err(e) => {
-                println!("Fetch failed: {}", e);
-                break;
+                eprintln!("Failed to set up game controllers: {}", e);
+                None
+            },
+        }
+    };
+    let mut debugger = if options.debug { Some(Debugger::new()) } else { None };
+    let frame_duration = Duration::from_micros(FRAME_DURATION_US);
+    let mut ticker = Ticker::new(frame_duration);
+    let mut executed: u64 = 0;
+    let mut trace: Option<Box<dyn Write>> = if !options.trace {
+        None
+    } else if let Some(path) = &options.trace_file {
+        match std::fs::File::create(path) {
+            Ok(file) => Some(Box::new(io::BufWriter::new(file))),
+            Err(e) => {
+                eprintln!("Failed to create trace file {}: {}", path, e);
+                exit(1);
             },
+        }
+    } else {
+        Some(Box::new(io::stderr()))
+    };
+    // Leaving the loop is an error, unless we were asked to stop.
+    let mut exit_code = 1;
+    // main loop
+    'frames: loop {
+        if Display::quit_requested(&disp) {
+            exit_code = 0;
+            break 'frames;
+        }
+
+        // The window keeps being serviced by the display thread while paused.
+        let paused = Display::is_paused(&disp);
+        Timer::set_paused(&timers, paused);
+        Timer::set_muted(&timers, Display::is_muted(&disp));
+        Display::tick_frame(&disp);
+        if let Some(gamepad) = &mut gamepad {
+            gamepad.poll(&disp);
+        }
+
+        if Display::take_dump_request(&disp) {
+            if let Some(path) = &options.dump_path {
+                dump_memory(&mem, path);
+            } else {
+                println!("F2 saves the memory only when --dump-on-exit <path> is given.");
+            }
+        }
+
+        // Switching programs resets the machine with the new one loaded.
+        let switch = Display::take_rom_request(&disp).filter(|ind| *ind < roms.len());
+        if let Some(ind) = switch {
+            current = ind;
+            println!("Switched to {}", paths[current]);
+        }
+
+        if Display::take_reset_request(&disp) || switch.is_some() {
+            cpu.reset();
+            if let Err(e) = mem.reset(&roms[current], addr) {
+                println!("Reset failed: {}", e);
+                break 'frames;
+            }
+            if let Some(font) = &options.font {
+                mem.load_custom_font(font);
+            }
+            Display::reset(&disp);
+            Timer::reset(&timers);
+        }
+
+        // A halted program stays on screen, with the timers and window still
+        // running, until it's reset or the window is closed.
+        let cycles = if paused || cpu.is_halted() {
+            0
+        } else if Display::turbo_active(&disp) {
+            options.cycles_per_frame * TURBO_MULTIPLIER
+        } else {
+            options.cycles_per_frame
         };
 
-        match cpu.decode(instr, Some(&disp), Some(&mut mem), Some(&mut timers)) {
-            Err(e) => {
+        for _ in 0..cycles {
+            let instr = match cpu.fetch(&mem) {
+                Ok(instr) => instr,
+                Err(e) => {
+                    println!("Fetch failed: {}", e);
+                    break 'frames;
+                },
+            };
+
+            // The PC has already moved past the fetched instruction.
+            let addr = cpu.pc().wrapping_sub(2);
+            if let Some(out) = &mut trace {
+                if let Err(e) = trace_instruction(out, addr, instr) {
+                    eprintln!("Failed to write trace: {}", e);
+                    trace = None;
+                }
+            }
+
+            if let Some(debugger) = &mut debugger {
+                if debugger.should_break(addr) {
+                    // The window keeps rendering while we wait at the prompt.
+                    Timer::set_paused(&timers, true);
+                    Display::flush(&disp);
+                    println!("{}", Debugger::format_state(addr, instr, &cpu.registers()));
+                    let result = debugger_prompt(debugger, &mut cpu, &mem);
+                    match result {
+                        PromptResult::Quit => {
+                            exit_code = 0;
+                            break 'frames;
+                        },
+                        PromptResult::Failed => break 'frames,
+                        _ => {},
+                    }
+                    Timer::set_paused(&timers, false);
+                    // Keep the timers still while single-stepping.
+                    if debugger.is_stepping() {
+                        Timer::freeze(&timers);
+                    } else {
+                        Timer::unfreeze(&timers);
+                    }
+                    // Fetch from the new address instead, stopping there when stepping.
+                    if result == PromptResult::Moved {
+                        continue;
+                    }
+                }
+            }
+
+            if let Err(e) = cpu.decode(instr, Some(&disp), Some(&mut mem), Some(&mut timers)) {
                 println!("Decode failed: {}", e);
+                break 'frames;
+            }
+            if let Some(op) = cpu.take_skipped_opcode() {
+                eprintln!("Skipping unknown instruction 0x{:04X}", op);
+            }
+
+            executed += 1;
+            if instruction_limit_reached(executed, options.max_instructions) {
+                println!("Stopped after {} instructions, {} cycles. I=0x{:03X}, next PC=0x{:03X}",
+                         executed, cpu.cycle_count(), cpu.index(), cpu.pc());
+                println!("{}", Debugger::format_state(addr, instr, &cpu.registers()));
+                exit_code = 0;
+                break 'frames;
+            }
+
+            if cpu.is_halted() {
+                println!("Program halted at 0x{:03X}. Close the window to exit.", cpu.pc());
                 break;
-            },
-            _ => {},
-        };
-        thread::sleep(Duration::from_micros(1400));
+            }
+        }
+
+        Display::flush(&disp);
+
+        // Write out the RPL flags whenever the program has changed them.
+        if let Some(path) = &options.rpl_file {
+            if cpu.rpl_flags() != saved_rpl {
+                saved_rpl = cpu.rpl_flags();
+                if let Err(e) = std::fs::write(path, saved_rpl) {
+                    eprintln!("Failed to save RPL flags: {}", e);
+                }
+            }
+        }
+
+        // Sleep until the start of the next frame.
+        ticker.sleep();
+    }
+
+    finish_run(&disp, &mem, &cpu, &options, &mut trace);
+    Timer::shutdown(&timers);
+    Display::shutdown(&disp);
+    exit(exit_code);
+}
+
+#[cfg(test)]
+mod tests {
+    use chip8::{cpu::cpu::{Cpu, CpuConfig, MemIncrement}, mem::mem::{Memory, FONT_SIZE, PROGRAM_ADDRESS}, error::error::Chip8Error};
+    use chip8::display::display::Display;
+
+    use super::{clock_hz_cycles, finish_run, Options, read_program, read_font, load_rom, stdin_repeated, split_rom_paths, quirks_profile, quirk_override, format_histogram, instruction_limit_reached, trace_instruction};
+
+    #[test]
+    fn read_program_stdin() {
+        let rom: &[u8] = &[0x00, 0xE0, 0x12, 0x00];
+        assert_eq!(read_program("-", rom).unwrap(), rom);
+
+        // Regular paths don't touch stdin.
+        assert!(read_program("/nonexistent/rom.ch8", rom).is_err());
+    }
+
+    #[test]
+    fn load_rom_file() {
+        let path = std::env::temp_dir().join(format!("chip8-rom-test-{}.ch8", std::process::id()));
+        let path_str = path.to_str().unwrap();
+        let addr = PROGRAM_ADDRESS;
+
+        let rom = [0x00, 0xE0, 0x12, 0x00];
+        std::fs::write(&path, rom).unwrap();
+        let mut mem = Memory::new();
+        assert_eq!(load_rom(&mut mem, path_str, addr), Ok(rom.to_vec()));
+        assert_eq!(mem.dump_range(addr, rom.len()), Ok(rom.to_vec()));
+
+        // Files are bounds checked as they're loaded.
+        std::fs::write(&path, vec![0xAA; 0x1000]).unwrap();
+        let result = load_rom(&mut mem, path_str, addr);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(result, Err(Chip8Error::ProgramTooLarge));
+
+        assert!(matches!(load_rom(&mut mem, "/nonexistent/rom.ch8", addr), Err(Chip8Error::Io(_))));
+    }
+
+    #[test]
+    fn read_font_size() {
+        let path = std::env::temp_dir().join(format!("chip8-font-test-{}.bin", std::process::id()));
+        let path_str = path.to_str().unwrap();
+
+        std::fs::write(&path, [0xAA; FONT_SIZE]).unwrap();
+        assert_eq!(read_font(path_str), Ok([0xAA; FONT_SIZE]));
+
+        std::fs::write(&path, [0xAA; FONT_SIZE + 1]).unwrap();
+        let result = read_font(path_str);
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(result, Err(Chip8Error::Io(_))));
+
+        assert!(read_font("/nonexistent/font.bin").is_err());
+    }
+
+    #[test]
+    fn rom_paths() {
+        let to_args = |args: &[&str]| -> Vec<String> { args.iter().map(|arg| arg.to_string()).collect() };
+
+        let args = to_args(&["a.ch8", "b.ch8", "--scale", "4", "--hud"]);
+        let (paths, flags) = split_rom_paths(&args);
+        assert_eq!(paths, to_args(&["a.ch8", "b.ch8"]));
+        assert_eq!(flags, to_args(&["--scale", "4", "--hud"]));
+
+        // Values of flags aren't mistaken for paths.
+        let args = to_args(&["-", "--trace", "out.txt", "c.ch8"]);
+        let (paths, flags) = split_rom_paths(&args);
+        assert_eq!(paths, to_args(&["-"]));
+        assert_eq!(flags, to_args(&["--trace", "out.txt", "c.ch8"]));
+
+        let args = to_args(&["--hud"]);
+        let (paths, flags) = split_rom_paths(&args);
+        assert!(paths.is_empty());
+        assert_eq!(flags.len(), 1);
+
+        assert!(!stdin_repeated(&to_args(&["-", "a.ch8"])));
+        assert!(stdin_repeated(&to_args(&["-", "a.ch8", "-"])));
+    }
+
+    #[test]
+    fn trace() {
+        let mut cpu = Cpu::new(CpuConfig::default());
+        let mut mem = Memory::new();
+        // V0 = 0x5, V0 += 0x3, jump back to the start.
+        let program = vec![0x60, 0x05, 0x70, 0x03, 0x12, 0x00];
+        assert!(mem.load_program(&program, PROGRAM_ADDRESS).is_ok());
+
+        let mut out: Vec<u8> = Vec::new();
+        for _ in 0..4 {
+            let addr = cpu.pc();
+            let instr = cpu.fetch(&mem).unwrap();
+            assert!(trace_instruction(&mut out, addr, instr).is_ok());
+            assert!(cpu.decode(instr, None, Some(&mut mem), None).is_ok());
+        }
+
+        assert_eq!(String::from_utf8(out).unwrap(),
+                   "PC:200 OP:6005 LD V0, 0x05\n\
+                    PC:202 OP:7003 ADD V0, 0x03\n\
+                    PC:204 OP:1200 JP 0x200\n\
+                    PC:200 OP:6005 LD V0, 0x05\n");
+    }
+
+    #[test]
+    fn instruction_limit() {
+        assert!(!instruction_limit_reached(u64::MAX, None));
+        assert!(!instruction_limit_reached(9, Some(10)));
+        assert!(instruction_limit_reached(10, Some(10)));
+        assert!(instruction_limit_reached(11, Some(10)));
+    }
+
+    #[test]
+    fn histogram_lines() {
+        let mut cpu = Cpu::new(CpuConfig::default());
+        cpu.set_profiling(true);
+        for instr in [0x6005, 0x8014, 0xF065, 0xF01E, 0xF01E].iter() {
+            assert!(cpu.decode(*instr, None, Some(&mut Memory::new()), None).is_ok());
+        }
+
+        assert_eq!(format_histogram(cpu.opcode_histogram().unwrap()),
+                   ["6___: 1", "8___: 1", "F___: 3", "8XY4: 1", "FX1E: 2", "FX65: 1"]);
+    }
+
+    #[test]
+    fn quirks_profiles() {
+        // (name, memory, vf reset, shift, jump, vblank, wrap)
+        let table = [
+            ("chip8", true, true, false, false, true, false),
+            ("schip", false, false, true, true, false, false),
+            ("xochip", true, false, false, false, false, true),
+        ];
+
+        for (name, mem, vf_reset, shift, jump, vblank, wrap) in table.iter() {
+            let profile = quirks_profile(name).unwrap();
+            let config = profile.config;
+            assert_eq!(config.mem_increment == MemIncrement::IncrementByXPlus1, *mem, "{}", name);
+            assert_eq!(config.logic_resets_vf, *vf_reset, "{}", name);
+            assert_eq!(config.shift_uses_vy, *shift, "{}", name);
+            assert_eq!(config.jump_quirk, *jump, "{}", name);
+            assert_eq!(config.draw_wait_vblank, *vblank, "{}", name);
+            assert_eq!(profile.wrap_sprites, *wrap, "{}", name);
+            assert_eq!(profile.xo_chip, *name == "xochip", "{}", name);
+        }
+
+        assert!(quirks_profile("cosmac").is_none());
+    }
+
+    #[test]
+    fn quirk_overrides() {
+        assert_eq!(quirk_override("--shift_quirk"), (String::from("--shift_quirk"), true));
+        assert_eq!(quirk_override("--no-shift_quirk"), (String::from("--shift_quirk"), false));
+        assert_eq!(quirk_override("--vblank_quirk=false"), (String::from("--vblank_quirk"), false));
+        assert_eq!(quirk_override("--vblank_quirk=true"), (String::from("--vblank_quirk"), true));
     }
 
-    exit(1);
+    #[test]
+    fn clock_hz_rounding() {
+        assert_eq!(clock_hz_cycles(660), 11);
+        assert_eq!(clock_hz_cycles(700), 11);
+        assert_eq!(clock_hz_cycles(30), 1);
+    }
+
+    #[test]
+    fn quit_dumps_memory() {
+        let disp = Display::new(true);
+        let mem = Memory::new();
+        let cpu = Cpu::new(CpuConfig::default());
+        let path = std::env::temp_dir().join(format!("chip8-dump-test-{}.bin", std::process::id()));
+        let options = Options { dump_path: Some(path.to_str().unwrap().to_string()), ..Options::default() };
+
+        // Closing the window stops the frame loop, which still saves the memory.
+        Display::request_quit(&disp);
+        assert!(Display::quit_requested(&disp));
+        finish_run(&disp, &mem, &cpu, &options, &mut None);
+        assert_eq!(std::fs::read(&path).unwrap(), mem.dump());
+        std::fs::remove_file(&path).unwrap();
+    }
 }
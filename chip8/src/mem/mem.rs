@@ -1,27 +1,68 @@
+use crate::error::error::Chip8Error;
 
-const MEM_SIZE: usize = 4096;
-const PROGRAM_ADDRESS: usize = 0x200;
+pub const MEM_SIZE: usize = 4096;
+// XO-CHIP extends the address space to 16 bits.
+const XO_CHIP_MEM_SIZE: usize = 65536;
+pub const PROGRAM_ADDRESS: usize = 0x200;
 const FONT_ADDRESS: usize = 0x50;
 const FONT_HEIGHT: usize = 5;
+// Size of the 0-F font glyphs.
+pub const FONT_SIZE: usize = FONT_HEIGHT * 16;
+// The SUPER-CHIP 8x10 font is stored right after the regular one.
+const BIG_FONT_ADDRESS: usize = 0xA0;
+const BIG_FONT_HEIGHT: usize = 10;
 
 pub struct Memory {
-    pub(crate) mem: [u8; 4096],
+    pub(crate) mem: Vec<u8>,
+    // Writes below PROGRAM_ADDRESS fail, since well-behaved programs leave the
+    // interpreter's memory (including the font) alone.
+    protect_interpreter: bool,
+}
+
+impl Default for Memory {
+    fn default() -> Self {
+        return Memory::new();
+    }
 }
 
 impl Memory {
     pub fn new() -> Self {
-       let mut mem = Memory { mem: [0; 4096]};
+       return Memory::with_size(MEM_SIZE);
+    }
+
+    pub fn new_xo_chip() -> Self {
+       return Memory::with_size(XO_CHIP_MEM_SIZE);
+    }
+
+    // Memory holding exactly |bytes|, without the font being loaded.
+    pub fn from_bytes(bytes: [u8; MEM_SIZE]) -> Self {
+        return Memory { mem: bytes.to_vec(), protect_interpreter: false };
+    }
+
+    // Regular sized memory with the font loaded, and |program| at PROGRAM_ADDRESS.
+    pub fn with_program(program: &[u8]) -> Result<Self, Chip8Error> {
+        let mut mem = Memory::new();
+        mem.load_program(program, PROGRAM_ADDRESS)?;
+        return Ok(mem);
+    }
+
+    fn with_size(size: usize) -> Self {
+       let mut mem = Memory { mem: vec![0; size], protect_interpreter: false };
        mem.load_font();
        return mem;
     }
 
-    // Program's are stored at 0x200 onwards
-    pub fn load_program(&mut self, program: &Vec<u8>) -> Result<i32, String> {
-        if program.len() > (self.mem.len() - PROGRAM_ADDRESS) {
-            return Err(String::from("Program is too large."));
+    // Program's are normally stored at 0x200 onwards, though some interpreters
+    // (e.g. the ETI-660) load them at a different |addr|.
+    pub fn load_program(&mut self, program: &[u8], addr: usize) -> Result<i32, Chip8Error> {
+        if addr > self.mem.len() {
+            return Err(Chip8Error::InvalidAddress(addr));
+        }
+        if program.len() > (self.mem.len() - addr) {
+            return Err(Chip8Error::ProgramTooLarge);
         }
 
-        let mut i = PROGRAM_ADDRESS;
+        let mut i = addr;
         for byte in program.iter() {
             self.mem[i] = *byte;
             i = i + 1;
@@ -30,6 +71,28 @@ impl Memory {
         return Ok(0);
     }
 
+    // Reads the program from the file at |path| and loads it at |addr|. Returns
+    // the size of the program.
+    pub fn load_program_from_file(&mut self, path: &str, addr: usize) -> Result<usize, Chip8Error> {
+        let program = match std::fs::read(path) {
+            Ok(program) => program,
+            Err(e) => return Err(Chip8Error::Io(format!("{}: {}", path, e))),
+        };
+
+        self.load_program(&program, addr)?;
+        return Ok(program.len());
+    }
+
+    // Clear the memory, and load the font and |program| back in at |addr|.
+    pub fn reset(&mut self, program: &[u8], addr: usize) -> Result<i32, Chip8Error> {
+        for byte in self.mem.iter_mut() {
+            *byte = 0;
+        }
+        self.load_font();
+
+        return self.load_program(program, addr);
+    }
+
     // Load system font into the memory.
     fn load_font(&mut self) {
         const FONT_ARRAY: [u8; 80] = [
@@ -51,50 +114,347 @@ impl Memory {
             0xF0, 0x80, 0xF0, 0x80, 0x80  // F
         ];
 
+        // SUPER-CHIP only had the digits, the letters are the ones used by XO-CHIP.
+        const BIG_FONT_ARRAY: [u8; 160] = [
+            0xFF, 0xFF, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, // 0
+            0x18, 0x78, 0x78, 0x18, 0x18, 0x18, 0x18, 0x18, 0xFF, 0xFF, // 1
+            0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // 2
+            0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 3
+            0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0x03, 0x03, // 4
+            0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 5
+            0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // 6
+            0xFF, 0xFF, 0x03, 0x03, 0x06, 0x0C, 0x18, 0x18, 0x18, 0x18, // 7
+            0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // 8
+            0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 9
+            0x7E, 0xFF, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3, // A
+            0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, // B
+            0x3C, 0xFF, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0xFF, 0x3C, // C
+            0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC, // D
+            0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // E
+            0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xC0, 0xC0  // F
+        ];
+
         for (i, val) in FONT_ARRAY.iter().enumerate() {
             self.mem[FONT_ADDRESS + i] = *val;
         }
+        for (i, val) in BIG_FONT_ARRAY.iter().enumerate() {
+            self.mem[BIG_FONT_ADDRESS + i] = *val;
+        }
+    }
+
+    // Replace the regular font with |font|, for programs which expect the glyphs
+    // of a particular interpreter. The big font is left as is.
+    pub fn load_custom_font(&mut self, font: &[u8; FONT_SIZE]) {
+        self.mem[FONT_ADDRESS..FONT_ADDRESS + FONT_SIZE].copy_from_slice(font);
     }
 
     pub fn get_font_addr(&self, font: u8) -> usize {
         return FONT_ADDRESS + (FONT_HEIGHT * (font & 0xF) as usize);
     }
 
-    pub fn read(&self, addr: usize) -> Result<u8, String> {
-        if addr >= MEM_SIZE {
-            return Err(String::from("Invalid read address."));
+    pub fn get_big_font_addr(&self, font: u8) -> usize {
+        return BIG_FONT_ADDRESS + (BIG_FONT_HEIGHT * (font & 0xF) as usize);
+    }
+
+    // Returns a copy of the 0-F font glyphs, FONT_HEIGHT bytes each.
+    pub fn font_glyphs(&self) -> Vec<u8> {
+        return self.mem[FONT_ADDRESS..FONT_ADDRESS + FONT_HEIGHT * 16].to_vec();
+    }
+
+    // Size of the address space, which is larger for XO-CHIP.
+    pub fn size(&self) -> usize {
+        return self.mem.len();
+    }
+
+    // Returns a copy of the entire memory contents.
+    pub fn snapshot(&self) -> Vec<u8> {
+        return self.mem.to_vec();
+    }
+
+    pub fn restore(&mut self, blob: &[u8]) -> Result<i32, Chip8Error> {
+        if blob.len() != self.mem.len() {
+            return Err(Chip8Error::InvalidSnapshot(String::from("invalid size")));
+        }
+
+        self.mem.copy_from_slice(blob);
+        return Ok(0);
+    }
+
+    pub fn read(&self, addr: usize) -> Result<u8, Chip8Error> {
+        if addr >= self.mem.len() {
+            return Err(Chip8Error::InvalidAddress(addr));
         }
 
         return Ok(self.mem[addr]);
     }
+
+    // The whole of the memory, for saving it to a file.
+    pub fn dump(&self) -> &[u8] {
+        return &self.mem;
+    }
+
+    // Returns a copy of |len| bytes starting at |start|.
+    pub fn dump_range(&self, start: usize, len: usize) -> Result<Vec<u8>, Chip8Error> {
+        match start.checked_add(len) {
+            Some(end) if end <= self.mem.len() => return Ok(self.mem[start..end].to_vec()),
+            _ => return Err(Chip8Error::InvalidAddress(std::cmp::max(start, self.mem.len()))),
+        }
+    }
+
+    // Catches buggy programs writing over the interpreter's memory, off by default.
+    pub fn set_protect_interpreter(&mut self, protect: bool) {
+        self.protect_interpreter = protect;
+    }
+
+    pub fn write(&mut self, addr: usize, val: u8) -> Result<(), Chip8Error> {
+        if addr >= self.mem.len() {
+            return Err(Chip8Error::InvalidAddress(addr));
+        }
+        if self.protect_interpreter && addr < PROGRAM_ADDRESS {
+            return Err(Chip8Error::ProtectedAddress(addr));
+        }
+
+        self.mem[addr] = val;
+        return Ok(());
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::mem::mem::{FONT_ADDRESS, FONT_HEIGHT};
+    use crate::mem::mem::{FONT_ADDRESS, FONT_HEIGHT, FONT_SIZE, BIG_FONT_ADDRESS, BIG_FONT_HEIGHT, PROGRAM_ADDRESS, MEM_SIZE};
+
+    use super::{Memory, Chip8Error};
 
-    use super::Memory;
+    #[test]
+    fn from_bytes() {
+        let mut bytes = [0; MEM_SIZE];
+        bytes[0x300] = 0xAB;
+        let mem = Memory::from_bytes(bytes);
+        assert_eq!(mem.size(), MEM_SIZE);
+        assert_eq!(mem.read(0x300).unwrap(), 0xAB);
+        // The contents are used as is, without the font.
+        assert_eq!(mem.read(FONT_ADDRESS).unwrap(), 0);
+    }
+
+    #[test]
+    fn with_program() {
+        let prog: Vec<u8> = vec![0x60, 0x05, 0x12, 0x00];
+        let mem = Memory::with_program(&prog).unwrap();
+        assert_eq!(mem.size(), MEM_SIZE);
+        assert_eq!(mem.dump_range(PROGRAM_ADDRESS, prog.len()).unwrap(), prog);
+        assert_eq!(mem.dump_range(FONT_ADDRESS, FONT_HEIGHT).unwrap(), vec![0xF0, 0x90, 0x90, 0x90, 0xF0]);
+
+        assert!(matches!(Memory::with_program(&vec![0; MEM_SIZE]), Err(Chip8Error::ProgramTooLarge)));
+    }
+
+    #[test]
+    fn dump() {
+        let prog: Vec<u8> = vec![0x60, 0x05, 0x12, 0x00];
+        let mem = Memory::with_program(&prog).unwrap();
+        let dump = mem.dump();
+        assert_eq!(dump.len(), MEM_SIZE);
+        assert_eq!(dump[PROGRAM_ADDRESS..PROGRAM_ADDRESS + prog.len()], prog[..]);
+        assert_eq!(dump[FONT_ADDRESS], 0xF0);
+        assert!(dump[PROGRAM_ADDRESS + prog.len()..].iter().all(|byte| *byte == 0));
+
+        assert_eq!(Memory::new_xo_chip().dump().len(), 65536);
+    }
 
     #[test]
     fn check_invalid_size() {
         let large_program = vec![0; 4000];
-        let mut mem = Memory{mem: [0; 4096]}; 
-        assert!(mem.load_program(&large_program).is_err());
+        let mut mem = Memory::from_bytes([0; MEM_SIZE]); 
+        assert_eq!(mem.load_program(&large_program, PROGRAM_ADDRESS), Err(Chip8Error::ProgramTooLarge));
     }
 
     #[test]
     fn check_load() {
         let prog: Vec<u8> = vec![0x8; 400];
-        let mut mem = Memory{mem: [0; 4096]};
-        assert!(mem.load_program(&prog).is_ok());
+        let mut mem = Memory::from_bytes([0; MEM_SIZE]);
+        assert!(mem.load_program(&prog, PROGRAM_ADDRESS).is_ok());
+
+        assert_eq!(mem.read(PROGRAM_ADDRESS).unwrap(), 0x8);
+        assert_eq!(mem.read(PROGRAM_ADDRESS + prog.len() - 1).unwrap(), 0x8);
+    }
+
+    #[test]
+    fn check_load_slice() {
+        const PROG: &[u8] = &[0x60, 0x05, 0x70, 0x03, 0x12, 0x00];
+        let mut mem = Memory::from_bytes([0; MEM_SIZE]);
+        assert!(mem.load_program(PROG, PROGRAM_ADDRESS).is_ok());
+
+        for (i, byte) in PROG.iter().enumerate() {
+            assert_eq!(mem.read(PROGRAM_ADDRESS + i).unwrap(), *byte);
+        }
+    }
+
+    #[test]
+    fn snapshot_restore() {
+        let prog: Vec<u8> = vec![0x8; 400];
+        let mut mem = Memory::new();
+        assert!(mem.load_program(&prog, PROGRAM_ADDRESS).is_ok());
+        let blob = mem.snapshot();
+        assert_eq!(blob.len(), MEM_SIZE);
+        assert_eq!(mem.size(), MEM_SIZE);
+
+        assert!(mem.load_program(&vec![0x1; 800], PROGRAM_ADDRESS).is_ok());
+        assert!(mem.restore(&blob).is_ok());
+        assert_eq!(&mem.mem[..], &blob[..]);
+        assert_eq!(mem.read(PROGRAM_ADDRESS + 400).unwrap(), 0);
+
+        assert!(mem.restore(&blob[..100]).is_err());
+    }
+
+    #[test]
+    fn write() {
+        let mut mem = Memory::from_bytes([0; MEM_SIZE]);
+        assert!(mem.write(0x300, 0xAB).is_ok());
+        assert_eq!(mem.read(0x300).unwrap(), 0xAB);
+
+        assert!(mem.write(4095, 0xCD).is_ok());
+        assert_eq!(mem.read(4095).unwrap(), 0xCD);
+    }
+
+    #[test]
+    fn protect_interpreter() {
+        let mut mem = Memory::new();
+        assert!(mem.write(FONT_ADDRESS, 0xAB).is_ok());
+
+        mem.set_protect_interpreter(true);
+        assert_eq!(mem.write(FONT_ADDRESS, 0xCD), Err(Chip8Error::ProtectedAddress(FONT_ADDRESS)));
+        assert_eq!(mem.write(PROGRAM_ADDRESS - 1, 0xCD), Err(Chip8Error::ProtectedAddress(PROGRAM_ADDRESS - 1)));
+        assert_eq!(mem.read(FONT_ADDRESS).unwrap(), 0xAB);
+        assert!(mem.write(PROGRAM_ADDRESS, 0xCD).is_ok());
+
+        // Resetting still loads the font.
+        assert!(mem.reset(&[], PROGRAM_ADDRESS).is_ok());
+        assert_eq!(mem.read(FONT_ADDRESS).unwrap(), 0xF0);
+        assert!(mem.write(0x0, 0xCD).is_err());
+    }
+
+    #[test]
+    fn write_invalid_addr() {
+        let mut mem = Memory::from_bytes([0; MEM_SIZE]);
+        assert_eq!(mem.write(MEM_SIZE, 0xAB), Err(Chip8Error::InvalidAddress(MEM_SIZE)));
+        assert_eq!(mem.read(MEM_SIZE), Err(Chip8Error::InvalidAddress(MEM_SIZE)));
+    }
 
-        assert_eq!(mem.read(crate::mem::mem::PROGRAM_ADDRESS).unwrap(), 0x8);
-        assert_eq!(mem.read(crate::mem::mem::PROGRAM_ADDRESS + prog.len() - 1).unwrap(), 0x8);
+    #[test]
+    fn xo_chip_range() {
+        let mut mem = Memory::new_xo_chip();
+        assert_eq!(mem.snapshot().len(), 65536);
+        assert!(mem.write(0xFFFF, 0xAB).is_ok());
+        assert_eq!(mem.read(0xFFFF).unwrap(), 0xAB);
+        assert!(mem.write(0x10000, 0xAB).is_err());
+        assert!(mem.read(0x10000).is_err());
+
+        // The regular memory is still limited to 4K.
+        let mut mem = Memory::new();
+        assert!(mem.write(0x1000, 0xAB).is_err());
+        assert!(mem.read(0x1000).is_err());
+
+        // Larger programs fit in the extended memory.
+        let mut mem = Memory::new_xo_chip();
+        assert!(mem.load_program(&vec![0x1; 8000], PROGRAM_ADDRESS).is_ok());
+    }
+
+    #[test]
+    fn load_program_from_file() {
+        let path = std::env::temp_dir().join(format!("chip8-mem-test-{}.ch8", std::process::id()));
+        let prog: Vec<u8> = vec![0x00, 0xE0, 0x12, 0x00];
+        std::fs::write(&path, &prog).unwrap();
+
+        let mut mem = Memory::new();
+        let loaded = mem.load_program_from_file(path.to_str().unwrap(), PROGRAM_ADDRESS);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded, Ok(prog.len()));
+        assert_eq!(mem.dump_range(PROGRAM_ADDRESS, prog.len()).unwrap(), prog);
+
+        assert!(matches!(mem.load_program_from_file("/nonexistent/rom.ch8", PROGRAM_ADDRESS),
+                         Err(Chip8Error::Io(_))));
+    }
+
+    #[test]
+    fn load_at_address() {
+        let mut mem = Memory::new();
+        let prog: Vec<u8> = vec![0x12, 0x34];
+        assert!(mem.load_program(&prog, 0x600).is_ok());
+        assert_eq!(mem.read(0x600).unwrap(), 0x12);
+        assert_eq!(mem.read(0x601).unwrap(), 0x34);
+        assert_eq!(mem.read(PROGRAM_ADDRESS).unwrap(), 0);
+
+        // Less room is left for the program the later it starts.
+        assert_eq!(mem.load_program(&vec![0; 2600], 0x600), Err(Chip8Error::ProgramTooLarge));
+        assert_eq!(mem.load_program(&prog, 0x1001), Err(Chip8Error::InvalidAddress(0x1001)));
+    }
+
+    #[test]
+    fn reset() {
+        let mut mem = Memory::new();
+        let program = vec![0x12, 0x00];
+        assert!(mem.load_program(&program, PROGRAM_ADDRESS).is_ok());
+        mem.write(0x202, 0xAB).unwrap();
+        mem.write(FONT_ADDRESS, 0xAB).unwrap();
+
+        assert!(mem.reset(&program, PROGRAM_ADDRESS).is_ok());
+        assert_eq!(mem.snapshot(), {
+            let mut fresh = Memory::new();
+            fresh.load_program(&program, PROGRAM_ADDRESS).unwrap();
+            fresh.snapshot()
+        });
+    }
+
+    #[test]
+    fn dump_range() {
+        let mut mem = Memory::new();
+        // The font for "0" followed by the start of "1".
+        assert_eq!(mem.dump_range(FONT_ADDRESS, 6).unwrap(), vec![0xF0, 0x90, 0x90, 0x90, 0xF0, 0x20]);
+
+        mem.write(0xFFF, 0xAB).unwrap();
+        assert_eq!(mem.dump_range(0xFFE, 2).unwrap(), vec![0x00, 0xAB]);
+        assert!(mem.dump_range(0x200, 0).unwrap().is_empty());
+
+        assert_eq!(mem.dump_range(0xFFE, 3), Err(Chip8Error::InvalidAddress(0x1000)));
+        assert!(mem.dump_range(0x1000, 1).is_err());
+        assert!(mem.dump_range(usize::MAX, 2).is_err());
+    }
+
+    #[test]
+    fn load_custom_font() {
+        let mut mem = Memory::new();
+        let big_font = mem.dump_range(BIG_FONT_ADDRESS, 16 * BIG_FONT_HEIGHT).unwrap();
+        let mut font = [0u8; FONT_SIZE];
+        for (i, byte) in font.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+
+        mem.load_custom_font(&font);
+        assert_eq!(mem.font_glyphs(), font);
+        assert_eq!(mem.dump_range(mem.get_font_addr(0x7), FONT_HEIGHT).unwrap(),
+                   vec![0x23, 0x24, 0x25, 0x26, 0x27]);
+        assert_eq!(mem.dump_range(BIG_FONT_ADDRESS, 16 * BIG_FONT_HEIGHT).unwrap(), big_font);
+
+        // Resetting brings back the default font.
+        assert!(mem.reset(&[], PROGRAM_ADDRESS).is_ok());
+        assert_eq!(mem.dump_range(mem.get_font_addr(0x0), FONT_HEIGHT).unwrap(),
+                   vec![0xF0, 0x90, 0x90, 0x90, 0xF0]);
     }
 
     #[test]
     fn get_font_addr() {
         let mem = Memory::new();
         assert_eq!(mem.get_font_addr(0x4), FONT_ADDRESS + (0x4 * FONT_HEIGHT));
+        // The big font doesn't overlap the regular one, or the program.
+        assert_eq!(mem.get_big_font_addr(0x0), BIG_FONT_ADDRESS);
+        assert_eq!(mem.get_big_font_addr(0x4), BIG_FONT_ADDRESS + (0x4 * BIG_FONT_HEIGHT));
+        assert_eq!(mem.get_big_font_addr(0x14), mem.get_big_font_addr(0x4));
+        assert!(mem.get_font_addr(0xF) + FONT_HEIGHT <= mem.get_big_font_addr(0x0));
+        assert!(mem.get_big_font_addr(0xF) + BIG_FONT_HEIGHT <= PROGRAM_ADDRESS);
+        assert_eq!(mem.dump_range(mem.get_big_font_addr(0x1), BIG_FONT_HEIGHT).unwrap(),
+                   vec![0x18, 0x78, 0x78, 0x18, 0x18, 0x18, 0x18, 0x18, 0xFF, 0xFF]);
+
+        let glyphs = mem.font_glyphs();
+        assert_eq!(glyphs.len(), FONT_SIZE);
+        assert_eq!(glyphs[0xA * FONT_HEIGHT..0xB * FONT_HEIGHT], [0xF0, 0x90, 0xF0, 0x90, 0x90]);
     }
 }
@@ -0,0 +1 @@
+pub mod rom;
\ No newline at end of file
@@ -0,0 +1,112 @@
+use crate::mem::mem::{MEM_SIZE, PROGRAM_ADDRESS};
+
+// Programs are loaded at PROGRAM_ADDRESS, so anything larger only fits in XO-CHIP memory.
+const MAX_CHIP8_ROM_SIZE: usize = MEM_SIZE - PROGRAM_ADDRESS;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Platform {
+    Chip8,
+    Schip,
+    XoChip,
+}
+
+impl Platform {
+    // Name of the matching quirks profile.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Platform::Chip8 => return "chip8",
+            Platform::Schip => return "schip",
+            Platform::XoChip => return "xochip",
+        }
+    }
+}
+
+fn is_xo_chip_instr(instr: u16) -> bool {
+    // Long I load, audio pattern load, and plane select.
+    return instr == 0xF000 || instr == 0xF002 || (instr & 0xF0FF) == 0xF001;
+}
+
+fn is_schip_instr(instr: u16) -> bool {
+    match instr {
+        // Scrolling, exit and the resolution switches.
+        0x00FB..=0x00FF => return true,
+        _ if (instr & 0xFFF0) == 0x00C0 => return true,
+        // 16x16 sprites.
+        _ if (instr & 0xF00F) == 0xD000 => return true,
+        // Large font, and the RPL flags.
+        _ if (instr & 0xF0FF) == 0xF030 => return true,
+        _ if (instr & 0xF0FF) == 0xF075 || (instr & 0xF0FF) == 0xF085 => return true,
+        _ => return false,
+    }
+}
+
+// Best-effort guess at the platform a program was written for, going by its
+// size and the instructions it uses. Data in the program can look like
+// instructions too, so this can get it wrong.
+pub fn detect_platform(bytes: &[u8]) -> Platform {
+    if bytes.len() > MAX_CHIP8_ROM_SIZE {
+        return Platform::XoChip;
+    }
+
+    let mut platform = Platform::Chip8;
+    for word in bytes.chunks_exact(2) {
+        let instr = u16::from_be_bytes([word[0], word[1]]);
+        if is_xo_chip_instr(instr) {
+            return Platform::XoChip;
+        }
+        if is_schip_instr(instr) {
+            platform = Platform::Schip;
+        }
+    }
+
+    return platform;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{detect_platform, Platform, MAX_CHIP8_ROM_SIZE};
+
+    #[test]
+    fn detect_chip8() {
+        assert_eq!(detect_platform(&[]), Platform::Chip8);
+        // CLS, LD V0, 0x05, DRW V0, V1, 5, JP 0x200
+        assert_eq!(detect_platform(&[0x00, 0xE0, 0x60, 0x05, 0xD0, 0x15, 0x12, 0x00]), Platform::Chip8);
+    }
+
+    #[test]
+    fn detect_schip() {
+        let table: [&[u8]; 6] = [
+            &[0x00, 0xE0, 0x00, 0xFF],
+            &[0x00, 0xC4],
+            &[0x60, 0x05, 0xD0, 0x10],
+            &[0xF3, 0x30],
+            &[0xF7, 0x75],
+            &[0xF2, 0x85, 0x12, 0x00],
+        ];
+        for bytes in table.iter() {
+            assert_eq!(detect_platform(bytes), Platform::Schip, "{:02X?}", bytes);
+        }
+
+        // Only instructions starting at even offsets are considered.
+        assert_eq!(detect_platform(&[0x60, 0x00, 0xFF, 0x12]), Platform::Chip8);
+    }
+
+    #[test]
+    fn detect_xo_chip() {
+        // Long I load, even alongside SUPER-CHIP instructions.
+        assert_eq!(detect_platform(&[0x00, 0xFF, 0xF0, 0x00, 0x12, 0x34]), Platform::XoChip);
+        assert_eq!(detect_platform(&[0xF0, 0x02]), Platform::XoChip);
+        assert_eq!(detect_platform(&[0xF3, 0x01]), Platform::XoChip);
+
+        // Too large for regular memory.
+        assert_eq!(detect_platform(&vec![0; MAX_CHIP8_ROM_SIZE]), Platform::Chip8);
+        assert_eq!(detect_platform(&vec![0; MAX_CHIP8_ROM_SIZE + 1]), Platform::XoChip);
+    }
+
+    #[test]
+    fn platform_name() {
+        assert_eq!(Platform::Chip8.name(), "chip8");
+        assert_eq!(Platform::Schip.name(), "schip");
+        assert_eq!(Platform::XoChip.name(), "xochip");
+    }
+}
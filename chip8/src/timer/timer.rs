@@ -1,37 +1,188 @@
-use std::thread;
-use std::sync::{Mutex, Arc};
-use std::time::Duration;
+use std::thread::{self, JoinHandle};
+use std::sync::{Mutex, Arc, atomic::{AtomicBool, Ordering}};
+#[cfg(test)]
+use std::sync::Condvar;
+use std::time::{Duration, Instant};
 
-use crate::audio::audio::Audio;
+use crate::audio::audio::{Audio, AudioConfig, PATTERN_SIZE};
+
+// Where the timer thread gets the time from and how it waits, so that tests
+// can control the passage of time.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+    fn sleep(&self, duration: Duration);
+}
+
+pub struct RealClock;
+
+impl Clock for RealClock {
+    fn now(&self) -> Instant {
+        return Instant::now();
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
+// A clock which only moves when advanced. Sleeping blocks until enough time
+// has been advanced past the point the sleep started.
+#[cfg(test)]
+pub struct FakeClock {
+    // The current time, and the number of sleeps which have been started.
+    state: Mutex<(Instant, u32)>,
+    changed: Condvar,
+}
+
+#[cfg(test)]
+impl FakeClock {
+    pub fn new() -> Self {
+        return FakeClock { state: Mutex::new((Instant::now(), 0)), changed: Condvar::new() };
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        self.state.lock().unwrap().0 += duration;
+        self.changed.notify_all();
+    }
+
+    // Number of sleeps which have been started, one per pass of a paced loop.
+    pub fn sleeps(&self) -> u32 {
+        return self.state.lock().unwrap().1;
+    }
+
+    // Block until |count| sleeps have been started in total.
+    pub fn wait_for_sleeps(&self, count: u32) {
+        let state = self.state.lock().unwrap();
+        let _state = self.changed.wait_while(state, |(_, sleeps)| *sleeps < count).unwrap();
+    }
+}
+
+#[cfg(test)]
+impl Default for FakeClock {
+    fn default() -> Self {
+        return FakeClock::new();
+    }
+}
+
+#[cfg(test)]
+impl Clock for FakeClock {
+    fn now(&self) -> Instant {
+        return self.state.lock().unwrap().0;
+    }
+
+    fn sleep(&self, duration: Duration) {
+        let mut state = self.state.lock().unwrap();
+        let wake = state.0 + duration;
+        state.1 += 1;
+        self.changed.notify_all();
+        let _state = self.changed.wait_while(state, |(now, _)| *now < wake).unwrap();
+    }
+}
+
+// Paces a loop to a fixed period. Ticks are scheduled at |start| + n * |period|
+// so the time spent working in each iteration doesn't add up to drift.
+pub struct Ticker {
+    start: Instant,
+    period: Duration,
+    ticks: u32,
+}
+
+impl Ticker {
+    pub fn new(period: Duration) -> Ticker {
+        return Ticker::with_start(Instant::now(), period);
+    }
+
+    fn with_start(start: Instant, period: Duration) -> Ticker {
+        return Ticker { start, period, ticks: 0 };
+    }
+
+    // How long to sleep at |now| until the next tick. If we've fallen behind,
+    // don't try to catch up, just start counting from now.
+    fn next_sleep(&mut self, now: Instant) -> Duration {
+        self.ticks += 1;
+        let deadline = self.start + self.period * self.ticks;
+        if deadline > now {
+            return deadline - now;
+        }
+
+        self.start = now;
+        self.ticks = 0;
+        return Duration::ZERO;
+    }
+
+    // Sleep until the next tick.
+    pub fn sleep(&mut self) {
+        self.sleep_on(&RealClock);
+    }
+
+    // Sleep until the next tick, as told by |clock|.
+    pub fn sleep_on(&mut self, clock: &dyn Clock) {
+        let duration = self.next_sleep(clock.now());
+        if !duration.is_zero() {
+            clock.sleep(duration);
+        }
+    }
+}
+
+// The timers count down at 60Hz.
+const TIMER_PERIOD: Duration = Duration::from_micros(16666);
 
 pub struct Timer {
     delay: Mutex<u8>,
     sound: Mutex<u8>,
+    paused: Mutex<bool>,
+    // Held by the debugger while single-stepping, separately from the user pausing.
+    frozen: AtomicBool,
+    muted: Mutex<bool>,
     audio: Option<Mutex<Audio>>,
+    // Tells the timer thread to exit.
+    shutdown: AtomicBool,
+    thread: Mutex<Option<JoinHandle<()>>>,
 }
 
 impl Timer {
-    pub fn new(for_test: bool) -> Arc<Timer> {
+    pub fn new(for_test: bool, audio_config: AudioConfig) -> Arc<Timer> {
         let timer = Arc::new(Timer {
             delay: Mutex::new(0),
             sound: Mutex::new(0),
-            audio: if !for_test {
-                Some(Mutex::new(Audio::new()))
-            } else {
-                None
-            }
+            paused: Mutex::new(false),
+            frozen: AtomicBool::new(false),
+            muted: Mutex::new(false),
+            audio: Some(Mutex::new(Audio::with_config(audio_config, for_test))),
+            shutdown: AtomicBool::new(false),
+            thread: Mutex::new(None),
         });
 
         if !for_test {
-            let timer_clone = Arc::clone(&timer);
-            thread::spawn(move || {
-                Timer::thread_loop(timer_clone);
-            });
+            Timer::spawn_thread(&timer);
         }
 
         return timer;
     }
 
+    fn spawn_thread(timer: &Arc<Timer>) {
+        Timer::spawn_thread_with_clock(timer, Arc::new(RealClock));
+    }
+
+    fn spawn_thread_with_clock(timer: &Arc<Timer>, clock: Arc<dyn Clock>) {
+        let timer_clone = Arc::clone(timer);
+        *timer.thread.lock().unwrap() = Some(thread::spawn(move || {
+            Timer::thread_loop(timer_clone, clock);
+        }));
+    }
+
+    // Stop the timer thread and wait for it to exit. The timers stop counting
+    // down and the sound is silenced.
+    pub fn shutdown(timer: &Arc<Timer>) {
+        timer.shutdown.store(true, Ordering::Relaxed);
+        let handle = timer.thread.lock().unwrap().take();
+        if let Some(handle) = handle {
+            if handle.join().is_err() {
+                eprintln!("Timer thread panicked");
+            }
+        }
+    }
+
     pub fn set_delay(timer: &Arc<Timer>, val: u8) {
         let mut delay = timer.delay.lock().unwrap();
         *delay = val;
@@ -52,7 +203,71 @@ impl Timer {
         return *sound;
     }
 
-    fn one_iteration(delay: &Mutex<u8>, sound: &Mutex<u8>, audio: &Option<Mutex<Audio>>) {
+    pub fn set_audio_pattern(timer: &Arc<Timer>, pattern: [u8; PATTERN_SIZE]) {
+        if let Some(audio) = &timer.audio {
+            audio.lock().unwrap().set_pattern(pattern);
+        }
+    }
+
+    pub fn get_audio_pattern(timer: &Arc<Timer>) -> Option<[u8; PATTERN_SIZE]> {
+        return timer.audio.as_ref().and_then(|audio| audio.lock().unwrap().pattern());
+    }
+
+    pub fn set_pitch(timer: &Arc<Timer>, pitch: u8) {
+        if let Some(audio) = &timer.audio {
+            audio.lock().unwrap().set_pitch(pitch);
+        }
+    }
+
+    pub fn get_pitch(timer: &Arc<Timer>) -> Option<u8> {
+        return timer.audio.as_ref().map(|audio| audio.lock().unwrap().pitch());
+    }
+
+    // Clear both timers and the XO-CHIP audio state, so that a new program
+    // doesn't inherit the previous one's sound.
+    pub fn reset(timer: &Arc<Timer>) {
+        Timer::set_delay(timer, 0);
+        Timer::set_sound(timer, 0);
+        if let Some(audio) = &timer.audio {
+            audio.lock().unwrap().reset_pattern();
+        }
+    }
+
+    // While paused, the timers hold their values and the audio is silenced.
+    pub fn set_paused(timer: &Arc<Timer>, val: bool) {
+        let mut paused = timer.paused.lock().unwrap();
+        *paused = val;
+    }
+
+    // Like pausing, but for the debugger, so that stepping through instructions
+    // doesn't let the timers run down while the pause state is left to the user.
+    pub fn freeze(timer: &Arc<Timer>) {
+        timer.frozen.store(true, Ordering::Relaxed);
+    }
+
+    pub fn unfreeze(timer: &Arc<Timer>) {
+        timer.frozen.store(false, Ordering::Relaxed);
+    }
+
+    // While muted, the sound timer keeps counting down but nothing is played.
+    pub fn set_muted(timer: &Arc<Timer>, val: bool) {
+        let mut muted = timer.muted.lock().unwrap();
+        *muted = val;
+    }
+
+    pub fn is_muted(timer: &Arc<Timer>) -> bool {
+        return *timer.muted.lock().unwrap();
+    }
+
+    fn one_iteration(delay: &Mutex<u8>, sound: &Mutex<u8>, paused: &Mutex<bool>, frozen: &AtomicBool,
+                     muted: &Mutex<bool>, audio: &Option<Mutex<Audio>>) {
+        if frozen.load(Ordering::Relaxed) || *paused.lock().unwrap() {
+            if let Some(audio) = audio {
+                audio.lock().unwrap().stop();
+            }
+            return;
+        }
+
         let mut delay = delay.lock().unwrap();
         if *delay > 0 {
             *delay -= 1;
@@ -63,37 +278,208 @@ impl Timer {
             *sound -= 1;
         }
 
-        if audio.is_some() {
-            if (*sound > 0) {
-                audio.as_ref().unwrap().lock().unwrap().start();
+        if let Some(audio) = audio {
+            let mut audio = audio.lock().unwrap();
+            let min_ticks = std::cmp::max(1, audio.config().min_sound_ticks);
+            if *sound >= min_ticks && !*muted.lock().unwrap() {
+                audio.start();
             } else {
-                audio.as_ref().unwrap().lock().unwrap().stop();
+                audio.stop();
             }
         }
     }
 
-    fn thread_loop(timer: Arc<Timer>) {
+    // Every tick of |clock| counts the timers down once.
+    fn thread_loop(timer: Arc<Timer>, clock: Arc<dyn Clock>) {
+        let mut ticker = Ticker::with_start(clock.now(), TIMER_PERIOD);
         loop {
-            Timer::one_iteration(&timer.delay, &timer.sound, &timer.audio);
-            thread::sleep(Duration::from_micros(16666));
+            ticker.sleep_on(clock.as_ref());
+            if timer.shutdown.load(Ordering::Relaxed) {
+                break;
+            }
+            Timer::one_iteration(&timer.delay, &timer.sound, &timer.paused, &timer.frozen, &timer.muted, &timer.audio);
+        }
+
+        if let Some(audio) = &timer.audio {
+            audio.lock().unwrap().stop();
         }
     }
 }
 
-
+#[cfg(test)]
 mod tests {
-    use super::Timer;
+    use super::{Timer, Audio, AudioConfig};
 
     #[test]
-    // Since we can't run the timer thread and meaningfully verify the code in a unit
-    // test, create a version of the Timer which doens't have a thread running
-    // and fake the passage of time by manually calling one_iteration().
+    // Create a version of the Timer which doesn't have a thread running and fake
+    // the passage of time by manually calling one_iteration().
     fn check_iterations() {
-        let timer = Timer::new(true);
+        let timer = Timer::new(true, AudioConfig::default());
         Timer::set_delay(&timer, 0x6);
-        Timer::one_iteration(&timer.delay, &timer.sound, &None);
+        Timer::one_iteration(&timer.delay, &timer.sound, &timer.paused, &timer.frozen, &timer.muted, &None);
         assert_eq!(Timer::get_delay(&timer), 0x5);
-        Timer::one_iteration(&timer.delay, &timer.sound, &None);
+        Timer::one_iteration(&timer.delay, &timer.sound, &timer.paused, &timer.frozen, &timer.muted, &None);
         assert_eq!(Timer::get_delay(&timer), 0x4);
     }
+
+    #[test]
+    fn check_paused() {
+        let timer = Timer::new(true, AudioConfig::default());
+        Timer::set_delay(&timer, 0x6);
+        Timer::set_sound(&timer, 0x6);
+
+        Timer::set_paused(&timer, true);
+        Timer::one_iteration(&timer.delay, &timer.sound, &timer.paused, &timer.frozen, &timer.muted, &None);
+        assert_eq!(Timer::get_delay(&timer), 0x6);
+        assert_eq!(Timer::get_sound(&timer), 0x6);
+
+        Timer::set_paused(&timer, false);
+        Timer::one_iteration(&timer.delay, &timer.sound, &timer.paused, &timer.frozen, &timer.muted, &None);
+        assert_eq!(Timer::get_delay(&timer), 0x5);
+        assert_eq!(Timer::get_sound(&timer), 0x5);
+    }
+
+    #[test]
+    fn check_muted() {
+        let timer = Timer::new(true, AudioConfig::default());
+        let audio = Some(std::sync::Mutex::new(Audio::with_config(AudioConfig::default(), true)));
+        Timer::set_sound(&timer, 0x6);
+
+        Timer::set_muted(&timer, true);
+        assert!(Timer::is_muted(&timer));
+        Timer::one_iteration(&timer.delay, &timer.sound, &timer.paused, &timer.frozen, &timer.muted, &audio);
+        assert!(!audio.as_ref().unwrap().lock().unwrap().is_playing());
+        // The sound timer still counts down.
+        assert_eq!(Timer::get_sound(&timer), 0x5);
+
+        Timer::set_muted(&timer, false);
+        Timer::one_iteration(&timer.delay, &timer.sound, &timer.paused, &timer.frozen, &timer.muted, &audio);
+        assert!(audio.as_ref().unwrap().lock().unwrap().is_playing());
+    }
+
+    #[test]
+    fn shutdown() {
+        let timer = Timer::new(true, AudioConfig::default());
+        let clock = std::sync::Arc::new(super::FakeClock::new());
+        Timer::set_delay(&timer, 0xFF);
+        Timer::set_sound(&timer, 0xFF);
+        Timer::spawn_thread_with_clock(&timer, clock.clone());
+
+        // Let the thread run for a couple of ticks, which starts the sound.
+        for sleeps in 1..=2 {
+            clock.wait_for_sleeps(sleeps);
+            clock.advance(super::TIMER_PERIOD);
+        }
+        clock.wait_for_sleeps(3);
+        assert_eq!(Timer::get_delay(&timer), 0xFD);
+        assert!(timer.audio.as_ref().unwrap().lock().unwrap().is_playing());
+
+        // The thread only notices the shutdown on its next tick.
+        timer.shutdown.store(true, std::sync::atomic::Ordering::Relaxed);
+        clock.advance(super::TIMER_PERIOD);
+        Timer::shutdown(&timer);
+        assert!(timer.thread.lock().unwrap().is_none());
+        assert!(!timer.audio.as_ref().unwrap().lock().unwrap().is_playing());
+
+        clock.advance(super::TIMER_PERIOD);
+        assert_eq!(Timer::get_delay(&timer), 0xFD);
+
+        // Shutting down again is harmless.
+        Timer::shutdown(&timer);
+    }
+
+    #[test]
+    fn check_min_sound_ticks() {
+        let timer = Timer::new(true, AudioConfig::default());
+        let config = AudioConfig { min_sound_ticks: 2, ..AudioConfig::default() };
+        let audio = Some(std::sync::Mutex::new(Audio::with_config(config, true)));
+
+        // Counts down to 2, which is enough to beep.
+        Timer::set_sound(&timer, 0x3);
+        Timer::one_iteration(&timer.delay, &timer.sound, &timer.paused, &timer.frozen, &timer.muted, &audio);
+        assert_eq!(Timer::get_sound(&timer), 0x2);
+        assert!(audio.as_ref().unwrap().lock().unwrap().is_playing());
+
+        // A sound value of 1 is below the threshold.
+        Timer::one_iteration(&timer.delay, &timer.sound, &timer.paused, &timer.frozen, &timer.muted, &audio);
+        assert_eq!(Timer::get_sound(&timer), 0x1);
+        assert!(!audio.as_ref().unwrap().lock().unwrap().is_playing());
+
+        // With the default threshold, counting down to 0 is silent, but a sound
+        // value of 1 does beep.
+        let audio = Some(std::sync::Mutex::new(Audio::with_config(AudioConfig::default(), true)));
+        Timer::one_iteration(&timer.delay, &timer.sound, &timer.paused, &timer.frozen, &timer.muted, &audio);
+        assert!(!audio.as_ref().unwrap().lock().unwrap().is_playing());
+        Timer::set_sound(&timer, 0x2);
+        Timer::one_iteration(&timer.delay, &timer.sound, &timer.paused, &timer.frozen, &timer.muted, &audio);
+        assert!(audio.as_ref().unwrap().lock().unwrap().is_playing());
+    }
+
+    #[test]
+    fn ticker() {
+        let ms = std::time::Duration::from_millis;
+        let start = std::time::Instant::now();
+        let mut ticker = super::Ticker::with_start(start, ms(10));
+
+        // Time spent working is taken out of the sleep, keeping ticks at 10ms.
+        assert_eq!(ticker.next_sleep(start + ms(3)), ms(7));
+        assert_eq!(ticker.next_sleep(start + ms(12)), ms(8));
+        assert_eq!(ticker.next_sleep(start + ms(20)), ms(10));
+        assert_eq!(ticker.next_sleep(start + ms(39)), ms(1));
+
+        // A late tick doesn't sleep, and the next ones are counted from then on.
+        assert_eq!(ticker.next_sleep(start + ms(55)), ms(0));
+        assert_eq!(ticker.next_sleep(start + ms(58)), ms(7));
+        assert_eq!(ticker.next_sleep(start + ms(65)), ms(10));
+    }
+
+    #[test]
+    fn check_frozen() {
+        let timer = Timer::new(true, AudioConfig::default());
+        let audio = Some(std::sync::Mutex::new(Audio::with_config(AudioConfig::default(), true)));
+        Timer::set_delay(&timer, 0x6);
+        Timer::set_sound(&timer, 0x6);
+
+        Timer::freeze(&timer);
+        Timer::one_iteration(&timer.delay, &timer.sound, &timer.paused, &timer.frozen, &timer.muted, &audio);
+        assert_eq!(Timer::get_delay(&timer), 0x6);
+        assert_eq!(Timer::get_sound(&timer), 0x6);
+        assert!(!audio.as_ref().unwrap().lock().unwrap().is_playing());
+
+        // Unpausing doesn't unfreeze.
+        Timer::set_paused(&timer, false);
+        Timer::one_iteration(&timer.delay, &timer.sound, &timer.paused, &timer.frozen, &timer.muted, &audio);
+        assert_eq!(Timer::get_delay(&timer), 0x6);
+
+        Timer::unfreeze(&timer);
+        Timer::one_iteration(&timer.delay, &timer.sound, &timer.paused, &timer.frozen, &timer.muted, &audio);
+        assert_eq!(Timer::get_delay(&timer), 0x5);
+        assert_eq!(Timer::get_sound(&timer), 0x5);
+        assert!(audio.as_ref().unwrap().lock().unwrap().is_playing());
+    }
+
+    #[test]
+    fn fake_clock() {
+        let timer = Timer::new(true, AudioConfig::default());
+        let clock = std::sync::Arc::new(super::FakeClock::new());
+        Timer::set_delay(&timer, 0x10);
+        Timer::spawn_thread_with_clock(&timer, clock.clone());
+
+        // Nothing counts down until the clock moves.
+        clock.wait_for_sleeps(1);
+        assert_eq!(Timer::get_delay(&timer), 0x10);
+
+        // The thread waits for the next tick after each iteration.
+        for sleeps in 2..=4 {
+            clock.advance(super::TIMER_PERIOD);
+            clock.wait_for_sleeps(sleeps);
+        }
+        assert_eq!(Timer::get_delay(&timer), 0xD);
+
+        // The thread notices the shutdown on its next tick.
+        timer.shutdown.store(true, std::sync::atomic::Ordering::Relaxed);
+        clock.advance(super::TIMER_PERIOD);
+        Timer::shutdown(&timer);
+        assert_eq!(Timer::get_delay(&timer), 0xD);
+    }
 }
\ No newline at end of file
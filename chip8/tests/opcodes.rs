@@ -0,0 +1,57 @@
+use chip8::{cpu::cpu::CpuConfig, display::display::{Display, WIDTH, HEIGHT}, emulator::emulator::Emulator};
+
+// Runs a small program through the whole fetch/decode pipeline, and checks
+// the resulting screen rather than the effect of each opcode on its own.
+const PROGRAM: [u8; 36] = [
+    0x00, 0xE0, // CLS
+    0x60, 0x05, // V0 = 0x05
+    0x70, 0x03, // V0 += 0x03
+    0x61, 0x04, // V1 = 0x04
+    0x80, 0x14, // V0 += V1, giving 0x0C
+    0x30, 0x0C, // Skip if V0 == 0x0C
+    0x60, 0x00, // V0 = 0x00 (skipped)
+    0x41, 0x05, // Skip if V1 != 0x05
+    0x61, 0x00, // V1 = 0x00 (skipped)
+    0x50, 0x10, // Skip if V0 == V1, which doesn't happen
+    0xF0, 0x29, // I = glyph for V0 ("C")
+    0x62, 0x00, // V2 = 0
+    0x63, 0x00, // V3 = 0
+    0xD2, 0x35, // Draw at (V2, V3)
+    0xF1, 0x29, // I = glyph for V1 ("4")
+    0x62, 0x05, // V2 = 5
+    0xD2, 0x35, // Draw at (V2, V3)
+    0x12, 0x22, // Loop forever
+];
+
+// The top left corner of the screen after running the program, everything
+// else should be off.
+const EXPECTED: [&str; 5] = [
+    "1111010010",
+    "1000010010",
+    "1000011110",
+    "1000000010",
+    "1111000010",
+];
+
+#[test]
+fn arith_skip_draw() {
+    let mut emu = Emulator::new(CpuConfig::default());
+    assert!(emu.load_program(&PROGRAM).is_ok());
+    assert!(emu.run_cycles(20).is_ok());
+
+    let registers = emu.cpu.registers();
+    assert_eq!(registers[0x0], 0x0C);
+    assert_eq!(registers[0x1], 0x04);
+    // Neither draw collided.
+    assert_eq!(registers[0xF], 0);
+    assert_eq!(emu.cpu.pc(), 0x222);
+
+    let pixels = Display::snapshot_buffer(&emu.disp);
+    assert_eq!(pixels.len(), WIDTH * HEIGHT);
+    for y in 0..HEIGHT {
+        for x in 0..WIDTH {
+            let on = EXPECTED.get(y).and_then(|row| row.as_bytes().get(x)) == Some(&b'1');
+            assert_eq!(pixels[y * WIDTH + x] != 0, on, "pixel {},{}", x, y);
+        }
+    }
+}
@@ -0,0 +1,42 @@
+use std::time::Instant;
+
+use chip8::{cpu::cpu::CpuConfig, emulator::emulator::Emulator};
+
+// Measures how many instructions per second the fetch/decode loop gets through,
+// without the frame pacing done in main(). Timing depends on the machine and
+// the build profile, so it's only run on request:
+//   cargo test --release --test throughput -- --ignored --nocapture
+const ITERATIONS: usize = 2_000_000;
+
+// A loop mixing arithmetic, skips, subroutine calls, memory and drawing.
+const PROGRAM: [u8; 34] = [
+    0x60, 0x05, // 200: V0 = 0x05
+    0x70, 0x01, // 202: V0 += 0x01
+    0x81, 0x04, // 204: V1 += V0
+    0x82, 0x11, // 206: V2 |= V1
+    0x83, 0x23, // 208: V3 ^= V2
+    0x84, 0x36, // 20A: V4 = V3 >> 1
+    0xA3, 0x00, // 20C: I = 0x300
+    0xF4, 0x1E, // 20E: I += V4
+    0x31, 0x00, // 210: Skip if V1 == 0x00
+    0x22, 0x20, // 212: Call 0x220
+    0xD0, 0x15, // 214: Draw at (V0, V1)
+    0x12, 0x02, // 216: Jump to 0x202
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0xEE, // 220: Return
+];
+
+#[test]
+#[ignore]
+fn decode_throughput() {
+    let mut emu = Emulator::new(CpuConfig::default());
+    assert!(emu.load_program(&PROGRAM).is_ok());
+
+    let start = Instant::now();
+    assert!(emu.run_cycles(ITERATIONS).is_ok());
+    let elapsed = start.elapsed();
+
+    let per_second = ITERATIONS as f64 / elapsed.as_secs_f64();
+    println!("Executed {} instructions in {:?}, {:.0} instructions/s", ITERATIONS, elapsed, per_second);
+    assert_eq!(emu.cpu.cycle_count(), ITERATIONS as u64);
+}